@@ -0,0 +1,76 @@
+use druns::buffer::BytePacketBuffer;
+use druns::decode::{self, Command};
+use druns::packet::Packet;
+use std::{
+    env, fs,
+    io::{self, Read},
+    process,
+};
+
+fn main() {
+    let options = match decode::parse_args(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{}", decode::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("druns-decode {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("druns-decode: {}", e);
+            eprintln!("try 'druns-decode --help' for more information");
+            process::exit(2);
+        }
+    };
+
+    let files = if options.files.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        options.files.clone()
+    };
+    let mut failed = false;
+    for (i, file) in files.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let input = if file == "-" {
+            let mut input = vec![];
+            io::stdin().read_to_end(&mut input).map(|_| input)
+        } else {
+            fs::read(file)
+        };
+        let name = if file == "-" {
+            "<stdin>"
+        } else {
+            file.as_str()
+        };
+        let decoded = input
+            .map_err(|e| e.to_string())
+            .and_then(|input| decode::decode_input(&input, options.format));
+        let (format, message) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                eprintln!("druns-decode: {}: {}", name, e);
+                failed = true;
+                continue;
+            }
+        };
+
+        println!(";; {}: {} bytes, {}", name, message.len(), format);
+        match Packet::read(&mut BytePacketBuffer::from_bytes(&message)) {
+            Ok(packet) => println!("{}", packet),
+            Err(e) => {
+                println!(";; parse error: {}", e);
+                failed = true;
+            }
+        }
+        if options.dump {
+            print!("\n;; HEX DUMP:\n{}", decode::dump(&message));
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
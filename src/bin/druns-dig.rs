@@ -0,0 +1,64 @@
+use druns::dig::{self, Command, Exchange};
+use druns::hints;
+use std::{env, net::IpAddr, process};
+
+fn main() {
+    let options = match dig::parse_args(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{}", dig::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("druns-dig {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("druns-dig: {}", e);
+            eprintln!("try 'druns-dig --help' for more information");
+            process::exit(2);
+        }
+    };
+
+    if !options.json {
+        println!(
+            "; <<>> druns-dig {} <<>> @{} {}",
+            env!("CARGO_PKG_VERSION"),
+            options.server,
+            dig::request(&options).questions[0]
+        );
+    }
+
+    let result = if options.trace {
+        let roots: Vec<IpAddr> = hints::ipv4_addresses(&hints::default())
+            .into_iter()
+            .map(IpAddr::from)
+            .collect();
+        let mut steps: Vec<Exchange> = vec![];
+        let result = dig::trace(&options, &roots, 53, |exchange| {
+            if options.json {
+                steps.push(exchange.clone());
+            } else {
+                println!("\n{}", dig::to_text(exchange));
+            }
+        });
+        if options.json {
+            let steps: Vec<String> = steps.iter().map(dig::to_json).collect();
+            println!("[{}]", steps.join(","));
+        }
+        result
+    } else {
+        dig::query(&options).map(|exchange| {
+            if options.json {
+                println!("{}", dig::to_json(&exchange));
+            } else {
+                println!("\n{}", dig::to_text(&exchange));
+            }
+        })
+    };
+
+    if let Err(e) = result {
+        eprintln!("druns-dig: {}", e);
+        process::exit(1);
+    }
+}
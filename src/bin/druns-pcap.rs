@@ -0,0 +1,51 @@
+use druns::pcap;
+use std::{env, path::Path, process};
+
+fn main() {
+    let mut files = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", pcap::USAGE);
+                return;
+            }
+            "-V" | "--version" => {
+                println!("druns-pcap {}", env!("CARGO_PKG_VERSION"));
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("druns-pcap: unknown option {}", arg);
+                eprintln!("try 'druns-pcap --help' for more information");
+                process::exit(2);
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("druns-pcap: no capture file given");
+        eprintln!("try 'druns-pcap --help' for more information");
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    for (i, file) in files.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match pcap::read(Path::new(file)) {
+            Ok(capture) => {
+                if files.len() > 1 {
+                    println!("== {}", file);
+                }
+                print!("{}", pcap::report(&capture));
+            }
+            Err(e) => {
+                eprintln!("druns-pcap: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -1,18 +1,150 @@
-use std::{convert::TryInto, fs, path::Path};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    fmt, fs,
+    path::Path,
+};
 
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
+pub type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// maximum length of a domain name on the wire, including length octets
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Reasons a message can fail to parse. Every variant carries the byte
+/// offset (from the start of the message) at which parsing failed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ParseError {
+    /// message is shorter than the fixed 12 byte header
+    TruncatedHeader {
+        offset: usize,
+    },
+    /// a read ran past the end of the message
+    UnexpectedEnd {
+        offset: usize,
+    },
+    /// label length uses the reserved 0x40/0x80 prefixes, or a string read
+    /// with read_string isn't valid text
+    BadLabel {
+        offset: usize,
+        length: u8,
+    },
+    /// compression pointer doesn't point strictly backwards
+    PointerLoop {
+        offset: usize,
+        target: usize,
+    },
+    /// name exceeds 255 bytes once expanded
+    NameTooLong {
+        offset: usize,
+    },
+    /// RDATA didn't consume exactly RDLENGTH bytes
+    RdLengthMismatch {
+        offset: usize,
+        expected: u16,
+        actual: usize,
+    },
+    UnknownOpcode {
+        offset: usize,
+        opcode: u8,
+    },
+}
+
+impl ParseError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::TruncatedHeader { offset }
+            | ParseError::UnexpectedEnd { offset }
+            | ParseError::BadLabel { offset, .. }
+            | ParseError::PointerLoop { offset, .. }
+            | ParseError::NameTooLong { offset }
+            | ParseError::RdLengthMismatch { offset, .. }
+            | ParseError::UnknownOpcode { offset, .. } => offset,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TruncatedHeader { offset } => {
+                write!(f, "truncated header: message is only {} bytes", offset)
+            }
+            ParseError::UnexpectedEnd { offset } => {
+                write!(f, "unexpected end of message at offset {}", offset)
+            }
+            ParseError::BadLabel { offset, length } => {
+                write!(
+                    f,
+                    "bad label (length byte {:#04x}) at offset {}",
+                    length, offset
+                )
+            }
+            ParseError::PointerLoop { offset, target } => write!(
+                f,
+                "compression pointer at offset {} loops to {}",
+                offset, target
+            ),
+            ParseError::NameTooLong { offset } => {
+                write!(f, "name longer than 255 bytes at offset {}", offset)
+            }
+            ParseError::RdLengthMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "rdata at offset {} has rdlength {} but {} bytes were consumed",
+                offset, expected, actual
+            ),
+            ParseError::UnknownOpcode { offset, opcode } => {
+                write!(f, "unknown opcode {} at offset {}", opcode, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// compression pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// classic limit for a DNS message over UDP without EDNS
+pub const DEFAULT_MAX_SIZE: usize = 512;
+/// largest message that fits the 16 bit length prefix used over TCP
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Returned by writes that would go past the buffer's maximum size, so callers
+/// can tell "doesn't fit" apart from other failures and set the TC bit.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BufferOverflow {
+    pub max_size: usize,
+}
+
+impl fmt::Display for BufferOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "buffer overflow: limit is {} bytes", self.max_size)
+    }
+}
+
+impl std::error::Error for BufferOverflow {}
 
 pub struct BytePacketBuffer {
-    buffer: [u8; 512],
+    buffer: Vec<u8>,
+    max_size: usize,
     pub pos: usize,
     pub size: usize,
+    // offsets of names (and their suffixes) already written, for compression
+    names: HashMap<String, usize>,
 }
 
 impl std::ops::DerefMut for BytePacketBuffer {
     fn deref_mut(&mut self) -> &mut Self::Target {
         // much better to have a size, which keeps track of buffer usage
         // rather than use pos, which can reset etc.
+        // hands out the full capacity, so the buffer can be used for recv
+        self.buffer.resize(self.max_size, 0);
         &mut self.buffer
     }
 }
@@ -29,28 +161,42 @@ impl BytePacketBuffer {
     pub fn new(from_file: String) -> BytePacketBuffer {
         let path = Path::new(&from_file);
         let contents = fs::read(path);
-        let contents = contents.expect(&format!("error reading contents from file {}", from_file));
+        let contents =
+            contents.unwrap_or_else(|_| panic!("error reading contents from file {}", from_file));
 
-        let mut buffer = [0; 512];
-        (0..contents.len()).for_each(|i| {
-            buffer[i] = contents[i];
-        });
+        BytePacketBuffer::from_bytes(&contents)
+    }
 
-        BytePacketBuffer {
-            buffer,
-            pos: 0,
-            size: contents.len(),
-        }
+    /// buffer holding a copy of `bytes`, ready to be read. Anything past
+    /// MAX_MESSAGE_SIZE is dropped.
+    pub fn from_bytes(bytes: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::with_max_size(MAX_MESSAGE_SIZE);
+        let size = bytes.len().min(MAX_MESSAGE_SIZE);
+        buffer.buffer.extend_from_slice(&bytes[0..size]);
+        buffer.size = size;
+        buffer
     }
 
     pub fn new_empty() -> BytePacketBuffer {
+        BytePacketBuffer::with_max_size(DEFAULT_MAX_SIZE)
+    }
+
+    /// empty buffer which grows as it's written to, up to `max_size` bytes
+    /// (capped at MAX_MESSAGE_SIZE)
+    pub fn with_max_size(max_size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buffer: [0; 512],
+            buffer: vec![],
+            max_size: max_size.min(MAX_MESSAGE_SIZE),
             pos: 0,
             size: 0,
+            names: HashMap::new(),
         }
     }
 
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
     pub fn reset_for_read(&mut self) {
         self.pos = 0;
     }
@@ -58,102 +204,136 @@ impl BytePacketBuffer {
 
 // reading from buffer
 impl BytePacketBuffer {
-    pub fn read_u8(&mut self) -> Result<u8> {
+    pub fn read_u8(&mut self) -> ParseResult<u8> {
         let result = self.read_u8_from(self.pos);
         self.pos += 1;
         result
     }
 
-    pub fn read_u8_from(&mut self, cpos: usize) -> Result<u8> {
-        if cpos >= 511 {
-            Err(format!("overflow {}", cpos).into())
+    pub fn read_u8_from(&self, cpos: usize) -> ParseResult<u8> {
+        if cpos >= self.size {
+            Err(ParseError::UnexpectedEnd { offset: cpos })
         } else {
             Ok(self.buffer[cpos])
         }
     }
 
-    pub fn read_u16(&mut self) -> Result<u16> {
+    pub fn read_u16(&mut self) -> ParseResult<u16> {
         let result = self.read_u16_from(self.pos);
         self.pos += 2;
         result
     }
 
-    pub fn read_u16_from(&mut self, mut cpos: usize) -> Result<u16> {
-        if cpos >= 510 {
-            return Err("overflow".into());
-        }
-        let mut result: u16 = self.read_u8_from(cpos).unwrap().into();
-        cpos += 1;
-        let sresult: u16 = self.read_u8_from(cpos).unwrap().into();
-        result = (result << 8) + sresult;
-        Ok(result)
+    pub fn read_u16_from(&self, cpos: usize) -> ParseResult<u16> {
+        let result: u16 = self.read_u8_from(cpos)?.into();
+        let sresult: u16 = self.read_u8_from(cpos + 1)?.into();
+        Ok((result << 8) + sresult)
     }
 
-    pub fn read_u32(&mut self) -> Result<u32> {
-        let mut result: u32 = self.read_u16().unwrap().into();
-        let sresult: u32 = self.read_u16().unwrap().into();
+    pub fn read_u32(&mut self) -> ParseResult<u32> {
+        let mut result: u32 = self.read_u16()?.into();
+        let sresult: u32 = self.read_u16()?.into();
         result = (result << 16) + sresult;
         Ok(result)
     }
 
-    pub fn read_string(&mut self, length: u8) -> String {
+    pub fn read_string(&mut self, length: u8) -> ParseResult<String> {
         let result = self.read_string_from(length, self.pos);
         self.pos += length as usize;
         result
     }
 
-    pub fn read_string_from(&mut self, length: u8, mut cpos: usize) -> String {
-        let mut vv: Vec<u8> = vec![];
-        (0..length).for_each(|_| {
-            vv.push(self.read_u8_from(cpos).unwrap());
-            cpos += 1;
-        });
-        String::from_utf8(vv).unwrap_or_else(|err| format!("utf8 error {}", err))
+    pub fn read_string_from(&self, length: u8, cpos: usize) -> ParseResult<String> {
+        let bytes = self.read_bytes_from(length as usize, cpos)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ParseError::BadLabel {
+            offset: cpos,
+            length,
+        })
     }
 
-    pub fn read_qname(&mut self) -> String {
-        let (qname, new_pos) = self.read_qname_from(self.pos);
+    pub fn read_bytes_from(&self, length: usize, cpos: usize) -> ParseResult<&[u8]> {
+        if cpos + length > self.size {
+            return Err(ParseError::UnexpectedEnd {
+                offset: self.size.max(cpos),
+            });
+        }
+        Ok(&self.buffer[cpos..cpos + length])
+    }
+
+    /// moves the read position forward without looking at the bytes
+    pub fn skip(&mut self, length: usize) -> ParseResult<()> {
+        self.read_bytes_from(length, self.pos)?;
+        self.pos += length;
+        Ok(())
+    }
+
+    pub fn read_qname(&mut self) -> ParseResult<String> {
+        let (qname, new_pos) = self.read_qname_from(self.pos)?;
         self.pos = new_pos;
-        qname
+        Ok(qname)
     }
 
-    pub fn read_qname_from(&mut self, mut cpos: usize) -> (String, usize) {
+    /// returns the name at `cpos` and the position right after it. Compression
+    /// pointers are only allowed to jump backwards, which rules out loops.
+    pub fn read_qname_from(&self, mut cpos: usize) -> ParseResult<(String, usize)> {
+        let start = cpos;
         let mut qname = String::new();
-        while {
-            let mut length = self.read_u8_from(cpos).unwrap();
-            cpos += 1;
-            if length & 0xC0 != 0 {
-                // jump directive
-                let following_byte = self.read_u8_from(cpos).unwrap() as u16;
-                cpos += 1;
-                let msb_removed_length = (length ^ 0xC0) as u16;
-                let jmp_position: u16 = following_byte + (msb_removed_length << 8);
-                let (jmp_name, _) = self.read_qname_from(jmp_position as usize);
-                qname = qname + &jmp_name;
-                length = 0; // exit
-            } else {
-                qname = qname + &self.read_string_from(length, cpos);
-                cpos += length as usize;
-            }
+        let mut wire_length = 0;
+        // position to resume from after the first jump
+        let mut end: Option<usize> = None;
+        // lowest offset reached so far, every jump must go below it
+        let mut floor = cpos;
 
-            if length != 0 {
-                qname += ".";
-                true
-            } else {
-                false
+        loop {
+            let length = self.read_u8_from(cpos)?;
+            match length & 0xC0 {
+                0xC0 => {
+                    // jump directive
+                    let following_byte = self.read_u8_from(cpos + 1)? as usize;
+                    let jmp_position = (((length & 0x3F) as usize) << 8) + following_byte;
+                    if jmp_position >= floor {
+                        return Err(ParseError::PointerLoop {
+                            offset: cpos,
+                            target: jmp_position,
+                        });
+                    }
+                    if end.is_none() {
+                        end = Some(cpos + 2);
+                    }
+                    floor = jmp_position;
+                    cpos = jmp_position;
+                }
+                0x00 => {
+                    cpos += 1;
+                    wire_length += length as usize + 1;
+                    if wire_length > MAX_NAME_LENGTH {
+                        return Err(ParseError::NameTooLong { offset: start });
+                    }
+                    if length == 0 {
+                        break;
+                    }
+                    // any byte may appear in a label (RFC 2181 section 11)
+                    qname += &escape_label(self.read_bytes_from(length as usize, cpos)?);
+                    qname += ".";
+                    cpos += length as usize;
+                }
+                _ => {
+                    return Err(ParseError::BadLabel {
+                        offset: cpos,
+                        length,
+                    })
+                }
             }
-        } {}
+        }
 
-        (qname, cpos)
+        Ok((qname, end.unwrap_or(cpos)))
     }
 }
 
 // writing to the buffer
 impl BytePacketBuffer {
     pub fn write_u8(&mut self, val: u8) -> Result<()> {
-        if self.pos > 511 {
-            return Err("buffer overflow".into());
-        }
+        self.reserve(1)?;
         self.buffer[self.pos] = val;
         self.pos += 1;
         self.size = self.pos;
@@ -180,9 +360,7 @@ impl BytePacketBuffer {
         if val.is_empty() {
             return Ok(());
         }
-        if self.pos + val.len() > 511 {
-            return Err("buffer overflow".into());
-        }
+        self.reserve(val.len())?;
         let destination_slice = &mut self.buffer[self.pos..self.pos + val.len()];
         destination_slice.copy_from_slice(val.as_bytes());
         self.pos += val.len();
@@ -190,23 +368,70 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    /// makes room for `length` more bytes at pos, failing if that would go
+    /// past max_size
+    fn reserve(&mut self, length: usize) -> Result<()> {
+        if self.pos + length > self.max_size {
+            return Err(BufferOverflow {
+                max_size: self.max_size,
+            }
+            .into());
+        }
+        if self.pos + length > self.buffer.len() {
+            self.buffer.resize(self.pos + length, 0);
+        }
+        Ok(())
+    }
+
+    /// writes a name, replacing the longest suffix that was already written
+    /// to this buffer with a compression pointer
     pub fn write_qname(&mut self, val: &str) -> Result<()> {
-        // TODO: implement jump directive here
-        if val.is_empty() {
-            let _ = self.write_u8(0);
-            return Ok(());
+        self.write_name(val, true)
+    }
+
+    /// writes a name in full. Used for rdata which must not be compressed
+    /// (e.g. SRV targets), while still making the name available as a
+    /// target for later pointers.
+    pub fn write_qname_uncompressed(&mut self, val: &str) -> Result<()> {
+        self.write_name(val, false)
+    }
+
+    fn write_name(&mut self, val: &str, compress: bool) -> Result<()> {
+        let labels = name_labels(val).map_err(|e| format!("{} in name {:?}", e, val))?;
+        // checked before anything is written, the same limits as on reading
+        if let Some(label) = labels.iter().find(|x| x.len() > 63) {
+            return Err(format!("label of {} bytes in name {:?}", label.len(), val).into());
+        }
+        let wire_length = labels.iter().map(|x| x.len() + 1).sum::<usize>() + 1;
+        if wire_length > MAX_NAME_LENGTH {
+            return Err(format!("name of {} bytes {:?}", wire_length, val).into());
         }
 
-        val.split('.').for_each(|label| {
-            let _ = self.write_u8(label.len().try_into().unwrap());
-            let _ = self.write_string(label);
-        });
+        for i in 0..labels.len() {
+            let suffix: Vec<String> = labels[i..].iter().map(|x| escape_label(x)).collect();
+            let suffix = suffix.join(".");
+            if compress {
+                if let Some(&offset) = self.names.get(&suffix) {
+                    return self.write_u16(0xC000 | offset as u16);
+                }
+            }
 
-        Ok(())
+            if self.pos <= MAX_POINTER_OFFSET {
+                self.names.entry(suffix).or_insert(self.pos);
+            }
+            let label = &labels[i];
+            self.write_u8(label.len() as u8)?;
+            for x in label.iter() {
+                self.write_u8(*x)?;
+            }
+        }
+
+        self.write_u8(0)
     }
 
+    /// overwrites two bytes which have already been written
     pub fn set_u16(&mut self, val: u16, pos: usize) -> Result<()> {
-        if pos > 510 {
+        if pos + 2 > self.size {
             return Err(format!("invalid range {}", pos).into());
         }
 
@@ -215,3 +440,76 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+/// a label as text, as in RFC 1035 section 5.1: `\` before the characters
+/// which mean something in a master file, and every byte outside printable
+/// ascii, space included, written as \DDD
+pub fn escape_label(label: &[u8]) -> String {
+    let mut text = String::new();
+    for &x in label.iter() {
+        match x {
+            b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                text.push('\\');
+                text.push(x as char);
+            }
+            0x21..=0x7e => text.push(x as char),
+            _ => text += &format!("\\{:03}", x),
+        }
+    }
+    text
+}
+
+/// the bytes of each label of a name written the way `escape_label` writes
+/// them, without the root label. The trailing dot is optional.
+pub fn name_labels(name: &str) -> std::result::Result<Vec<Vec<u8>>, String> {
+    let mut labels = vec![];
+    if name.is_empty() || name == "." {
+        return Ok(labels);
+    }
+    let mut label = vec![];
+    let mut ended = false;
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        ended = false;
+        match bytes[i] {
+            b'\\' => {
+                let digits = &bytes[i + 1..bytes.len().min(i + 4)];
+                if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+                    let value: u16 = std::str::from_utf8(digits).unwrap().parse().unwrap();
+                    label.push(
+                        u8::try_from(value).map_err(|_| format!("invalid escape \\{}", value))?,
+                    );
+                    i += 4;
+                    continue;
+                }
+                match bytes.get(i + 1) {
+                    Some(x) => label.push(*x),
+                    None => return Err("name ends with a lone \\".to_string()),
+                }
+                i += 2;
+                continue;
+            }
+            b'.' if label.is_empty() => return Err("empty label".to_string()),
+            b'.' => {
+                labels.push(std::mem::take(&mut label));
+                ended = true;
+            }
+            x => label.push(x),
+        }
+        i += 1;
+    }
+    if !ended {
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+/// `name` with every label escaped the way `escape_label` does it, so names
+/// with the same bytes also have the same text
+pub fn canonical_name(name: &str) -> std::result::Result<String, String> {
+    Ok(name_labels(name)?
+        .iter()
+        .map(|x| escape_label(x) + ".")
+        .collect())
+}
@@ -0,0 +1,252 @@
+use super::packet::{normalize_name, parent_name, QueryType, Record};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    time::Instant,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, qtype: QueryType, class: u16) -> CacheKey {
+        CacheKey {
+            name: normalize_name(name),
+            qtype,
+            class,
+        }
+    }
+}
+
+/// what a negative entry says about its name (RFC 2308)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Negative {
+    /// the name doesn't exist, for any type
+    NxDomain,
+    /// the name exists but has no records of the type
+    NoData,
+}
+
+/// NXDOMAIN covers every type of a name, so it's stored under type 0, which is
+/// reserved and never queried
+const NXDOMAIN_TYPE: QueryType = QueryType::UNKNOWN(0);
+
+struct Entry {
+    // for negative entries, the SOA record from the authority section
+    records: Vec<Record>,
+    negative: Option<Negative>,
+    inserted: Instant,
+    // smallest ttl in the set, the whole set expires together
+    ttl: u32,
+    last_used: u64,
+}
+
+/// RRsets keyed by (name, type, class). TTLs count down from the time a set
+/// is inserted, and once more than `max_entries` sets are cached the least
+/// recently used one is evicted.
+pub struct Cache {
+    entries: HashMap<CacheKey, Entry>,
+    // last use -> key, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    max_entries: usize,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            max_entries,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// groups `records` into RRsets and caches each, replacing whatever was
+    /// cached for the same key. OPT records are skipped.
+    pub fn insert(&mut self, records: &[Record]) {
+        let mut rrsets: Vec<(CacheKey, Vec<Record>)> = vec![];
+        for record in records.iter() {
+            if matches!(record, Record::OPT { .. }) {
+                continue;
+            }
+            let key = CacheKey::new(
+                record.name(),
+                QueryType::from_num(record.to_num()),
+                record.class(),
+            );
+            match rrsets.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, rrset)) => rrset.push(record.clone()),
+                None => rrsets.push((key, vec![record.clone()])),
+            }
+        }
+
+        for (key, rrset) in rrsets.into_iter() {
+            self.insert_rrset(key, rrset);
+        }
+    }
+
+    pub fn insert_rrset(&mut self, key: CacheKey, records: Vec<Record>) {
+        // the name exists after all
+        self.remove(&CacheKey::new(&key.name, NXDOMAIN_TYPE, key.class));
+        self.insert_entry(key, records, None);
+    }
+
+    /// remembers that `name` doesn't exist, or has no records of `qtype`.
+    /// The entry lives for the smaller of the SOA's ttl and minimum field.
+    pub fn insert_negative(
+        &mut self,
+        name: &str,
+        qtype: QueryType,
+        class: u16,
+        negative: Negative,
+        soa: &Record,
+    ) {
+        let minimum = match soa {
+            Record::SOA { minimum, .. } => *minimum,
+            _ => return,
+        };
+        let qtype = match negative {
+            Negative::NxDomain => NXDOMAIN_TYPE,
+            Negative::NoData => qtype,
+        };
+        let mut soa = soa.clone();
+        soa.set_ttl(soa.ttl().min(minimum));
+        self.insert_entry(CacheKey::new(name, qtype, class), vec![soa], Some(negative));
+    }
+
+    fn insert_entry(&mut self, key: CacheKey, records: Vec<Record>, negative: Option<Negative>) {
+        let ttl = records.iter().map(|x| x.ttl()).min().unwrap_or(0);
+        if ttl == 0 {
+            // only meant to be used for the transaction at hand
+            return;
+        }
+
+        self.remove(&key);
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                records,
+                negative,
+                inserted: Instant::now(),
+                ttl,
+                last_used: self.tick,
+            },
+        );
+
+        while self.entries.len() > self.max_entries {
+            let oldest = match self.lru.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(key) = self.lru.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// cached records with their ttl reduced by the time spent in the cache
+    pub fn get(&mut self, name: &str, qtype: QueryType, class: u16) -> Option<Vec<Record>> {
+        match self.lookup(CacheKey::new(name, qtype, class)) {
+            Some((None, records)) => Some(records),
+            _ => None,
+        }
+    }
+
+    /// cached NXDOMAIN for the name, or NODATA for the type, along with the
+    /// SOA record to put in the authority section
+    pub fn get_negative(
+        &mut self,
+        name: &str,
+        qtype: QueryType,
+        class: u16,
+    ) -> Option<(Negative, Vec<Record>)> {
+        for qtype in [NXDOMAIN_TYPE, qtype].iter() {
+            if let Some((Some(negative), records)) = self.lookup(CacheKey::new(name, *qtype, class))
+            {
+                return Some((negative, records));
+            }
+        }
+        None
+    }
+
+    fn lookup(&mut self, key: CacheKey) -> Option<(Option<Negative>, Vec<Record>)> {
+        let (elapsed, ttl) = match self.entries.get(&key) {
+            Some(entry) => (entry.inserted.elapsed().as_secs(), entry.ttl),
+            None => return None,
+        };
+        if elapsed >= ttl as u64 {
+            self.remove(&key);
+            return None;
+        }
+
+        self.tick += 1;
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.tick, key);
+        entry.last_used = self.tick;
+
+        let records = entry
+            .records
+            .iter()
+            .map(|x| {
+                let mut record = x.clone();
+                record.set_ttl(x.ttl().saturating_sub(elapsed as u32));
+                record
+            })
+            .collect();
+        Some((entry.negative, records))
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    /// addresses of the nameservers for the closest enclosing zone of `name`
+    /// (possibly `name` itself) whose NS set and glue are both cached
+    pub fn closest_delegation(&mut self, name: &str) -> Option<(String, Vec<Ipv4Addr>)> {
+        let name = normalize_name(name);
+        let mut zone = Some(name.as_str());
+        while let Some(current) = zone {
+            if let Some(ns_records) = self.get(current, QueryType::NS, 1) {
+                let servers = self.addresses(&ns_records);
+                if !servers.is_empty() {
+                    return Some((current.to_string(), servers));
+                }
+            }
+            zone = parent_name(current);
+        }
+        None
+    }
+
+    /// cached ipv4 addresses of the hosts in a set of NS records
+    pub fn addresses(&mut self, ns_records: &[Record]) -> Vec<Ipv4Addr> {
+        let mut servers = vec![];
+        for record in ns_records.iter() {
+            if let Record::NS { host, .. } = record {
+                for a in self.get(host, QueryType::A, 1).unwrap_or_default() {
+                    if let Record::A { ip, .. } = a {
+                        servers.push(Ipv4Addr::from(ip));
+                    }
+                }
+            }
+        }
+        servers
+    }
+}
@@ -0,0 +1,183 @@
+use super::config::Mode;
+use super::log::Level;
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+pub const DEFAULT_PORT: u16 = 34254;
+
+pub const USAGE: &str = "\
+druns, a recursive DNS resolver
+
+usage: druns [options]
+
+options:
+  -l, --listen <addr>       address to listen on [default: 0.0.0.0]
+  -p, --port <port>         port to listen on, for UDP and TCP [default: 34254]
+  -m, --mode <mode>         recursive or forwarder [default: recursive]
+  -u, --upstream <addr>     server to forward to, as ip or ip:port, may be
+                            repeated (forwarder mode only)
+      --root-hints <file>   root servers to start recursion from, in
+                            named.root format, instead of the built-in copy
+                            (recursive mode only)
+      --cache-size <n>      number of RRsets kept in the cache [default: 10000]
+      --log-level <level>   off, error, warn, info or debug [default: warn]
+  -v, --verbose             log more, can be repeated
+  -q, --quiet               only log errors
+  -c, --config <file>       configuration file
+  -h, --help                print this help
+  -V, --version             print the version
+";
+
+/// what was given on the command line, None for anything left out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub listen: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub mode: Option<Mode>,
+    pub upstreams: Vec<SocketAddr>,
+    pub root_hints: Option<PathBuf>,
+    pub cache_size: Option<usize>,
+    pub log_level: Option<Level>,
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+/// bad command line, the message says what to fix
+#[derive(Debug, PartialEq)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+fn usage_error<T>(message: String) -> Result<T, UsageError> {
+    Err(UsageError(message))
+}
+
+/// parses the arguments after the program name. Options take their value
+/// either as the next argument or after an `=`.
+pub fn parse_args<I, S>(args: I) -> Result<Command, UsageError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut options = Options::default();
+    let mut verbosity: Option<Level> = None;
+    let mut args = args.into_iter().map(Into::into);
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        if inline.is_some() && !takes_value(&name) {
+            return usage_error(format!("{} doesn't take a value", name));
+        }
+        let mut value = |name: &str| match inline.clone().or_else(|| args.next()) {
+            Some(value) => Ok(value),
+            None => usage_error(format!("{} needs a value", name)),
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-l" | "--listen" => {
+                let addr = value(&name)?;
+                options.listen = Some(
+                    addr.parse()
+                        .or_else(|_| usage_error(format!("invalid listen address {:?}", addr)))?,
+                );
+            }
+            "-p" | "--port" => options.port = Some(parse_port(&value(&name)?)?),
+            "-m" | "--mode" => options.mode = Some(value(&name)?.parse().map_err(UsageError)?),
+            "-u" | "--upstream" => options.upstreams.push(parse_upstream(&value(&name)?)?),
+            "--root-hints" => options.root_hints = Some(PathBuf::from(value(&name)?)),
+            "--cache-size" => {
+                let size = value(&name)?;
+                options.cache_size = match size.parse() {
+                    Ok(size) if size > 0 => Some(size),
+                    _ => {
+                        return usage_error(format!(
+                            "invalid cache size {:?}, expected a number above 0",
+                            size
+                        ))
+                    }
+                };
+            }
+            "--log-level" => options.log_level = Some(value(&name)?.parse().map_err(UsageError)?),
+            "-q" | "--quiet" => verbosity = Some(Level::Error),
+            "-c" | "--config" => options.config = Some(PathBuf::from(value(&name)?)),
+            _ if is_verbose(&name) => {
+                let mut level = verbosity.unwrap_or(Level::Warn);
+                for _ in 0..name.matches('v').count() {
+                    level = match level {
+                        Level::Off => Level::Error,
+                        Level::Error => Level::Warn,
+                        Level::Warn => Level::Info,
+                        _ => Level::Debug,
+                    };
+                }
+                verbosity = Some(level);
+            }
+            _ if name.starts_with('-') => {
+                return usage_error(format!("unknown option {}", name));
+            }
+            _ => return usage_error(format!("unexpected argument {:?}", name)),
+        }
+    }
+
+    if verbosity.is_some() {
+        options.log_level = verbosity;
+    }
+    Ok(Command::Run(options))
+}
+
+/// -v, -vv, ... and --verbose
+fn is_verbose(arg: &str) -> bool {
+    arg == "--verbose"
+        || (arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v'))
+}
+
+fn takes_value(name: &str) -> bool {
+    !matches!(name, "--help" | "--version" | "--quiet" | "--verbose")
+}
+
+fn parse_port(port: &str) -> Result<u16, UsageError> {
+    match port.parse() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => usage_error(format!(
+            "invalid port {:?}, expected a number from 1 to 65535",
+            port
+        )),
+    }
+}
+
+/// `ip`, `ip:port` or `[ipv6]:port`, port 53 if it's left out
+fn parse_upstream(addr: &str) -> Result<SocketAddr, UsageError> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() > 0 => Ok(addr),
+        _ => usage_error(format!(
+            "invalid upstream {:?}, expected ip or ip:port",
+            addr
+        )),
+    }
+}
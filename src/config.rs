@@ -0,0 +1,624 @@
+use super::cli::{Options, DEFAULT_PORT};
+use super::log::Level;
+use super::lookup::{DEFAULT_CACHE_SIZE, DEFAULT_DEADLINE, DEFAULT_RETRIES, DEFAULT_TIMEOUT};
+use super::packet::normalize_name;
+use super::toml::{self, Entry, Table, Value};
+use std::{
+    error::Error,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// how many queries are resolved at the same time
+pub const DEFAULT_WORKERS: usize = 32;
+
+/// queries waiting for a worker
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// client TCP connections open at the same time
+pub const DEFAULT_TCP_CONNECTIONS: usize = 128;
+
+/// of those, how many a single client may have
+pub const DEFAULT_TCP_CONNECTIONS_PER_CLIENT: usize = 16;
+
+/// how long a client TCP connection may sit idle between queries
+pub const DEFAULT_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how queries get answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// follow referrals from the root servers
+    Recursive,
+    /// hand every query to the upstream servers
+    Forwarder,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "recursive" => Ok(Mode::Recursive),
+            "forwarder" => Ok(Mode::Forwarder),
+            _ => Err(format!(
+                "unknown mode {:?}, expected recursive or forwarder",
+                s
+            )),
+        }
+    }
+}
+
+/// an address block such as 10.0.0.0/8. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let prefix = prefix as usize;
+    let whole = prefix / 8;
+    if net[..whole] != ip[..whole] {
+        return false;
+    }
+    let bits = prefix % 8;
+    bits == 0 || (net[whole] ^ ip[whole]) >> (8 - bits) == 0
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let invalid = || format!("invalid network {:?}, expected ip or ip/prefix", s);
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(invalid()),
+            },
+            None => max,
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+/// which clients may query. Denied networks win over allowed ones, and an
+/// empty allow list lets in everyone who isn't denied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    pub allow: Vec<Network>,
+    pub deny: Vec<Network>,
+}
+
+impl Acl {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|x| x.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip))
+    }
+}
+
+/// names at or below `zone` sent to `upstreams`, whatever the mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardRule {
+    pub zone: String,
+    pub upstreams: Vec<SocketAddr>,
+}
+
+/// a zone served from a local file instead of being resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalZone {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub mode: Mode,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub tcp_connections: usize,
+    pub tcp_connections_per_client: usize,
+    pub root_hints: Option<PathBuf>,
+    pub upstreams: Vec<SocketAddr>,
+    pub forward: Vec<ForwardRule>,
+    pub timeout: Duration,
+    pub retries: usize,
+    pub deadline: Duration,
+    pub tcp_idle_timeout: Duration,
+    pub cache_size: usize,
+    pub acl: Acl,
+    pub zones: Vec<LocalZone>,
+    pub log_level: Level,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT))],
+            mode: Mode::Recursive,
+            workers: DEFAULT_WORKERS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            tcp_connections: DEFAULT_TCP_CONNECTIONS,
+            tcp_connections_per_client: DEFAULT_TCP_CONNECTIONS_PER_CLIENT,
+            root_hints: None,
+            upstreams: vec![],
+            forward: vec![],
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            deadline: DEFAULT_DEADLINE,
+            tcp_idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT,
+            cache_size: DEFAULT_CACHE_SIZE,
+            acl: Acl::default(),
+            zones: vec![],
+            log_level: Level::Warn,
+        }
+    }
+}
+
+/// what's wrong with a config, and where
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,
+    /// the offending key, with its table, e.g. `cache.size`
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+        }
+        if self.path.is_some() || self.line.is_some() {
+            write!(f, " ")?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ConfigError {
+    fn new(message: String) -> ConfigError {
+        ConfigError {
+            path: None,
+            line: None,
+            key: None,
+            message,
+        }
+    }
+}
+
+type ConfigResult<T> = std::result::Result<T, ConfigError>;
+
+/// one table of the file, with its name for error messages
+struct Section<'a> {
+    name: String,
+    table: &'a Table,
+}
+
+impl<'a> Section<'a> {
+    fn full_key(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    fn error(&self, key: &str, message: String) -> ConfigError {
+        ConfigError {
+            path: None,
+            line: self.table.entries.get(key).map(|x| x.line),
+            key: Some(self.full_key(key)),
+            message,
+        }
+    }
+
+    /// fails on the first key not in `known`, which is most likely a typo
+    fn check_keys(&self, known: &[&str]) -> ConfigResult<()> {
+        match self
+            .table
+            .entries
+            .iter()
+            .find(|(key, _)| !known.contains(&key.as_str()))
+        {
+            Some((key, _)) => Err(self.error(key, "unknown key".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn entry(&self, key: &str) -> Option<&'a Entry> {
+        self.table.entries.get(key)
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, found: &Value) -> ConfigError {
+        self.error(
+            key,
+            format!("expected {}, found {}", expected, found.type_name()),
+        )
+    }
+
+    fn string(&self, key: &str) -> ConfigResult<Option<String>> {
+        match self.entry(key).map(|x| &x.value) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(x) => Err(self.wrong_type(key, "a string", x)),
+        }
+    }
+
+    fn strings(&self, key: &str) -> ConfigResult<Option<Vec<String>>> {
+        match self.entry(key).map(|x| &x.value) {
+            None => Ok(None),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|x| match x {
+                    Value::String(s) => Ok(s.clone()),
+                    x => Err(self.wrong_type(key, "an array of strings", x)),
+                })
+                .collect::<ConfigResult<_>>()
+                .map(Some),
+            Some(x) => Err(self.wrong_type(key, "an array of strings", x)),
+        }
+    }
+
+    /// a whole number no smaller than `min`
+    fn count(&self, key: &str, min: i64) -> ConfigResult<Option<usize>> {
+        match self.entry(key).map(|x| &x.value) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if *n >= min => Ok(Some(*n as usize)),
+            Some(Value::Integer(_)) => {
+                Err(self.error(key, format!("expected a number of at least {}", min)))
+            }
+            Some(x) => Err(self.wrong_type(key, "an integer", x)),
+        }
+    }
+
+    /// a string run through `parse`, whose error becomes the message
+    fn parsed<T>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> ConfigResult<Option<T>> {
+        match self.string(key)? {
+            Some(s) => parse(&s).map(Some).map_err(|e| self.error(key, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn parsed_list<T>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> ConfigResult<Option<Vec<T>>> {
+        match self.strings(key)? {
+            Some(items) => items
+                .iter()
+                .map(|x| parse(x).map_err(|e| self.error(key, e)))
+                .collect::<ConfigResult<_>>()
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `500ms`, `2s` or `1m`, never zero
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {:?}, expected e.g. 500ms, 2s or 1m", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let amount: u64 = match s[..split].parse() {
+        Ok(amount) if amount > 0 => amount,
+        _ => return Err(invalid()),
+    };
+    match &s[split..] {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        _ => Err(invalid()),
+    }
+}
+
+/// `ip:port`, or `ip` for the default port
+fn parse_addr(s: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    match s.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() > 0 => Ok(addr),
+        _ => Err(format!("invalid address {:?}, expected ip or ip:port", s)),
+    }
+}
+
+impl Config {
+    /// reads and validates a config file. Relative paths in it are taken
+    /// from the directory the file is in.
+    pub fn load(path: &Path) -> ConfigResult<Config> {
+        let with_path = |mut e: ConfigError| {
+            e.path = Some(path.to_path_buf());
+            e
+        };
+        let text =
+            fs::read_to_string(path).map_err(|e| with_path(ConfigError::new(e.to_string())))?;
+        let mut config = Config::from_str(&text).map_err(with_path)?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if let Some(hints) = &config.root_hints {
+            config.root_hints = Some(dir.join(hints));
+        }
+        for zone in config.zones.iter_mut() {
+            zone.file = dir.join(&zone.file);
+        }
+        Ok(config)
+    }
+
+    /// command line options win over whatever the file says
+    pub fn apply(&mut self, options: &Options) -> ConfigResult<()> {
+        if options.listen.is_some() || options.port.is_some() {
+            let first = self.listen.first().copied();
+            let ip = options
+                .listen
+                .or_else(|| first.map(|x| x.ip()))
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+            let port = options
+                .port
+                .or_else(|| first.map(|x| x.port()))
+                .unwrap_or(DEFAULT_PORT);
+            self.listen = vec![SocketAddr::new(ip, port)];
+        }
+        if let Some(mode) = options.mode {
+            self.mode = mode;
+        }
+        if !options.upstreams.is_empty() {
+            self.upstreams = options.upstreams.clone();
+        }
+        if let Some(hints) = &options.root_hints {
+            self.root_hints = Some(hints.clone());
+        }
+        if let Some(size) = options.cache_size {
+            self.cache_size = size;
+        }
+        if let Some(level) = options.log_level {
+            self.log_level = level;
+        }
+        self.validate()
+    }
+
+    /// settings which are fine one by one, but not together
+    pub fn validate(&self) -> ConfigResult<()> {
+        let error = |key: &str, message: &str| {
+            Err(ConfigError {
+                key: Some(key.to_string()),
+                ..ConfigError::new(message.to_string())
+            })
+        };
+        if self.listen.is_empty() {
+            return error("server.listen", "at least one address is needed");
+        }
+        match self.mode {
+            Mode::Forwarder if self.upstreams.is_empty() => error(
+                "resolver.upstreams",
+                "forwarder mode needs at least one upstream",
+            ),
+            Mode::Forwarder if self.root_hints.is_some() => error(
+                "resolver.root_hints",
+                "root hints only apply to recursive mode",
+            ),
+            Mode::Recursive if !self.upstreams.is_empty() => error(
+                "resolver.upstreams",
+                "upstreams only apply to forwarder mode",
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    /// parses and validates a config, with paths left as they are written
+    fn from_str(text: &str) -> ConfigResult<Config> {
+        let doc = toml::parse(text).map_err(|e| ConfigError {
+            line: Some(e.line),
+            ..ConfigError::new(e.message)
+        })?;
+        let mut config = Config::default();
+
+        for (name, table) in doc.tables.iter() {
+            let section = Section {
+                name: name.clone(),
+                table,
+            };
+            match name.as_str() {
+                "" => section.check_keys(&[])?,
+                "server" => config.read_server(&section)?,
+                "resolver" => config.read_resolver(&section)?,
+                "timeouts" => config.read_timeouts(&section)?,
+                "cache" => {
+                    section.check_keys(&["size"])?;
+                    if let Some(size) = section.count("size", 1)? {
+                        config.cache_size = size;
+                    }
+                }
+                "acl" => {
+                    section.check_keys(&["allow", "deny"])?;
+                    if let Some(allow) = section.parsed_list("allow", Network::from_str)? {
+                        config.acl.allow = allow;
+                    }
+                    if let Some(deny) = section.parsed_list("deny", Network::from_str)? {
+                        config.acl.deny = deny;
+                    }
+                }
+                "log" => {
+                    section.check_keys(&["level"])?;
+                    if let Some(level) = section.parsed("level", Level::from_str)? {
+                        config.log_level = level;
+                    }
+                }
+                _ => {
+                    return Err(ConfigError {
+                        line: Some(table.line),
+                        key: Some(name.clone()),
+                        ..ConfigError::new("unknown table".to_string())
+                    })
+                }
+            }
+        }
+
+        for (name, tables) in doc.arrays.iter() {
+            for table in tables.iter() {
+                let section = Section {
+                    name: name.clone(),
+                    table,
+                };
+                match name.as_str() {
+                    "zone" => config.zones.push(read_zone(&section)?),
+                    "forward" => config.forward.push(read_forward(&section)?),
+                    _ => {
+                        return Err(ConfigError {
+                            line: Some(table.line),
+                            key: Some(name.clone()),
+                            ..ConfigError::new("unknown table".to_string())
+                        })
+                    }
+                }
+            }
+        }
+
+        // point at the line of a key that's only wrong in combination
+        config.validate().map_err(|mut e| {
+            let entry = e.key.as_ref().and_then(|key| {
+                let (table, key) = key.split_at(key.find('.')?);
+                doc.tables.get(table)?.entries.get(&key[1..])
+            });
+            e.line = entry.map(|x| x.line);
+            e
+        })?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    fn read_server(&mut self, section: &Section) -> ConfigResult<()> {
+        section.check_keys(&[
+            "listen",
+            "mode",
+            "workers",
+            "queue_size",
+            "tcp_connections",
+            "tcp_connections_per_client",
+        ])?;
+        if let Some(listen) = section.parsed_list("listen", |x| parse_addr(x, DEFAULT_PORT))? {
+            self.listen = listen;
+        }
+        if let Some(mode) = section.parsed("mode", Mode::from_str)? {
+            self.mode = mode;
+        }
+        if let Some(workers) = section.count("workers", 1)? {
+            self.workers = workers;
+        }
+        if let Some(size) = section.count("queue_size", 1)? {
+            self.queue_size = size;
+        }
+        if let Some(connections) = section.count("tcp_connections", 1)? {
+            self.tcp_connections = connections;
+        }
+        if let Some(connections) = section.count("tcp_connections_per_client", 1)? {
+            self.tcp_connections_per_client = connections;
+        }
+        Ok(())
+    }
+
+    fn read_resolver(&mut self, section: &Section) -> ConfigResult<()> {
+        section.check_keys(&["root_hints", "upstreams"])?;
+        if let Some(hints) = section.string("root_hints")? {
+            self.root_hints = Some(PathBuf::from(hints));
+        }
+        if let Some(upstreams) = section.parsed_list("upstreams", |x| parse_addr(x, 53))? {
+            self.upstreams = upstreams;
+        }
+        Ok(())
+    }
+
+    fn read_timeouts(&mut self, section: &Section) -> ConfigResult<()> {
+        section.check_keys(&["query", "retries", "deadline", "tcp_idle"])?;
+        if let Some(timeout) = section.parsed("query", parse_duration)? {
+            self.timeout = timeout;
+        }
+        if let Some(retries) = section.count("retries", 0)? {
+            self.retries = retries;
+        }
+        if let Some(deadline) = section.parsed("deadline", parse_duration)? {
+            self.deadline = deadline;
+        }
+        if let Some(idle) = section.parsed("tcp_idle", parse_duration)? {
+            self.tcp_idle_timeout = idle;
+        }
+        Ok(())
+    }
+}
+
+fn missing(section: &Section, key: &str) -> ConfigError {
+    ConfigError {
+        line: Some(section.table.line),
+        key: Some(section.full_key(key)),
+        ..ConfigError::new("missing".to_string())
+    }
+}
+
+fn read_forward(section: &Section) -> ConfigResult<ForwardRule> {
+    section.check_keys(&["zone", "upstreams"])?;
+    let zone = section
+        .string("zone")?
+        .ok_or_else(|| missing(section, "zone"))?;
+    let upstreams = section
+        .parsed_list("upstreams", |x| parse_addr(x, 53))?
+        .ok_or_else(|| missing(section, "upstreams"))?;
+    if upstreams.is_empty() {
+        return Err(section.error("upstreams", "at least one upstream is needed".to_string()));
+    }
+    Ok(ForwardRule {
+        zone: normalize_name(&zone),
+        upstreams,
+    })
+}
+
+fn read_zone(section: &Section) -> ConfigResult<LocalZone> {
+    section.check_keys(&["name", "file"])?;
+    let missing = |key: &str| missing(section, key);
+    Ok(LocalZone {
+        name: normalize_name(&section.string("name")?.ok_or_else(|| missing("name"))?),
+        file: PathBuf::from(section.string("file")?.ok_or_else(|| missing("file"))?),
+    })
+}
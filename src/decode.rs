@@ -0,0 +1,480 @@
+use super::buffer::{BytePacketBuffer, ParseError, ParseResult};
+use super::cli::UsageError;
+use super::packet::{class_name, escape_text, QueryType, ResponseCode};
+use super::zone::parse_hex;
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+pub const USAGE: &str = "\
+druns-decode, prints DNS messages saved as raw bytes, hex or base64
+
+usage: druns-decode [options] [file ...]
+
+Reads standard input when no file, or -, is given. Each file holds one message.
+
+options:
+  -f, --format <format>   raw, hex, base64 or auto [default: auto]. Hex and
+                          base64 may be split by whitespace
+  -n, --no-dump           leave out the annotated hex dump
+  -h, --help              print this help
+  -V, --version           print the version
+";
+
+/// bytes shown on each line of the hex dump
+const BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Raw,
+    Hex,
+    Base64,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Raw => f.write_str("raw"),
+            Format::Hex => f.write_str("hex"),
+            Format::Base64 => f.write_str("base64"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// None to guess from the input
+    pub format: Option<Format>,
+    pub dump: bool,
+    /// empty, or "-", for standard input
+    pub files: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            format: None,
+            dump: true,
+            files: vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+pub fn parse_args<I, S>(args: I) -> std::result::Result<Command, UsageError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut options = Options::default();
+    let mut args = args.into_iter().map(Into::into);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-n" | "--no-dump" => options.dump = false,
+            "-f" | "--format" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| UsageError(format!("{} needs a value", arg)))?;
+                options.format = match value.as_str() {
+                    "raw" => Some(Format::Raw),
+                    "hex" => Some(Format::Hex),
+                    "base64" => Some(Format::Base64),
+                    "auto" => None,
+                    _ => return Err(UsageError(format!("unknown format {:?}", value))),
+                };
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(UsageError(format!("unknown option {}", arg)))
+            }
+            _ => options.files.push(arg),
+        }
+    }
+    Ok(Command::Run(options))
+}
+
+/// the message held by `input`, along with the format it was in. Without a
+/// format, text made only of hex digits is taken as hex, other text as base64
+/// when it decodes, and anything else as raw bytes.
+pub fn decode_input(
+    input: &[u8],
+    format: Option<Format>,
+) -> std::result::Result<(Format, Vec<u8>), String> {
+    let text = std::str::from_utf8(input)
+        .ok()
+        .map(|x| x.split_whitespace().collect::<String>());
+    let format = match (format, &text) {
+        (Some(format), _) => format,
+        (None, Some(text)) if text.is_empty() => Format::Raw,
+        (None, Some(text)) if parse_hex(text).is_ok() => Format::Hex,
+        (None, Some(text)) if decode_base64(text).is_ok() => Format::Base64,
+        (None, _) => Format::Raw,
+    };
+    let message = match (format, text) {
+        (Format::Raw, _) => input.to_vec(),
+        (Format::Hex, Some(text)) => parse_hex(&text)?,
+        (Format::Base64, Some(text)) => decode_base64(&text)?,
+        (_, None) => return Err(format!("{} input is not text", format)),
+    };
+    Ok((format, message))
+}
+
+/// standard or URL-safe base64, with or without padding
+pub fn decode_base64(text: &str) -> std::result::Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.trim_end_matches('=').chars() {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => return Err(format!("invalid base64 character {:?}", c)),
+        };
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // a single character left over carries less than a byte
+    if count >= 6 {
+        return Err("invalid base64 length".into());
+    }
+    Ok(bytes)
+}
+
+/// bytes of the message that make up one field
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub offset: usize,
+    pub length: usize,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    /// "header", "question 1", "answer 2" and so on
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+pub struct Annotation {
+    pub sections: Vec<Section>,
+    /// why the walk stopped early, the bytes after the last field are
+    /// unaccounted for
+    pub error: Option<ParseError>,
+}
+
+/// splits `bytes` into the fields of a DNS message. Unlike `Packet::read`,
+/// unknown opcodes are described rather than rejected, and the
+/// walk keeps the fields it found before a malformed one.
+pub fn annotate(bytes: &[u8]) -> Annotation {
+    let mut walker = Walker {
+        buffer: BytePacketBuffer::from_bytes(bytes),
+        pos: 0,
+        sections: vec![],
+    };
+    let error = walker.message().err();
+    Annotation {
+        sections: walker.sections,
+        error,
+    }
+}
+
+/// the annotated hex dump of `bytes`, one field per line
+pub fn dump(bytes: &[u8]) -> String {
+    let annotation = annotate(bytes);
+    let mut out = String::new();
+    let mut end = 0;
+    for section in annotation.sections.iter() {
+        out += &format!(";; {}\n", section.name);
+        for field in section.fields.iter() {
+            dump_field(
+                &mut out,
+                bytes,
+                field.offset,
+                field.length,
+                &field.description,
+            );
+            end = field.offset + field.length;
+        }
+    }
+    if let Some(error) = annotation.error {
+        out += &format!(";; error: {}\n", error);
+        if end < bytes.len() {
+            out += ";; unparsed\n";
+            dump_field(&mut out, bytes, end, bytes.len() - end, "");
+        }
+    }
+    out
+}
+
+fn dump_field(out: &mut String, bytes: &[u8], offset: usize, length: usize, description: &str) {
+    let chunks = bytes[offset..offset + length].chunks(BYTES_PER_LINE);
+    for (i, chunk) in chunks.enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|x| format!("{:02x}", x)).collect();
+        let line = format!(
+            "{:04x}  {:<width$}  {}",
+            offset + i * BYTES_PER_LINE,
+            hex.join(" "),
+            if i == 0 { description } else { "" },
+            width = BYTES_PER_LINE * 3 - 1
+        );
+        *out += line.trim_end();
+        out.push('\n');
+    }
+}
+
+struct Walker {
+    buffer: BytePacketBuffer,
+    pos: usize,
+    sections: Vec<Section>,
+}
+
+impl Walker {
+    fn message(&mut self) -> ParseResult<()> {
+        self.section("header".to_string());
+        if self.buffer.size < 12 {
+            return Err(ParseError::TruncatedHeader {
+                offset: self.buffer.size,
+            });
+        }
+        self.u16(|id| format!("id {}", id))?;
+        self.u16(describe_flags)?;
+        let questions = self.u16(|x| format!("questions {}", x))?;
+        let counts = [
+            ("answer", self.u16(|x| format!("answers {}", x))?),
+            ("authority", self.u16(|x| format!("authority {}", x))?),
+            ("additional", self.u16(|x| format!("additional {}", x))?),
+        ];
+
+        for i in 0..questions {
+            self.section(format!("question {}", i + 1));
+            self.name()?;
+            self.u16(|x| format!("type {}", QueryType::from_num(x)))?;
+            self.u16(|x| format!("class {}", class_name(x)))?;
+        }
+        for (section, count) in counts.iter() {
+            for i in 0..*count {
+                self.section(format!("{} {}", section, i + 1));
+                self.record()?;
+            }
+        }
+
+        if self.pos < self.buffer.size {
+            self.section("trailing data".to_string());
+            self.take(self.buffer.size - self.pos, "past the records".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self) -> ParseResult<()> {
+        self.name()?;
+        let rtype = self.u16(|x| format!("type {}", QueryType::from_num(x)))?;
+        if rtype == 41 {
+            self.u16(|x| format!("udp payload size {}", x))?;
+            self.u32(|x| {
+                format!(
+                    "extended rcode {}, version {}, flags{}",
+                    x >> 24,
+                    (x >> 16) & 0xff,
+                    if x & 0x8000 != 0 { " do" } else { "" }
+                )
+            })?;
+        } else {
+            self.u16(|x| format!("class {}", class_name(x)))?;
+            self.u32(|x| format!("ttl {}", x))?;
+        }
+        let length = self.u16(|x| format!("rdlength {}", x))? as usize;
+        self.buffer.read_bytes_from(length, self.pos)?;
+
+        // rdata that doesn't have the expected layout is shown as one field,
+        // Packet::read says what is wrong with it
+        let start = self.pos;
+        let fields = self.current().fields.len();
+        if self.rdata(rtype, start + length).is_err() || self.pos != start + length {
+            self.pos = start;
+            self.current().fields.truncate(fields);
+            self.take(length, "rdata".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn rdata(&mut self, rtype: u16, end: usize) -> ParseResult<()> {
+        let length = end - self.pos;
+        match QueryType::from_num(rtype) {
+            QueryType::A if length == 4 => {
+                let ip = self.buffer.read_bytes_from(4, self.pos)?;
+                let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+                self.take(4, format!("address {}", ip))
+            }
+            QueryType::AAAA if length == 16 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(self.buffer.read_bytes_from(16, self.pos)?);
+                self.take(16, format!("address {}", Ipv6Addr::from(ip)))
+            }
+            QueryType::NS | QueryType::CNAME | QueryType::PTR => self.name(),
+            QueryType::MX => {
+                self.u16(|x| format!("preference {}", x))?;
+                self.name()
+            }
+            QueryType::SOA => {
+                self.name()?;
+                self.name()?;
+                for field in ["serial", "refresh", "retry", "expire", "minimum"].iter() {
+                    self.u32(|x| format!("{} {}", field, x))?;
+                }
+                Ok(())
+            }
+            QueryType::SRV => {
+                for field in ["priority", "weight", "port"].iter() {
+                    self.u16(|x| format!("{} {}", field, x))?;
+                }
+                self.name()
+            }
+            QueryType::TXT => {
+                while self.pos < end {
+                    let length = self.buffer.read_u8_from(self.pos)? as usize;
+                    let text = self.buffer.read_bytes_from(length, self.pos + 1)?;
+                    self.take(length + 1, format!("string \"{}\"", escape_text(text)))?;
+                }
+                Ok(())
+            }
+            QueryType::UNKNOWN(41) => {
+                while self.pos < end {
+                    self.u16(|x| format!("option code {}", x))?;
+                    let length = self.u16(|x| format!("option length {}", x))?;
+                    self.take(length as usize, "option data".to_string())?;
+                }
+                Ok(())
+            }
+            _ => self.take(length, "rdata".to_string()),
+        }
+    }
+
+    /// one field per label, ending with the root label or a compression
+    /// pointer along with the name it points to
+    fn name(&mut self) -> ParseResult<()> {
+        loop {
+            let length = self.buffer.read_u8_from(self.pos)?;
+            match length & 0xC0 {
+                0xC0 => {
+                    let target = (((length & 0x3F) as usize) << 8)
+                        + self.buffer.read_u8_from(self.pos + 1)? as usize;
+                    if target >= self.pos {
+                        return Err(ParseError::PointerLoop {
+                            offset: self.pos,
+                            target,
+                        });
+                    }
+                    let (name, _) = self.buffer.read_qname_from(target)?;
+                    let name = if name.is_empty() {
+                        ".".to_string()
+                    } else {
+                        name
+                    };
+                    return self.take(2, format!("pointer to {:04x}: {}", target, name));
+                }
+                0x00 if length == 0 => return self.take(1, "root label".to_string()),
+                0x00 => {
+                    let label = self.buffer.read_bytes_from(length as usize, self.pos + 1)?;
+                    let label = format!("label \"{}\"", escape_text(label));
+                    self.take(length as usize + 1, label)?;
+                }
+                _ => {
+                    return Err(ParseError::BadLabel {
+                        offset: self.pos,
+                        length,
+                    })
+                }
+            }
+        }
+    }
+
+    fn u16(&mut self, describe: impl FnOnce(u16) -> String) -> ParseResult<u16> {
+        let value = self.buffer.read_u16_from(self.pos)?;
+        self.take(2, describe(value))?;
+        Ok(value)
+    }
+
+    fn u32(&mut self, describe: impl FnOnce(u32) -> String) -> ParseResult<u32> {
+        let high = self.buffer.read_u16_from(self.pos)? as u32;
+        let low = self.buffer.read_u16_from(self.pos + 2)? as u32;
+        let value = (high << 16) + low;
+        self.take(4, describe(value))?;
+        Ok(value)
+    }
+
+    fn take(&mut self, length: usize, description: String) -> ParseResult<()> {
+        self.buffer.read_bytes_from(length, self.pos)?;
+        let field = Field {
+            offset: self.pos,
+            length,
+            description,
+        };
+        self.current().fields.push(field);
+        self.pos += length;
+        Ok(())
+    }
+
+    fn section(&mut self, name: String) {
+        self.sections.push(Section {
+            name,
+            fields: vec![],
+        });
+    }
+
+    fn current(&mut self) -> &mut Section {
+        self.sections
+            .last_mut()
+            .expect("walk starts with a section")
+    }
+}
+
+fn describe_flags(flags: u16) -> String {
+    let mut description = String::from("flags");
+    let names = [
+        (15, "qr"),
+        (10, "aa"),
+        (9, "tc"),
+        (8, "rd"),
+        (7, "ra"),
+        (5, "ad"),
+        (4, "cd"),
+    ];
+    for (bit, name) in names.iter() {
+        if flags & (1 << bit) != 0 {
+            description += " ";
+            description += name;
+        }
+    }
+    let opcode = match (flags >> 11) & 0xf {
+        0 => "QUERY".to_string(),
+        1 => "IQUERY".to_string(),
+        2 => "STATUS".to_string(),
+        4 => "NOTIFY".to_string(),
+        5 => "UPDATE".to_string(),
+        x => format!("OPCODE{}", x),
+    };
+    format!(
+        "{}, opcode {}, rcode {}",
+        description,
+        opcode,
+        ResponseCode::from(flags & 0xf)
+    )
+}
@@ -0,0 +1,515 @@
+use super::buffer::{BytePacketBuffer, Result, MAX_MESSAGE_SIZE};
+use super::cli::{UsageError, DEFAULT_PORT};
+use super::lookup::{create_request_packet, Protocol};
+use super::packet::{
+    class_name, display_name, is_subdomain, normalize_name, Packet, PacketType, QueryType, Record,
+    ResponseCode,
+};
+use super::tcp;
+use super::zone::{absolute_name, parse_class, parse_type};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+pub const USAGE: &str = "\
+druns-dig, sends a DNS query and prints the reply
+
+usage: druns-dig [@server] [name] [type] [class] [options]
+
+server is ip, ip:port or [ipv6]:port [default: 127.0.0.1:34254]. Without a
+name, the root servers are asked for.
+
+options:
+  -p, --port <port>       port of the server, when it isn't given with it
+  +tcp                    query over TCP instead of UDP
+  +norecurse              leave the RD flag unset
+  +dnssec                 set the DO flag
+  +bufsize=<n>            EDNS buffer size to advertise [default: 1232]
+  +noedns                 send no OPT record at all
+  +timeout=<seconds>      how long to wait for each reply [default: 5]
+  +trace                  follow the delegations down from the root servers,
+                          asking the server only for nameserver addresses
+  +json                   print JSON instead of dig style sections
+  -h, --help              print this help
+  -V, --version           print the version
+";
+
+pub const DEFAULT_BUFSIZE: u16 = 1232;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// referrals followed by +trace before giving up
+const MAX_TRACE_STEPS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub server: SocketAddr,
+    /// absolute, "" for the root
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: u16,
+    pub tcp: bool,
+    pub recurse: bool,
+    pub dnssec: bool,
+    /// None to send the query without EDNS
+    pub bufsize: Option<u16>,
+    pub timeout: Duration,
+    pub trace: bool,
+    pub json: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            server: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+            name: String::new(),
+            qtype: QueryType::NS,
+            class: 1,
+            tcp: false,
+            recurse: true,
+            dnssec: false,
+            bufsize: Some(DEFAULT_BUFSIZE),
+            timeout: DEFAULT_TIMEOUT,
+            trace: false,
+            json: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+fn usage_error<T>(message: String) -> std::result::Result<T, UsageError> {
+    Err(UsageError(message))
+}
+
+/// parses the arguments after the program name, which may come in any order
+/// as long as the type and class follow the name
+pub fn parse_args<I, S>(args: I) -> std::result::Result<Command, UsageError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut options = Options::default();
+    let mut server: Option<String> = None;
+    let mut port: Option<u16> = None;
+    let mut name: Option<String> = None;
+    let mut qtype: Option<QueryType> = None;
+    let mut class: Option<u16> = None;
+    let mut args = args.into_iter().map(Into::into);
+
+    while let Some(arg) = args.next() {
+        if let Some(flag) = arg.strip_prefix('+') {
+            let (flag, value) = match flag.find('=') {
+                Some(i) => (&flag[..i], Some(&flag[i + 1..])),
+                None => (flag, None),
+            };
+            let (flag, on) = match flag.strip_prefix("no") {
+                Some(flag) => (flag, false),
+                None => (flag, true),
+            };
+            match (flag, value) {
+                ("tcp", None) => options.tcp = on,
+                ("recurse", None) | ("rec", None) => options.recurse = on,
+                ("dnssec", None) => options.dnssec = on,
+                ("edns", None) if on => options.bufsize = options.bufsize.or(Some(DEFAULT_BUFSIZE)),
+                ("edns", None) => options.bufsize = None,
+                ("trace", None) => options.trace = on,
+                ("json", None) => options.json = on,
+                ("bufsize", Some(value)) if on => {
+                    options.bufsize =
+                        Some(value.parse().or_else(|_| {
+                            usage_error(format!("invalid buffer size {:?}", value))
+                        })?);
+                }
+                ("timeout", Some(value)) if on => {
+                    options.timeout = match value.parse::<u64>() {
+                        Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                        _ => return usage_error(format!("invalid timeout {:?}", value)),
+                    };
+                }
+                _ => return usage_error(format!("unknown option {}", arg)),
+            }
+            continue;
+        }
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-p" | "--port" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| UsageError(format!("{} needs a value", arg)))?;
+                port = match value.parse() {
+                    Ok(port) if port > 0 => Some(port),
+                    _ => return usage_error(format!("invalid port {:?}", value)),
+                };
+            }
+            _ if arg.starts_with('@') => server = Some(arg[1..].to_string()),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return usage_error(format!("unknown option {}", arg))
+            }
+            _ if name.is_none() => name = Some(arg),
+            _ if qtype.is_none() && parse_type(&arg).is_some() => qtype = parse_type(&arg),
+            _ if class.is_none() && parse_class(&arg).is_some() => class = parse_class(&arg),
+            _ => return usage_error(format!("unexpected argument {:?}", arg)),
+        }
+    }
+
+    if let Some(server) = server {
+        options.server = parse_server(&server, port.unwrap_or(DEFAULT_PORT))?;
+    } else if let Some(port) = port {
+        options.server.set_port(port);
+    }
+    if let Some(name) = name {
+        options.name = absolute_name(&name, "").map_err(UsageError)?;
+        options.qtype = QueryType::A;
+    }
+    options.qtype = qtype.unwrap_or(options.qtype);
+    options.class = class.unwrap_or(options.class);
+    Ok(Command::Run(options))
+}
+
+fn parse_server(server: &str, port: u16) -> std::result::Result<SocketAddr, UsageError> {
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    server.parse().or_else(|_| {
+        usage_error(format!(
+            "invalid server {:?}, expected ip, ip:port or [ipv6]:port",
+            server
+        ))
+    })
+}
+
+/// a query and the reply it got
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub server: SocketAddr,
+    pub protocol: Protocol,
+    pub response: Packet,
+    /// of the reply
+    pub size: usize,
+    pub elapsed: Duration,
+}
+
+/// the query `options` describe
+pub fn request(options: &Options) -> Packet {
+    let mut request = create_request_packet(&options.name, options.qtype);
+    request.questions[0].class = options.class;
+    request.header.recursion_desired = options.recurse;
+    request.set_edns(options.bufsize.map(|udp_payload_size| Record::OPT {
+        udp_payload_size,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok: options.dnssec,
+        options: vec![],
+    }));
+    request.update_counts();
+    request
+}
+
+pub fn query(options: &Options) -> Result<Exchange> {
+    send(
+        options.server,
+        &request(options),
+        options.tcp,
+        options.timeout,
+    )
+}
+
+/// sends `request` and waits up to `timeout` for the reply. Replies cut short
+/// over UDP are asked for again over TCP.
+pub fn send(
+    server: SocketAddr,
+    request: &Packet,
+    tcp: bool,
+    timeout: Duration,
+) -> Result<Exchange> {
+    let mut buffer = BytePacketBuffer::new_empty();
+    request.write(&mut buffer)?;
+    let start = Instant::now();
+
+    let mut reply = if tcp {
+        tcp::exchange(server, &buffer, timeout)?
+    } else {
+        send_udp(server, &buffer, request.header.id, timeout)?
+    };
+    let size = reply.size;
+    let response = Packet::read(&mut reply)?;
+    if response.header.is_truncated && !tcp {
+        return send(server, request, true, timeout);
+    }
+    Ok(Exchange {
+        server,
+        protocol: if tcp { Protocol::Tcp } else { Protocol::Udp },
+        response,
+        size,
+        elapsed: start.elapsed(),
+    })
+}
+
+fn send_udp(
+    server: SocketAddr,
+    request: &BytePacketBuffer,
+    id: u16,
+    timeout: Duration,
+) -> Result<BytePacketBuffer> {
+    let local: SocketAddr = if server.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let socket = UdpSocket::bind(local)?;
+    // only datagrams from the server get through
+    socket.connect(server)?;
+    socket.send(&request[0..request.size])?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(format!("no reply from {} within {:?}", server, timeout).into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let mut reply = BytePacketBuffer::with_max_size(MAX_MESSAGE_SIZE);
+        reply.size = match socket.recv(&mut reply) {
+            Ok(size) => size,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // anything else is a late reply to some earlier query
+        if reply.size >= 2 && u16::from_be_bytes([reply[0], reply[1]]) == id {
+            return Ok(reply);
+        }
+    }
+}
+
+/// resolves the question step by step from `roots`, reporting each reply to
+/// `step` as it comes. Nameservers without glue are looked up through the
+/// server of `options`.
+pub fn trace<F>(options: &Options, roots: &[IpAddr], port: u16, mut step: F) -> Result<()>
+where
+    F: FnMut(&Exchange),
+{
+    let mut request = request(options);
+    request.header.recursion_desired = false;
+    let mut zone = String::new();
+    let mut servers: Vec<SocketAddr> = roots.iter().map(|x| SocketAddr::new(*x, port)).collect();
+
+    for _ in 0..MAX_TRACE_STEPS {
+        let exchange = ask_any(&servers, &request, options)?;
+        step(&exchange);
+        let response = &exchange.response;
+        if response.header.rcode != ResponseCode::no_error || !response.answers.is_empty() {
+            return Ok(());
+        }
+
+        // a referral has to lead further down
+        let next_zone = response.authority.iter().find_map(|x| match x {
+            Record::NS { name, .. }
+                if is_subdomain(name, &zone) && normalize_name(name) != zone =>
+            {
+                Some(normalize_name(name))
+            }
+            _ => None,
+        });
+        let next_zone = match next_zone {
+            Some(next_zone) => next_zone,
+            None => return Ok(()),
+        };
+        let hosts: Vec<String> = response
+            .authority
+            .iter()
+            .filter_map(|x| match x {
+                Record::NS { name, host, .. } if normalize_name(name) == next_zone => {
+                    Some(normalize_name(host))
+                }
+                _ => None,
+            })
+            .collect();
+
+        servers = response
+            .additional
+            .iter()
+            .filter_map(|x| match x {
+                Record::A { name, ip, .. } if hosts.contains(&normalize_name(name)) => {
+                    Some(SocketAddr::new(IpAddr::from(*ip), port))
+                }
+                Record::AAAA { name, ip, .. } if hosts.contains(&normalize_name(name)) => {
+                    Some(SocketAddr::new(IpAddr::from(*ip), port))
+                }
+                _ => None,
+            })
+            .collect();
+        if servers.is_empty() {
+            servers = lookup_addresses(&hosts, port, options);
+        }
+        if servers.is_empty() {
+            return Err(format!("no addresses for the nameservers of {}", next_zone).into());
+        }
+        zone = next_zone;
+    }
+    Err(format!("more than {} referrals", MAX_TRACE_STEPS).into())
+}
+
+/// the first reply from `servers`, tried in order
+fn ask_any(servers: &[SocketAddr], request: &Packet, options: &Options) -> Result<Exchange> {
+    let mut last_error = None;
+    for server in servers.iter() {
+        match send(*server, request, options.tcp, options.timeout) {
+            Ok(exchange) => return Ok(exchange),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "no servers to ask".into()))
+}
+
+/// ipv4 addresses of the first of `hosts` the server of `options` resolves
+fn lookup_addresses(hosts: &[String], port: u16, options: &Options) -> Vec<SocketAddr> {
+    for host in hosts.iter() {
+        let mut host_options = options.clone();
+        host_options.name = host.clone();
+        host_options.qtype = QueryType::A;
+        host_options.recurse = true;
+        if let Ok(exchange) = query(&host_options) {
+            let addresses: Vec<SocketAddr> = exchange
+                .response
+                .answers
+                .iter()
+                .filter_map(|x| match x {
+                    Record::A { ip, .. } => Some(SocketAddr::new(IpAddr::from(*ip), port)),
+                    _ => None,
+                })
+                .collect();
+            if !addresses.is_empty() {
+                return addresses;
+            }
+        }
+    }
+    vec![]
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Udp => "udp",
+        Protocol::Tcp => "tcp",
+    }
+}
+
+/// the reply in dig style, with where it came from and how long it took
+pub fn to_text(exchange: &Exchange) -> String {
+    format!(
+        "{}\n;; Query time: {} msec\n;; SERVER: {}({})\n;; MSG SIZE  rcvd: {}\n",
+        exchange.response,
+        exchange.elapsed.as_millis(),
+        exchange.server,
+        protocol_name(exchange.protocol),
+        exchange.size
+    )
+}
+
+/// the reply as a JSON object
+pub fn to_json(exchange: &Exchange) -> String {
+    let response = &exchange.response;
+    let header = &response.header;
+    let flags: Vec<String> = [
+        ("qr", header.qr == PacketType::Response),
+        ("aa", header.authoritative),
+        ("tc", header.is_truncated),
+        ("rd", header.recursion_desired),
+        ("ra", header.recursion_available),
+    ]
+    .iter()
+    .filter(|(_, set)| *set)
+    .map(|(name, _)| json_string(name))
+    .collect();
+    let questions: Vec<String> = response
+        .questions
+        .iter()
+        .map(|x| {
+            format!(
+                "{{\"name\":{},\"class\":{},\"type\":{}}}",
+                json_string(display_name(&x.name)),
+                json_string(&class_name(x.class)),
+                json_string(&x.qtype.to_string())
+            )
+        })
+        .collect();
+    let edns = match response.edns() {
+        Some(Record::OPT {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok,
+            ..
+        }) => format!(
+            "{{\"version\":{},\"udp\":{},\"do\":{},\"extended_rcode\":{}}}",
+            version, udp_payload_size, dnssec_ok, extended_rcode
+        ),
+        _ => "null".to_string(),
+    };
+
+    format!(
+        "{{\"server\":{},\"protocol\":{},\"time_ms\":{},\"size\":{},\"id\":{},\"opcode\":{},\
+         \"rcode\":{},\"flags\":[{}],\"question\":[{}],\"answer\":{},\"authority\":{},\
+         \"additional\":{},\"edns\":{}}}",
+        json_string(&exchange.server.to_string()),
+        json_string(protocol_name(exchange.protocol)),
+        exchange.elapsed.as_millis(),
+        exchange.size,
+        header.id,
+        header.opcode,
+        json_string(&header.rcode.to_string()),
+        flags.join(","),
+        questions.join(","),
+        json_records(&response.answers),
+        json_records(&response.authority),
+        json_records(&response.additional),
+        edns
+    )
+}
+
+fn json_records(records: &[Record]) -> String {
+    let records: Vec<String> = records
+        .iter()
+        .filter(|x| !matches!(x, Record::OPT { .. }))
+        .map(|x| {
+            format!(
+                "{{\"name\":{},\"ttl\":{},\"class\":{},\"type\":{},\"data\":{}}}",
+                json_string(display_name(x.name())),
+                x.ttl(),
+                json_string(&class_name(x.class())),
+                json_string(&QueryType::from_num(x.to_num()).to_string()),
+                json_string(&x.data_text())
+            )
+        })
+        .collect();
+    format!("[{}]", records.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,128 @@
+use super::packet::{is_subdomain, labels, normalize_name};
+use std::{
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// failures in a row after which an upstream is left alone for a while
+pub const MAX_FAILURES: u32 = 3;
+
+/// how long an upstream which keeps failing is skipped
+pub const HOLD_DOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    down_until: Option<Instant>,
+    /// smoothed round trip time of the answers so far
+    rtt: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    pub fn new(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    pub fn is_down(&self) -> bool {
+        matches!(self.health.lock().unwrap().down_until, Some(x) if x > Instant::now())
+    }
+
+    /// failures since the last answer
+    pub fn failures(&self) -> u32 {
+        self.health.lock().unwrap().failures
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.health.lock().unwrap().rtt
+    }
+
+    pub fn succeeded(&self, rtt: Duration) {
+        let mut health = self.health.lock().unwrap();
+        health.failures = 0;
+        health.down_until = None;
+        health.rtt = Some(match health.rtt {
+            Some(old) => (old * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    pub fn failed(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        if health.failures >= MAX_FAILURES {
+            health.down_until = Some(Instant::now() + HOLD_DOWN);
+        }
+    }
+}
+
+/// queries for names at or below `zone` go to `upstreams`
+#[derive(Debug)]
+pub struct Rule {
+    pub zone: String,
+    pub upstreams: Vec<Upstream>,
+}
+
+/// picks the upstreams for a name: the servers of the most specific rule
+/// covering it, or else the default ones. Without either, the name is
+/// resolved from the root as usual.
+#[derive(Debug, Default)]
+pub struct Forwarder {
+    rules: Vec<Rule>,
+    default: Vec<Upstream>,
+}
+
+impl Forwarder {
+    pub fn new(default: Vec<SocketAddr>) -> Forwarder {
+        Forwarder {
+            rules: vec![],
+            default: default.into_iter().map(Upstream::new).collect(),
+        }
+    }
+
+    pub fn add_rule(&mut self, zone: &str, upstreams: Vec<SocketAddr>) {
+        self.rules.push(Rule {
+            zone: normalize_name(zone),
+            upstreams: upstreams.into_iter().map(Upstream::new).collect(),
+        });
+        // most specific zone first
+        self.rules
+            .sort_by_key(|x| std::cmp::Reverse(labels(&x.zone).len()));
+    }
+
+    /// the zone of the matching rule, the root for the default upstreams,
+    /// along with the upstreams themselves
+    pub fn upstreams_for(&self, name: &str) -> Option<(&str, &[Upstream])> {
+        let (zone, upstreams) = self
+            .rules
+            .iter()
+            .find(|x| is_subdomain(name, &x.zone))
+            .map(|x| (x.zone.as_str(), x.upstreams.as_slice()))
+            .unwrap_or(("", &self.default));
+        if upstreams.is_empty() {
+            None
+        } else {
+            Some((zone, upstreams))
+        }
+    }
+
+    /// the order to try `upstreams` in: working ones first, fastest first,
+    /// with those held down only as a last resort
+    pub fn ordered(upstreams: &[Upstream]) -> Vec<&Upstream> {
+        let (mut up, down): (Vec<&Upstream>, Vec<&Upstream>) =
+            upstreams.iter().partition(|x| !x.is_down());
+        // ones not heard from yet go first, so every server gets measured
+        up.sort_by_key(|x| (x.failures(), x.rtt().unwrap_or_default()));
+        up.extend(down);
+        up
+    }
+}
@@ -0,0 +1,67 @@
+use super::buffer::Result;
+use super::packet::{normalize_name, QueryType, Record};
+use super::zone;
+use std::{fs, net::Ipv4Addr, path::Path};
+
+/// copy of the named.root file published by IANA, used when no other hints
+/// file is configured
+pub const DEFAULT: &str = include_str!("named.root");
+
+/// the root NS records in a named.root style hints file, along with the A and
+/// AAAA records of their hosts. It is a master file like any zone, just
+/// without the SOA.
+pub fn parse(text: &str) -> Result<Vec<Record>> {
+    let mut records = zone::parse_records(text, "")?;
+    for record in records.iter_mut() {
+        match record {
+            Record::NS { name, host, .. } if name.is_empty() => *host = normalize_name(host),
+            Record::NS { name, .. } => {
+                return Err(format!("NS record for {}, not the root", name).into())
+            }
+            Record::A { name, .. } | Record::AAAA { name, .. } => *name = normalize_name(name),
+            _ => {
+                return Err(format!(
+                    "unexpected {} record for {}",
+                    QueryType::from_num(record.to_num()),
+                    record.name()
+                )
+                .into())
+            }
+        }
+    }
+
+    if ipv4_addresses(&records).is_empty() {
+        return Err("no root server addresses found".into());
+    }
+    Ok(records)
+}
+
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// the built-in hints
+pub fn default() -> Vec<Record> {
+    parse(DEFAULT).expect("built-in root hints are valid")
+}
+
+/// ipv4 addresses of the root servers named by the NS records
+pub fn ipv4_addresses(records: &[Record]) -> Vec<Ipv4Addr> {
+    let hosts: Vec<&str> = records
+        .iter()
+        .filter_map(|x| match x {
+            Record::NS { host, .. } => Some(host.as_str()),
+            _ => None,
+        })
+        .collect();
+    records
+        .iter()
+        .filter_map(|x| match x {
+            Record::A { name, ip, .. } if hosts.contains(&name.as_str()) => {
+                Some(Ipv4Addr::from(*ip))
+            }
+            _ => None,
+        })
+        .collect()
+}
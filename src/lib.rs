@@ -1,3 +1,18 @@
+#[macro_use]
+pub mod log;
+
 pub mod buffer;
+pub mod cache;
+pub mod cli;
+pub mod config;
+pub mod decode;
+pub mod dig;
+pub mod forward;
+pub mod hints;
 pub mod lookup;
 pub mod packet;
+pub mod pcap;
+pub mod pool;
+pub mod tcp;
+mod toml;
+pub mod zone;
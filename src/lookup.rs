@@ -1,127 +1,1173 @@
-use super::buffer::{BytePacketBuffer, Result};
-use super::packet::{Header, Packet, PacketType, QueryType, Question, Record, ResponseCode};
+use super::buffer::{
+    BufferOverflow, BytePacketBuffer, ParseError, ParseResult, Result, DEFAULT_MAX_SIZE,
+    MAX_MESSAGE_SIZE,
+};
+use super::cache::{Cache, Negative};
+use super::config::{Acl, Config, Mode};
+use super::forward::{Forwarder, Upstream};
+use super::hints;
+use super::packet::{
+    is_subdomain, normalize_name, Header, Packet, PacketType, QueryType, Question, Record,
+    ResponseCode,
+};
+use super::pool::Pool;
+use super::tcp;
+use super::zone::{Zone, Zones};
 use std::{
-    net::{Ipv4Addr, UdpSocket},
-    str::FromStr,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub fn start() -> Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 34254))?;
+/// largest UDP message we advertise over EDNS, and accept from upstream
+pub const EDNS_PAYLOAD_SIZE: u16 = 4096;
+
+/// queries from a single client waiting for a worker
+const QUEUE_SIZE_PER_CLIENT: usize = 64;
+
+/// share of the workers a single client can keep busy at once
+const WORKERS_PER_CLIENT_DIVISOR: usize = 2;
+
+/// longest CNAME chain followed for a single query
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// how many nameserver lookups may be nested inside each other while
+/// following delegations without glue
+const MAX_NS_LOOKUP_DEPTH: usize = 4;
+
+/// random ports tried before letting the OS pick one
+const MAX_BIND_ATTEMPTS: usize = 8;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_RETRIES: usize = 1;
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// number of RRsets kept in the cache
+pub const DEFAULT_CACHE_SIZE: usize = 10000;
+
+/// how long to wait before priming again after every root server failed
+const PRIMING_RETRY: Duration = Duration::from_secs(30);
+
+/// State shared by every query the server handles
+pub struct Resolver {
+    pub cache: Mutex<Cache>,
+    /// addresses from the root hints, asked when the cache has no root
+    /// servers of its own
+    pub root_servers: Vec<Ipv4Addr>,
+    /// port used to reach upstream servers, only ever changed by tests
+    pub upstream_port: u16,
+    /// names sent to other resolvers instead of being resolved here
+    pub forwarder: Forwarder,
+    /// zones answered from local files, with authority
+    pub zones: Zones,
+    /// how long to wait for each reply from an upstream server
+    pub timeout: Duration,
+    /// how many more times a server is asked after the first try times out
+    pub retries: usize,
+    /// time allowed for resolving a client query, across every server asked
+    pub deadline: Duration,
+}
+
+impl Resolver {
+    pub fn new(root_servers: Vec<Ipv4Addr>, cache_size: usize) -> Resolver {
+        Resolver {
+            cache: Mutex::new(Cache::new(cache_size)),
+            root_servers,
+            upstream_port: 53,
+            forwarder: Forwarder::default(),
+            zones: Zones::default(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            deadline: DEFAULT_DEADLINE,
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(hints::ipv4_addresses(&hints::default()), DEFAULT_CACHE_SIZE)
+    }
+}
+
+fn forwarder(config: &Config) -> Forwarder {
+    let default = match config.mode {
+        Mode::Forwarder => config.upstreams.clone(),
+        Mode::Recursive => vec![],
+    };
+    let mut forwarder = Forwarder::new(default);
+    for rule in config.forward.iter() {
+        forwarder.add_rule(&rule.zone, rule.upstreams.clone());
+    }
+    forwarder
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// everything the listeners share
+struct Server {
+    resolver: Resolver,
+    pool: Pool,
+    acl: Acl,
+    tcp_idle_timeout: Duration,
+    /// open client connections by client, each one has a thread
+    tcp_connections: Mutex<HashMap<IpAddr, usize>>,
+    max_tcp_connections: usize,
+    max_tcp_connections_per_client: usize,
+}
+
+impl Server {
+    /// takes a connection slot for `client`, false if it already has as many
+    /// as it may, or everyone together does
+    fn open_tcp_connection(&self, client: IpAddr) -> bool {
+        let mut open = self.tcp_connections.lock().unwrap();
+        let total: usize = open.values().sum();
+        let from_client = open.entry(client).or_default();
+        if total >= self.max_tcp_connections || *from_client >= self.max_tcp_connections_per_client
+        {
+            if *from_client == 0 {
+                open.remove(&client);
+            }
+            return false;
+        }
+        *from_client += 1;
+        true
+    }
+
+    fn close_tcp_connection(&self, client: IpAddr) {
+        let mut open = self.tcp_connections.lock().unwrap();
+        if let Some(from_client) = open.get_mut(&client) {
+            *from_client -= 1;
+            if *from_client == 0 {
+                open.remove(&client);
+            }
+        }
+    }
+}
+
+pub fn start(config: &Config) -> Result<()> {
+    let hints = match &config.root_hints {
+        Some(path) => hints::read(path)?,
+        None => hints::default(),
+    };
+    let mut resolver = Resolver::new(hints::ipv4_addresses(&hints), config.cache_size);
+    resolver.timeout = config.timeout;
+    resolver.retries = config.retries;
+    resolver.deadline = config.deadline;
+    resolver.forwarder = forwarder(config);
+    for zone in config.zones.iter() {
+        let zone = Zone::load(&zone.file, &zone.name)?;
+        info!(
+            "serving {} with {} records",
+            zone.origin,
+            zone.records().len()
+        );
+        resolver.zones.insert(zone);
+    }
+    let server = Arc::new(Server {
+        resolver,
+        pool: Pool::new(
+            config.workers,
+            config.queue_size,
+            QUEUE_SIZE_PER_CLIENT,
+            config.workers / WORKERS_PER_CLIENT_DIVISOR,
+        ),
+        acl: config.acl.clone(),
+        tcp_idle_timeout: config.tcp_idle_timeout,
+        tcp_connections: Mutex::new(HashMap::new()),
+        max_tcp_connections: config.tcp_connections,
+        max_tcp_connections_per_client: config.tcp_connections_per_client,
+    });
+
+    let mut threads = vec![];
+    if config.mode == Mode::Recursive {
+        let priming_server = server.clone();
+        threads.push(thread::spawn(move || keep_primed(&priming_server.resolver)));
+    }
+    for addr in config.listen.iter() {
+        let listener = TcpListener::bind(addr)?;
+        let tcp_server = server.clone();
+        threads.push(thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let server = tcp_server.clone();
+                        let client = match stream.peer_addr() {
+                            Ok(addr) => addr.ip(),
+                            Err(e) => {
+                                error!("error accepting connection: {}", e);
+                                continue;
+                            }
+                        };
+                        if !server.open_tcp_connection(client) {
+                            warn!("too many tcp connections, closing the one from {}", client);
+                            continue;
+                        }
+                        thread::spawn(move || {
+                            if let Err(e) = handle_tcp_connection(&server, stream) {
+                                error!("error on tcp connection: {}", e);
+                            }
+                            server.close_tcp_connection(client);
+                        });
+                    }
+                    Err(e) => error!("error accepting connection: {}", e),
+                }
+            }
+        }));
+
+        let socket = Arc::new(UdpSocket::bind(addr)?);
+        let udp_server = server.clone();
+        threads.push(thread::spawn(move || loop {
+            match handle_query(&udp_server, &socket) {
+                Ok(_) => {}
+                Err(e) => error!("error occured: {}", e),
+            }
+        }));
+        info!("listening on {}", addr);
+    }
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+    Ok(())
+}
+
+/// primes the cache, and primes it again whenever the root NS set expires
+fn keep_primed(resolver: &Resolver) {
     loop {
-        match handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("error occured: {}", e),
+        let wait = match prime(resolver) {
+            Ok(ttl) => {
+                info!("primed the root servers for {}s", ttl);
+                Duration::from_secs(ttl as u64)
+            }
+            Err(e) => {
+                warn!("priming failed, using the root hints: {}", e);
+                PRIMING_RETRY
+            }
+        };
+        thread::sleep(wait.max(Duration::from_secs(1)));
+    }
+}
+
+/// asks the root servers from the hints for the current root NS set (RFC
+/// 8109), caching it along with the addresses of the servers. Returns how
+/// long the set and its addresses can be used for.
+pub fn prime(resolver: &Resolver) -> Result<u32> {
+    let deadline = Instant::now() + resolver.deadline;
+    let mut request = create_request_packet("", QueryType::NS);
+    request.header.recursion_desired = false;
+    // the full root NS set with its addresses doesn't fit in 512 bytes
+    request.set_edns(Some(edns_record(None)));
+
+    for server in resolver.root_servers.iter() {
+        let addr = SocketAddr::from((*server, resolver.upstream_port));
+        let mut pck = match lookup(resolver, addr, &request, deadline) {
+            Ok(pck) => pck,
+            Err(e) if Instant::now() < deadline => {
+                info!("no usable priming answer from {}: {}", server, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if pck.header.rcode != ResponseCode::no_error {
+            info!("{} answered {} to priming", server, pck.header.rcode);
+            continue;
         }
+        // only the root NS set, and the addresses of its hosts
+        pck.answers
+            .retain(|x| matches!(x, Record::NS { name, .. } if name.is_empty()));
+        pck.authority.clear();
+        if pck.answers.is_empty() {
+            info!("{} sent no root NS records", server);
+            continue;
+        }
+        let pck = strip_out_of_bailiwick(pck, "");
+
+        let ttl = pck
+            .answers
+            .iter()
+            .chain(pck.additional.iter())
+            .filter(|x| !matches!(x, Record::OPT { .. }))
+            .map(|x| x.ttl())
+            .min()
+            .unwrap_or(0);
+        cache_response(resolver, &pck);
+        return Ok(ttl);
     }
+
+    Err("no root server answered the priming query".into())
 }
 
-fn handle_query(socket: &UdpSocket) -> Result<()> {
-    let mut buffer = BytePacketBuffer::new_empty();
-    let (_, src) = socket
-        .recv_from(&mut buffer)
-        .expect("didn't receive any data");
+/// receives one query and leaves resolving it to the pool
+fn handle_query(server: &Arc<Server>, socket: &Arc<UdpSocket>) -> Result<()> {
+    let mut buffer = BytePacketBuffer::with_max_size(EDNS_PAYLOAD_SIZE as usize);
+    let (size, src) = socket.recv_from(&mut buffer)?;
+    buffer.size = size;
 
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    if !server.acl.permits(src.ip()) {
+        info!("refusing query from {}", src);
+        if let Some(response_buf) = early_reply(&buffer, ResponseCode::refused)? {
+            socket.send_to(&response_buf[0..response_buf.size], src)?;
+        }
+        return Ok(());
+    }
+
+    // the job takes the buffer, this is kept to answer if there's no room
+    let query = BytePacketBuffer::from_bytes(&buffer[0..buffer.size]);
+    let job_server = server.clone();
+    let job_socket = socket.clone();
+    let job =
+        Box::new(
+            move || match process_query(&job_server.resolver, &mut buffer, Protocol::Udp) {
+                Ok(Some(response_buf)) => {
+                    if let Err(e) = job_socket.send_to(&response_buf[0..response_buf.size], src) {
+                        warn!("error sending response to {}: {}", src, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("error occured: {}", e),
+            },
+        );
+    if !server.pool.submit(src.ip(), job) {
+        warn!("too many queries waiting, turning away query from {}", src);
+        if let Some(response_buf) = early_reply(&query, ResponseCode::serv_fail)? {
+            socket.send_to(&response_buf[0..response_buf.size], src)?;
+        }
+    }
+    Ok(())
+}
+
+/// serves any number of length-prefixed queries on one connection, until the
+/// client closes it or takes too long to send the next query, or to take the
+/// last response. The queries are resolved by the pool one at a time, as
+/// responses have to go out in order.
+fn handle_tcp_connection(server: &Arc<Server>, mut stream: TcpStream) -> Result<()> {
+    stream.set_write_timeout(Some(server.tcp_idle_timeout))?;
+    let client = stream.peer_addr()?.ip();
+    let permitted = server.acl.permits(client);
+    while let Some(mut buffer) = tcp::read_message_within(&mut stream, server.tcp_idle_timeout)? {
+        if !permitted {
+            info!("refusing query from {}", client);
+            if let Some(response_buf) = early_reply(&buffer, ResponseCode::refused)? {
+                tcp::write_message(&mut stream, &response_buf)?;
+            }
+            continue;
+        }
+
+        let query = BytePacketBuffer::from_bytes(&buffer[0..buffer.size]);
+        let (sender, receiver) = mpsc::channel();
+        let job_server = server.clone();
+        let job = Box::new(move || {
+            let response = process_query(&job_server.resolver, &mut buffer, Protocol::Tcp)
+                .unwrap_or_else(|e| {
+                    error!("error occured: {}", e);
+                    None
+                });
+            let _ = sender.send(response);
+        });
+        if !server.pool.submit(client, job) {
+            warn!(
+                "too many queries waiting, turning away query from {}",
+                client
+            );
+            if let Some(response_buf) = early_reply(&query, ResponseCode::serv_fail)? {
+                tcp::write_message(&mut stream, &response_buf)?;
+            }
+            continue;
+        }
+
+        if let Some(response_buf) = receiver.recv()? {
+            tcp::write_message(&mut stream, &response_buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// `rcode` for a query turned away before a worker gets to it, REFUSED for a
+/// client the ACL keeps out or SERVFAIL when the pool has no room. Looks no
+/// further into the query than needed to echo it.
+fn early_reply(buffer: &BytePacketBuffer, rcode: ResponseCode) -> Result<Option<BytePacketBuffer>> {
+    if is_response(buffer) {
+        return Ok(None);
+    }
+    let response = error_response(buffer, rcode);
+    Ok(Some(write_response(&response, DEFAULT_MAX_SIZE)?))
+}
+
+/// parses and resolves one client query, returning the serialized response
+pub fn process_query(
+    resolver: &Resolver,
+    buffer: &mut BytePacketBuffer,
+    protocol: Protocol,
+) -> Result<Option<BytePacketBuffer>> {
+    let mut packet = match Packet::read(buffer) {
+        Ok(packet) => packet,
+        Err(err) => {
+            info!("malformed query: {}", err);
+            if is_response(buffer) {
+                return Ok(None);
+            }
+            let rcode = match err {
+                ParseError::UnknownOpcode { .. } => ResponseCode::not_imp,
+                _ => ResponseCode::format_err,
+            };
+            let response = error_response(buffer, rcode);
+            return Ok(Some(write_response(&response, DEFAULT_MAX_SIZE)?));
+        }
+    };
+    if packet.header.qr == PacketType::Response {
+        // answering it could start a loop with whoever sent it
+        info!("ignoring response {} sent as a query", packet.header.id);
+        return Ok(None);
+    }
 
     // do some checks on packet
     if let Some(question) = packet.questions.first() {
-        println!("question: {:#?}", question);
+        debug!("question: {}", question);
     } else {
-        println!("no question found");
+        debug!("no question found");
     }
+
+    let client_edns = packet.edns().cloned();
+    let max_size = match protocol {
+        Protocol::Udp => packet.max_udp_payload().min(EDNS_PAYLOAD_SIZE as usize),
+        Protocol::Tcp => MAX_MESSAGE_SIZE,
+    };
+    if let Some(Record::OPT { version, .. }) = client_edns {
+        if version > 0 {
+            let response = bad_version_response(&packet);
+            return Ok(Some(write_response(&response, max_size)?));
+        }
+    }
+    // whatever the client sent in additional, upstream gets only our OPT
     packet.additional.clear();
-    packet.header.addi_c = 0;
+    packet.set_edns(Some(edns_record(client_edns.as_ref())));
 
-    println!("packet is {:#?}", packet);
+    debug!("query:\n{}", packet);
 
-    let root_servers = vec!["198.41.0.4", "199.9.14.201"];
-    let root_ipv4: Vec<Ipv4Addr> = root_servers
-        .iter()
-        .map(|x| Ipv4Addr::from_str(x).unwrap())
-        .collect();
+    let mut response = if packet.header.opcode != 0 {
+        // parses fine, but only standard queries are served
+        rejection(&packet, ResponseCode::not_imp)
+    } else if packet.questions.len() != 1 {
+        rejection(&packet, ResponseCode::format_err)
+    } else if is_refused(&packet) {
+        rejection(&packet, ResponseCode::refused)
+    } else if let Some(zone) = resolver.zones.find(&packet.questions[0].name) {
+        authoritative_response(zone, &packet)
+    } else {
+        let deadline = Instant::now() + resolver.deadline;
+        match resolve(resolver, &packet, 0, deadline) {
+            Ok(response) => response,
+            Err(e) => {
+                info!("failed to resolve {:?}: {}", packet.questions[0].name, e);
+                rejection(&packet, ResponseCode::serv_fail)
+            }
+        }
+    };
+
+    debug!("response:\n{}", response);
+    // echo EDNS only to clients which used it
+    response.set_edns(client_edns.as_ref().map(|x| edns_record(Some(x))));
+    response.header.recursion_available = true;
+    let response_buf = write_response(&response, max_size)?;
+
+    Ok(Some(response_buf))
+}
+
+/// resolves the question of `request_packet`, following CNAMEs (within the
+/// same answer, from the cache, or with new queries) until the records of the
+/// requested type, or a negative answer, are found
+///
+/// `depth` counts how many nameserver lookups this resolution is nested in,
+/// see resolve_ns_addresses. Every upstream query gives up at `deadline`.
+fn resolve(
+    resolver: &Resolver,
+    request_packet: &Packet,
+    depth: usize,
+    deadline: Instant,
+) -> Result<Packet> {
+    let question = match request_packet.questions.first() {
+        Some(question) => question,
+        None => return Err("no question in request".into()),
+    };
+    // CNAME and ANY queries are answered with whatever is at the name itself
+    let follow = !matches!(question.qtype.to_num(), 5 | 255);
+
+    let mut chain: Vec<Record> = vec![];
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut name = normalize_name(&question.name);
+    loop {
+        seen.insert(name.clone());
+        let step = resolve_name(resolver, request_packet, &name, depth, deadline)?;
+
+        // walk the part of the chain contained in this answer
+        let mut current = name.clone();
+        let mut found = vec![];
+        while found.is_empty() {
+            let owned: Vec<&Record> = step
+                .answers
+                .iter()
+                .filter(|x| normalize_name(x.name()) == current)
+                .collect();
+            found = owned
+                .iter()
+                .filter(|x| x.to_num() == question.qtype.to_num() || !follow)
+                .map(|x| (*x).clone())
+                .collect();
+            if !found.is_empty() || !follow {
+                break;
+            }
+
+            match owned.iter().find(|x| matches!(x, Record::CNAME { .. })) {
+                Some(cname @ Record::CNAME { host, .. }) => {
+                    chain.push((*cname).clone());
+                    current = normalize_name(host);
+                    if chain.len() > MAX_CNAME_CHAIN || !seen.insert(current.clone()) {
+                        return Err(format!("cname loop or chain too long at {}", current).into());
+                    }
+                }
+                _ => break,
+            }
+        }
 
-    let opt_response = resolve(socket, root_ipv4.as_slice(), &packet)?;
+        if !found.is_empty() || current == name {
+            // either done, or the last name in the chain has no such records
+            let mut response = response_for(request_packet);
+            response.header.rcode = step.header.rcode;
+            response.answers = chain;
+            response.answers.extend(found.clone());
+            if found.is_empty() {
+                response.authority = step.authority;
+            }
+            response.update_counts();
+            return Ok(response);
+        }
 
-    // let mut req_buffer = BytePacketBuffer::new_empty();
-    // packet.write(&mut req_buffer);
+        // the chain leads to a name this answer says nothing about
+        name = current;
+    }
+}
 
-    // socket.send_to(&req_buffer[0..req_buffer.size], server)?;
-    // //    socket.set_read_timeout(Some(Duration::from_secs(10)))?;
-    // let mut response_buf = BytePacketBuffer::new_empty();
-    // let (size, _) = socket.recv_from(&mut response_buf)?;
-    // response_buf.size = size; // that's why it's a bad idea to allow Deref of the BytePacketBuffer (it gives ability to directly manipulate buffer, without changing size)
+/// answers for one name, with the type and class of the original question.
+/// Served from the cache when possible, otherwise resolution starts at the
+/// closest delegation that's cached, or the root servers.
+fn resolve_name(
+    resolver: &Resolver,
+    request_packet: &Packet,
+    name: &str,
+    depth: usize,
+    deadline: Instant,
+) -> Result<Packet> {
+    let mut request = request_packet.clone();
+    request.questions[0].name = name.to_string();
+    let question = &request.questions[0];
 
-    println!("response: {:#?}", opt_response);
-    if let Some(response) = opt_response {
-        let mut response_buf = BytePacketBuffer::new_empty();
-        response.write(&mut response_buf);
-        socket.send_to(&response_buf[0..response_buf.size], src)?;
-        if !response.answers.is_empty() {
-            println!("answers: {:#?}", response.answers);
+    let (cached, negative, delegation) = {
+        let mut cache = resolver.cache.lock().unwrap();
+        let mut cached = cache.get(name, question.qtype, question.class);
+        if cached.is_none() && question.qtype != QueryType::CNAME {
+            cached = cache.get(name, QueryType::CNAME, question.class);
+        }
+        let negative = cache.get_negative(name, question.qtype, question.class);
+        (cached, negative, cache.closest_delegation(name))
+    };
+    if let Some(answers) = cached {
+        let mut response = response_for(&request);
+        response.answers = answers;
+        response.update_counts();
+        return Ok(response);
+    }
+    if let Some((negative, soa)) = negative {
+        // the SOA goes along so the client can cache the answer as well
+        let mut response = response_for(&request);
+        if negative == Negative::NxDomain {
+            response.header.rcode = ResponseCode::nx_domain;
         }
+        response.authority = soa;
+        response.update_counts();
+        return Ok(response);
     }
-    // TODO: in case of failure, no proper response is being sent to the server
 
-    // if !response_packet.authority.is_empty() {
-    //     println!("authoritative: {:#?}", response_packet.authority);
-    // }
-    // if !response_packet.additional.is_empty() {
-    //     println!("additional: {:#?}", response_packet.additional);
-    // }
+    if let Some((zone, upstreams)) = resolver.forwarder.upstreams_for(name) {
+        // the upstream does the recursion
+        request.header.recursion_desired = true;
+        return forward(resolver, zone, upstreams, &request, deadline);
+    }
 
-    Ok(())
+    let (zone, servers) = match delegation {
+        Some(delegation) => delegation,
+        None => (String::new(), resolver.root_servers.clone()),
+    };
+    resolve_from(resolver, &zone, &servers, &request, depth, deadline)
 }
 
-fn resolve(
-    socket: &UdpSocket,
+/// asks `servers`, which serve `zone`, following referrals further down
+fn resolve_from(
+    resolver: &Resolver,
+    zone: &str,
     servers: &[Ipv4Addr],
     request_packet: &Packet,
-) -> Result<Option<Packet>> {
+    depth: usize,
+    deadline: Instant,
+) -> Result<Packet> {
     for server in servers.iter() {
-        //println!("trying server {}", server);
-        let pck = lookup(socket, *server, request_packet)?;
-        //println!("packet {:#?}", pck);
+        debug!("trying {} for {}", server, zone);
+        let addr = SocketAddr::from((*server, resolver.upstream_port));
+        let pck = match lookup(resolver, addr, request_packet, deadline) {
+            Ok(pck) => pck,
+            Err(e) if Instant::now() < deadline => {
+                info!("no usable answer from {}: {}", server, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if matches!(
+            pck.header.rcode,
+            ResponseCode::serv_fail | ResponseCode::refused
+        ) {
+            // this server can't help, another one for the zone might
+            info!("{} answered {}", server, pck.header.rcode);
+            continue;
+        }
+        let pck = strip_out_of_bailiwick(pck, zone);
+        cache_response(resolver, &pck);
         if !pck.answers.is_empty() {
-            return Ok(Some(pck));
+            return Ok(pck);
+        }
+        if let Some(negative) = negative_kind(&pck) {
+            cache_negative(resolver, &pck, negative);
+            return Ok(pck);
         }
 
+        // a referral has to lead further down, or it would send us in circles
+        let next_zone = match pck.authority.iter().find_map(|x| match x {
+            Record::NS { name, .. } if normalize_name(name) != normalize_name(zone) => {
+                Some(normalize_name(name))
+            }
+            _ => None,
+        }) {
+            Some(next_zone) => next_zone,
+            None => continue,
+        };
+        let ns_hosts: Vec<&str> = pck
+            .authority
+            .iter()
+            .filter_map(|x| match x {
+                Record::NS { name, host, .. } if normalize_name(name) == next_zone => {
+                    Some(host.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+
         let servers: Vec<Ipv4Addr> = pck
             .additional
             .iter()
-            .filter(|x| matches!(x, Record::A { .. }))
-            .map(|server| match server {
-                Record::A { ip, .. } => Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
-                _ => panic!("should have been filtered out"),
+            .filter_map(|x| match x {
+                Record::A { name, ip, .. }
+                    if ns_hosts.iter().any(|host| host.eq_ignore_ascii_case(name)) =>
+                {
+                    Some(Ipv4Addr::from(*ip))
+                }
+                _ => None,
             })
             .collect();
-        //println!("servers size {}", servers.len());
+        debug!("{} glue addresses for {}", servers.len(), next_zone);
+        if !servers.is_empty() {
+            return resolve_from(
+                resolver,
+                &next_zone,
+                servers.as_slice(),
+                request_packet,
+                depth,
+                deadline,
+            );
+        }
+
+        // referral without glue, the nameservers have to be looked up first
+        let servers = resolve_ns_addresses(resolver, &ns_hosts, depth, deadline)?;
         if !servers.is_empty() {
-            return resolve(socket, servers.as_slice(), request_packet);
+            return resolve_from(
+                resolver,
+                &next_zone,
+                &servers,
+                request_packet,
+                depth,
+                deadline,
+            );
         }
     }
 
     Err("no servers remaining for request".into())
 }
 
-fn lookup(socket: &UdpSocket, server: Ipv4Addr, request_packet: &Packet) -> Result<Packet> {
-    let mut req_buffer = BytePacketBuffer::new_empty();
-    request_packet.write(&mut req_buffer);
-    socket.send_to(&req_buffer[0..req_buffer.size], (server.to_string(), 53))?;
-    let mut response_buf = BytePacketBuffer::new_empty();
-    let (size, _) = socket.recv_from(&mut response_buf)?;
-    response_buf.size = size; // that's why it's a bad idea to allow Deref of the BytePacketBuffer (it gives ability to directly manipulate buffer, without changing size)
+/// relays `request_packet` to the first of `upstreams` with a usable answer,
+/// keeping track of how each of them is doing
+fn forward(
+    resolver: &Resolver,
+    zone: &str,
+    upstreams: &[Upstream],
+    request_packet: &Packet,
+    deadline: Instant,
+) -> Result<Packet> {
+    for upstream in Forwarder::ordered(upstreams) {
+        let start = Instant::now();
+        let pck = match lookup(resolver, upstream.addr, request_packet, deadline) {
+            Ok(pck) => pck,
+            Err(e) => {
+                upstream.failed();
+                if Instant::now() >= deadline {
+                    return Err(e);
+                }
+                info!("no usable answer from {}: {}", upstream.addr, e);
+                continue;
+            }
+        };
+        if matches!(
+            pck.header.rcode,
+            ResponseCode::serv_fail | ResponseCode::refused
+        ) {
+            // it did answer, so this says nothing about its health; another
+            // upstream might still do better
+            info!("{} answered {}", upstream.addr, pck.header.rcode);
+            continue;
+        }
+        upstream.succeeded(start.elapsed());
+
+        let pck = strip_out_of_bailiwick(pck, zone);
+        cache_response(resolver, &pck);
+        if let Some(negative) = negative_kind(&pck) {
+            cache_negative(resolver, &pck, negative);
+        }
+        return Ok(pck);
+    }
+
+    Err("no upstreams remaining for request".into())
+}
+
+/// addresses of the first of `ns_hosts` that can be resolved. Each level of
+/// nesting is a lookup started in the middle of another one, so a limit on
+/// it stops delegations which depend on each other from looping forever.
+fn resolve_ns_addresses(
+    resolver: &Resolver,
+    ns_hosts: &[&str],
+    depth: usize,
+    deadline: Instant,
+) -> Result<Vec<Ipv4Addr>> {
+    if depth >= MAX_NS_LOOKUP_DEPTH {
+        return Err(format!("nameserver lookups nested too deep for {:?}", ns_hosts).into());
+    }
+
+    let mut last_err = None;
+    for host in ns_hosts.iter() {
+        let request = create_request_packet(host, QueryType::A);
+        match resolve(resolver, &request, depth + 1, deadline) {
+            Ok(response) => {
+                let servers: Vec<Ipv4Addr> = response
+                    .answers
+                    .iter()
+                    .filter_map(|x| match x {
+                        Record::A { ip, .. } => Some(Ipv4Addr::from(*ip)),
+                        _ => None,
+                    })
+                    .collect();
+                if !servers.is_empty() {
+                    return Ok(servers);
+                }
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    match last_err {
+        Some(e) => Err(e),
+        None => Ok(vec![]),
+    }
+}
+
+/// drops what servers for `zone` have no say over, before any of it is cached
+/// or followed: records outside the zone, NS records which aren't on the way
+/// to the question, and additional records other than glue for those NS hosts
+fn strip_out_of_bailiwick(mut response: Packet, zone: &str) -> Packet {
+    let qname = match response.questions.first() {
+        Some(question) => question.name.clone(),
+        None => return response,
+    };
+    let keep = |record: &Record| {
+        let keep = is_subdomain(record.name(), zone);
+        if !keep {
+            info!("dropping out of bailiwick record for {}: {}", zone, record);
+        }
+        keep
+    };
+
+    response.answers.retain(keep);
+    response.authority.retain(|x| match x {
+        Record::NS { name, .. } => keep(x) && is_subdomain(&qname, name),
+        _ => keep(x),
+    });
+
+    let ns_hosts: Vec<String> = response
+        .answers
+        .iter()
+        .chain(response.authority.iter())
+        .filter_map(|x| match x {
+            Record::NS { host, .. } => Some(normalize_name(host)),
+            _ => None,
+        })
+        .collect();
+    response.additional.retain(|x| match x {
+        Record::OPT { .. } => true,
+        Record::A { name, .. } | Record::AAAA { name, .. } => {
+            keep(x) && ns_hosts.contains(&normalize_name(name))
+        }
+        _ => false,
+    });
+    response.update_counts();
+    response
+}
+
+/// NXDOMAIN, or NODATA: no error and no answers, with an SOA in authority
+/// rather than the NS records of a referral
+fn negative_kind(response: &Packet) -> Option<Negative> {
+    let has_soa = response
+        .authority
+        .iter()
+        .any(|x| matches!(x, Record::SOA { .. }));
+    if response.header.rcode == ResponseCode::nx_domain {
+        Some(Negative::NxDomain)
+    } else if response.header.rcode == ResponseCode::no_error
+        && response.answers.is_empty()
+        && has_soa
+    {
+        Some(Negative::NoData)
+    } else {
+        None
+    }
+}
+
+/// negative answers are only cached when they come with an SOA (RFC 2308
+/// section 5)
+fn cache_negative(resolver: &Resolver, response: &Packet, negative: Negative) {
+    let soa = response
+        .authority
+        .iter()
+        .find(|x| matches!(x, Record::SOA { .. }));
+    if let (Some(soa), Some(question)) = (soa, response.questions.first()) {
+        resolver.cache.lock().unwrap().insert_negative(
+            &question.name,
+            question.qtype,
+            question.class,
+            negative,
+            soa,
+        );
+    }
+}
+
+/// keeps answers, and the NS records and glue of referrals
+fn cache_response(resolver: &Resolver, response: &Packet) {
+    let mut cache = resolver.cache.lock().unwrap();
+    cache.insert(&response.answers);
+    cache.insert(&response.authority);
+    cache.insert(&response.additional);
+}
+
+/// asks `server`, waiting up to `timeout` for each of 1 + `retries` tries, but
+/// never past `deadline`. Every try goes out from a fresh socket on a random
+/// port with a random ID, and only a reply from `server` with that ID and our
+/// question is accepted, so a forged answer has to guess both.
+fn lookup(
+    resolver: &Resolver,
+    server: SocketAddr,
+    request_packet: &Packet,
+    deadline: Instant,
+) -> Result<Packet> {
+    let mut request_packet = request_packet.clone();
+
+    for _ in 0..=resolver.retries {
+        let try_deadline = match remaining(deadline) {
+            Some(remaining) => Instant::now() + remaining.min(resolver.timeout),
+            None => break,
+        };
+        request_packet.header.id = random_u16();
+        let mut req_buffer = BytePacketBuffer::new_empty();
+        request_packet.write(&mut req_buffer)?;
+
+        let socket = bind_random_port(server.is_ipv6())?;
+        socket.send_to(&req_buffer[0..req_buffer.size], server)?;
+        let response_packet = match receive_reply(&socket, server, &request_packet, try_deadline)? {
+            Some(response_packet) => response_packet,
+            None => continue,
+        };
+
+        if response_packet.header.is_truncated {
+            // didn't fit in a datagram, ask again over tcp for the whole answer
+            let timeout = remaining(deadline)
+                .ok_or("deadline exceeded")?
+                .min(resolver.timeout);
+            return lookup_tcp(server, &request_packet, &req_buffer, timeout);
+        }
+
+        return Ok(response_packet);
+    }
+
+    Err(format!("timed out waiting for {}", server).into())
+}
+
+/// waits on `socket` for the reply to `request_packet` until `until`, dropping
+/// anything else which arrives in the meantime. None if nothing matched in time.
+fn receive_reply(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request_packet: &Packet,
+    until: Instant,
+) -> Result<Option<Packet>> {
+    while let Some(timeout) = remaining(until) {
+        socket.set_read_timeout(Some(timeout))?;
+        let mut response_buf = BytePacketBuffer::with_max_size(EDNS_PAYLOAD_SIZE as usize);
+        let (size, src) = match socket.recv_from(&mut response_buf) {
+            Ok(x) => x,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e.into()),
+        };
+        response_buf.size = size; // that's why it's a bad idea to allow Deref of the BytePacketBuffer (it gives ability to directly manipulate buffer, without changing size)
+        if src != server {
+            warn!("ignoring datagram from {}, expected {}", src, server);
+            continue;
+        }
 
-    let mut response_packet = Packet::new();
-    response_packet.read(&mut response_buf);
+        match Packet::read(&mut response_buf) {
+            Ok(response_packet) if is_reply_to(&response_packet, request_packet) => {
+                return Ok(Some(response_packet))
+            }
+            Ok(_) => warn!("ignoring mismatched reply from {}", src),
+            Err(e) => info!("ignoring malformed reply from {}: {}", src, e),
+        }
+    }
+    Ok(None)
+}
 
+fn lookup_tcp(
+    server: SocketAddr,
+    request_packet: &Packet,
+    req_buffer: &BytePacketBuffer,
+    timeout: Duration,
+) -> Result<Packet> {
+    let mut response_buf = tcp::exchange(server, req_buffer, timeout)?;
+    let response_packet = Packet::read(&mut response_buf)?;
+    if !is_reply_to(&response_packet, request_packet) {
+        return Err(format!("mismatched reply from {} over tcp", server).into());
+    }
     Ok(response_packet)
 }
 
+/// whether `response` answers `request`: same ID and the same question
+fn is_reply_to(response: &Packet, request: &Packet) -> bool {
+    response.header.qr == PacketType::Response
+        && response.header.id == request.header.id
+        && response.questions.len() == request.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(request.questions.iter())
+            .all(|(a, b)| {
+                a.name.eq_ignore_ascii_case(&b.name) && a.qtype == b.qtype && a.class == b.class
+            })
+}
+
+/// binds a UDP socket to a random unprivileged port, leaving the choice to
+/// the OS if a few picks are all taken
+fn bind_random_port(ipv6: bool) -> Result<UdpSocket> {
+    let any = if ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        let port = 1024 + random_u16() % (u16::MAX - 1024);
+        match UdpSocket::bind((any, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(UdpSocket::bind((any, 0))?)
+}
+
+/// unpredictable 16 bits, from the random keys std seeds each RandomState with
+fn random_u16() -> u16 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.finish() as u16
+}
+
+/// time left until `deadline`, None once it has passed
+fn remaining(deadline: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|x| *x > Duration::from_millis(0))
+}
+
+/// serializes a response for a client that accepts at most `max_size` bytes,
+/// falling back to a truncated response if it doesn't fit, and to SERVFAIL
+/// if something in it can't be written at all, such as an overlong name
+pub fn write_response(response: &Packet, max_size: usize) -> Result<BytePacketBuffer> {
+    let mut buffer = BytePacketBuffer::with_max_size(max_size);
+    let err = match response.write(&mut buffer) {
+        Ok(()) => return Ok(buffer),
+        Err(err) if err.is::<BufferOverflow>() => {
+            let mut buffer = BytePacketBuffer::with_max_size(max_size);
+            match response.truncated().write(&mut buffer) {
+                Ok(()) => return Ok(buffer),
+                Err(err) => err,
+            }
+        }
+        Err(err) => err,
+    };
+
+    warn!(
+        "can't write response {}, sending SERVFAIL: {}",
+        response.header.id, err
+    );
+    let mut failure = rejection(response, ResponseCode::serv_fail);
+    failure.set_edns(response.edns().cloned());
+    let mut buffer = BytePacketBuffer::with_max_size(max_size);
+    failure.write(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// OPT record advertising our payload size. The DO bit is copied from the
+/// client's OPT record, if any.
+fn edns_record(client: Option<&Record>) -> Record {
+    let dnssec_ok = matches!(
+        client,
+        Some(Record::OPT {
+            dnssec_ok: true,
+            ..
+        })
+    );
+    Record::OPT {
+        udp_payload_size: EDNS_PAYLOAD_SIZE,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok,
+        options: vec![],
+    }
+}
+
+/// BADVERS reply for clients using an EDNS version other than 0. BADVERS is
+/// 16, so the header rcode stays 0 and the upper bits go in the OPT record.
+fn bad_version_response(request: &Packet) -> Packet {
+    let mut packet = response_for(request);
+    packet.set_edns(Some(Record::OPT {
+        udp_payload_size: EDNS_PAYLOAD_SIZE,
+        extended_rcode: 1,
+        version: 0,
+        dnssec_ok: false,
+        options: vec![],
+    }));
+    packet
+}
+
+/// empty response to `request`, echoing its id and question
+fn response_for(request: &Packet) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.id = request.header.id;
+    packet.header.qr = PacketType::Response;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = true;
+    packet.questions = request.questions.clone();
+    packet.update_counts();
+    packet
+}
+
+/// the answer of a local zone, which is never looked for elsewhere
+fn authoritative_response(zone: &Zone, request: &Packet) -> Packet {
+    let question = &request.questions[0];
+    let lookup = zone.lookup(&question.name, question.qtype);
+    let mut packet = response_for(request);
+    packet.header.authoritative = lookup.authoritative;
+    packet.header.rcode = lookup.rcode;
+    packet.answers = lookup.answers;
+    packet.authority = lookup.authority;
+    packet.additional = lookup.additional;
+    packet.update_counts();
+    packet
+}
+
+/// `request` turned down with `rcode`, echoing its id and question
+fn rejection(request: &Packet, rcode: ResponseCode) -> Packet {
+    let mut packet = response_for(request);
+    packet.header.rcode = rcode;
+    packet
+}
+
+/// queries which are understood, but which we won't answer: zone transfers,
+/// and classes other than IN
+fn is_refused(request: &Packet) -> bool {
+    request
+        .questions
+        .iter()
+        .any(|x| x.class != 1 || matches!(x.qtype.to_num(), 251 | 252))
+}
+
+/// whether the QR bit of a message is set, parsed or not
+fn is_response(buffer: &BytePacketBuffer) -> bool {
+    matches!(buffer.read_u8_from(2), Ok(flags) if flags & 0x80 != 0)
+}
+
+/// reply with `rcode` for a message that couldn't be parsed. The id, opcode
+/// and RD flag are taken from the raw header, and the question is echoed
+/// back if it can be read on its own.
+fn error_response(buffer: &BytePacketBuffer, rcode: ResponseCode) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.id = buffer.read_u16_from(0).unwrap_or(0);
+    packet.header.qr = PacketType::Response;
+    packet.header.rcode = rcode;
+    if let Ok(flags) = buffer.read_u8_from(2) {
+        packet.header.opcode = (flags >> 3) & 0x0F;
+        packet.header.recursion_desired = flags & 1 == 1;
+    }
+    packet.header.recursion_available = true;
+    packet.questions = raw_questions(buffer).unwrap_or_default();
+    packet.update_counts();
+    packet
+}
+
+/// the question section of a message whose other parts may be broken
+fn raw_questions(buffer: &BytePacketBuffer) -> ParseResult<Vec<Question>> {
+    let count = buffer.read_u16_from(4)?;
+    let mut pos = 12;
+    let mut questions = vec![];
+    for _ in 0..count {
+        let (name, next) = buffer.read_qname_from(pos)?;
+        questions.push(Question {
+            name,
+            qtype: QueryType::from_num(buffer.read_u16_from(next)?),
+            class: buffer.read_u16_from(next + 2)?,
+        });
+        pos = next + 4;
+    }
+    Ok(questions)
+}
+
 pub fn create_request_packet(qname: &str, qtype: QueryType) -> Packet {
     let mut packet = Packet::new();
     let header = Header {
-        id: 8378,
+        id: random_u16(),
         qr: PacketType::Query,
         opcode: 0,
         authoritative: false,
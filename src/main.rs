@@ -1,6 +1,47 @@
-use druns::lookup;
+use druns::cli::{self, Command, Options};
+use druns::config::{Config, ConfigError};
+use druns::{log, lookup};
+use std::{env, process};
 
 fn main() {
-    println!("starting from main");
-    lookup::start().unwrap();
+    let options = match cli::parse_args(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("druns {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("druns: {}", e);
+            eprintln!("try 'druns --help' for more information");
+            process::exit(2);
+        }
+    };
+
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("druns: {}", e);
+            process::exit(2);
+        }
+    };
+
+    log::set_level(config.log_level);
+    if let Err(e) = lookup::start(&config) {
+        eprintln!("druns: {}", e);
+        process::exit(1);
+    }
+}
+
+/// the config file if there is one, with the command line on top
+fn load_config(options: &Options) -> Result<Config, ConfigError> {
+    let mut config = match &options.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.apply(options)?;
+    Ok(config)
 }
@@ -1,9 +1,15 @@
 use Record::A;
 
-use super::buffer::{BytePacketBuffer, Result};
-use std::{convert::TryInto, fmt::Debug, net::Ipv6Addr};
+use super::buffer::{BytePacketBuffer, ParseError, ParseResult, Result, DEFAULT_MAX_SIZE};
+use super::zone;
+use std::{
+    convert::TryInto,
+    fmt::{self, Debug},
+    net::Ipv6Addr,
+    str::FromStr,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
@@ -23,48 +29,154 @@ impl Packet {
         }
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) {
+    pub fn read(buffer: &mut BytePacketBuffer) -> ParseResult<Packet> {
+        let mut packet = Packet::new();
         buffer.reset_for_read();
-        self.header.read_header(buffer);
-        (0..self.header.ques_c).for_each(|_| {
+        packet.header.read_header(buffer)?;
+        for _ in 0..packet.header.ques_c {
             let mut question = Question::new();
-            question.read(buffer);
-            self.questions.push(question);
-        });
+            question.read(buffer)?;
+            packet.questions.push(question);
+        }
+
+        for _ in 0..packet.header.ans_c {
+            packet.answers.push(Record::read(buffer)?);
+        }
 
-        (0..self.header.ans_c).for_each(|_| {
-            self.answers.push(Record::read(buffer).unwrap());
-        });
+        for _ in 0..packet.header.auth_c {
+            packet.authority.push(Record::read(buffer)?);
+        }
 
-        (0..self.header.auth_c).for_each(|_| {
-            self.authority.push(Record::read(buffer).unwrap());
-        });
+        for _ in 0..packet.header.addi_c {
+            packet.additional.push(Record::read(buffer)?);
+        }
+
+        Ok(packet)
+    }
+}
 
-        (0..self.header.addi_c).for_each(|_| {
-            self.additional.push(Record::read(buffer).unwrap());
-        });
+impl Default for Packet {
+    fn default() -> Self {
+        Packet::new()
     }
 }
 
 impl Packet {
-    pub fn write(&self, buffer: &mut BytePacketBuffer) {
-        self.header.write(buffer);
-        self.questions.iter().for_each(|a| {
-            a.write(buffer);
-        });
-        self.answers.iter().for_each(|a| {
-            a.write(buffer).unwrap();
-        });
-        self.authority.iter().for_each(|a| {
-            a.write(buffer).unwrap();
-        });
-        self.additional.iter().for_each(|a| {
-            a.write(buffer).unwrap();
-        });
-    }
-}
-
-#[derive(Debug)]
+    /// fails with `BufferOverflow` if the packet doesn't fit in the buffer,
+    /// in which case the buffer holds a partial message
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.header.write(buffer)?;
+        for question in self.questions.iter() {
+            question.write(buffer)?;
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter())
+        {
+            record.write(buffer)?;
+        }
+        Ok(())
+    }
+
+    /// copy of the packet with only the header, questions and OPT record, and
+    /// the TC bit set. Sent in place of responses that are too large for the
+    /// client.
+    pub fn truncated(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.header = self.header.clone();
+        packet.header.is_truncated = true;
+        packet.header.ans_c = 0;
+        packet.header.auth_c = 0;
+        packet.questions = self.questions.clone();
+        packet.additional = self.edns().into_iter().cloned().collect();
+        packet.header.addi_c = packet.additional.len() as u16;
+        packet
+    }
+}
+
+impl Packet {
+    /// sets the header counts from the length of each section
+    pub fn update_counts(&mut self) {
+        self.header.ques_c = self.questions.len() as u16;
+        self.header.ans_c = self.answers.len() as u16;
+        self.header.auth_c = self.authority.len() as u16;
+        self.header.addi_c = self.additional.len() as u16;
+    }
+}
+
+// EDNS(0), RFC 6891
+impl Packet {
+    /// the OPT pseudo-record, if the sender supports EDNS
+    pub fn edns(&self) -> Option<&Record> {
+        self.additional
+            .iter()
+            .find(|x| matches!(x, Record::OPT { .. }))
+    }
+
+    /// replaces any OPT record in the additional section with `opt`, or just
+    /// removes it when `opt` is None
+    pub fn set_edns(&mut self, opt: Option<Record>) {
+        self.additional.retain(|x| !matches!(x, Record::OPT { .. }));
+        self.additional.extend(opt);
+        self.header.addi_c = self.additional.len() as u16;
+    }
+
+    /// largest UDP response the sender of this packet can receive
+    pub fn max_udp_payload(&self) -> usize {
+        match self.edns() {
+            Some(Record::OPT {
+                udp_payload_size, ..
+            }) => (*udp_payload_size as usize).max(DEFAULT_MAX_SIZE),
+            _ => DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+/// the sections of the message the way dig prints them
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.header)?;
+        writeln!(
+            f,
+            ";; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.additional.len()
+        )?;
+        if let Some(opt) = self.edns() {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n{}\n", opt)?;
+        }
+        if !self.questions.is_empty() {
+            f.write_str("\n;; QUESTION SECTION:\n")?;
+            for question in self.questions.iter() {
+                writeln!(f, ";{}", question)?;
+            }
+        }
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authority),
+            ("ADDITIONAL", &self.additional),
+        ];
+        for (title, records) in sections.iter() {
+            let records: Vec<&Record> = records
+                .iter()
+                .filter(|x| !matches!(x, Record::OPT { .. }))
+                .collect();
+            if !records.is_empty() {
+                write!(f, "\n;; {} SECTION:\n", title)?;
+                for record in records {
+                    writeln!(f, "{}", record)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Question {
     pub name: String,
     pub qtype: QueryType, // UNKNOWN type not handled
@@ -80,27 +192,47 @@ impl Question {
         }
     }
 
-    fn read(&mut self, buffer: &mut BytePacketBuffer) {
-        self.name = buffer.read_qname();
-        self.qtype = QueryType::from_num(buffer.read_u16().unwrap());
-        self.class = buffer.read_u16().unwrap();
+    fn read(&mut self, buffer: &mut BytePacketBuffer) -> ParseResult<()> {
+        self.name = buffer.read_qname()?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?);
+        self.class = buffer.read_u16()?;
+        Ok(())
+    }
+}
+
+/// e.g. `example.com. IN A`
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            display_name(&self.name),
+            class_name(self.class),
+            self.qtype
+        )
     }
 }
 
 impl Question {
-    fn write(&self, buffer: &mut BytePacketBuffer) {
-        let _ = buffer.write_qname(&self.name);
-        let _ = buffer.write_u16(self.qtype.to_num());
-        let _ = buffer.write_u16(self.class);
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+        buffer.write_u16(self.qtype.to_num())?;
+        buffer.write_u16(self.class)?;
+        Ok(())
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum QueryType {
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
+    TXT,
     AAAA,
+    SRV,
     UNKNOWN(u16),
 }
 
@@ -110,8 +242,12 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
             QueryType::UNKNOWN(x) => x,
         }
     }
@@ -121,13 +257,50 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
             x => QueryType::UNKNOWN(x),
         }
     }
 }
 
+// compare by number, so UNKNOWN(1) and A are the same type
+impl PartialEq for QueryType {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_num() == other.to_num()
+    }
+}
+
+impl Eq for QueryType {}
+
+impl std::hash::Hash for QueryType {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.to_num().hash(state);
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match QueryType::from_num(self.to_num()) {
+            QueryType::A => f.write_str("A"),
+            QueryType::NS => f.write_str("NS"),
+            QueryType::CNAME => f.write_str("CNAME"),
+            QueryType::SOA => f.write_str("SOA"),
+            QueryType::PTR => f.write_str("PTR"),
+            QueryType::MX => f.write_str("MX"),
+            QueryType::TXT => f.write_str("TXT"),
+            QueryType::AAAA => f.write_str("AAAA"),
+            QueryType::SRV => f.write_str("SRV"),
+            QueryType::UNKNOWN(41) => f.write_str("OPT"),
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+        }
+    }
+}
+
 impl Debug for QueryType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&self.to_num().to_string())
@@ -154,6 +327,24 @@ pub enum Record {
         host: String,
         ttl: u32,
     },
+    SOA {
+        name: String,
+        class: u16,
+        ttl: u32,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR {
+        name: String,
+        class: u16,
+        ttl: u32,
+        host: String,
+    },
     MX {
         name: String,
         class: u16,
@@ -161,97 +352,219 @@ pub enum Record {
         host: String,
         ttl: u32,
     },
+    TXT {
+        name: String,
+        class: u16,
+        ttl: u32,
+        // character-strings, which aren't necessarily text
+        data: Vec<Vec<u8>>,
+    },
     AAAA {
         name: String,
         class: u16,
         ttl: u32,
         ip: Ipv6Addr,
     },
+    SRV {
+        name: String,
+        class: u16,
+        ttl: u32,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// EDNS pseudo-record. Its owner is always the root, and the class and ttl
+    /// fields are repurposed for the values below.
+    OPT {
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    },
+    /// any other type, with its rdata kept as is (RFC 3597)
     UNKNOWN {
         name: String,
         rtype: u16,
         class: u16,
         ttl: u32,
-        length: u16,
+        data: Vec<u8>,
     },
 }
 
+#[derive(Eq, Debug, PartialEq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
 impl Record {
-    fn read(buffer: &mut BytePacketBuffer) -> Result<Record> {
-        let name = buffer.read_qname();
-        let rtype = buffer.read_u16().unwrap();
-        let class = buffer.read_u16().unwrap();
-        let ttl = buffer.read_u32().unwrap();
-        let length = buffer.read_u16().unwrap();
-
-        match rtype {
+    /// one record in wire format, at the buffer's position
+    pub(crate) fn read(buffer: &mut BytePacketBuffer) -> ParseResult<Record> {
+        let name = buffer.read_qname()?;
+        let rtype = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let length = buffer.read_u16()?;
+        let rdata_start = buffer.pos;
+        // make sure the whole rdata is present before looking into it
+        buffer.read_bytes_from(length as usize, rdata_start)?;
+
+        let record = match rtype {
             1 => {
-                let ip = buffer.read_u32().unwrap();
+                let ip = buffer.read_u32()?;
 
-                Ok(A {
+                A {
                     name,
                     class,
                     ttl,
                     ip: Record::parse_ip(ip),
-                })
+                }
             }
 
             2 => {
-                let host = buffer.read_qname();
-                Ok(Record::NS {
+                let host = buffer.read_qname()?;
+                Record::NS {
                     name,
                     class,
                     ttl,
                     host,
-                })
+                }
             }
 
             5 => {
-                let host = buffer.read_qname();
-                Ok(Record::CNAME {
+                let host = buffer.read_qname()?;
+                Record::CNAME {
                     name,
                     class,
                     host,
                     ttl,
-                })
+                }
+            }
+
+            6 => Record::SOA {
+                name,
+                class,
+                ttl,
+                mname: buffer.read_qname()?,
+                rname: buffer.read_qname()?,
+                serial: buffer.read_u32()?,
+                refresh: buffer.read_u32()?,
+                retry: buffer.read_u32()?,
+                expire: buffer.read_u32()?,
+                minimum: buffer.read_u32()?,
+            },
+
+            12 => {
+                let host = buffer.read_qname()?;
+                Record::PTR {
+                    name,
+                    class,
+                    ttl,
+                    host,
+                }
             }
 
             15 => {
-                let priority = buffer.read_u16().unwrap();
-                let host = buffer.read_qname();
-                Ok(Record::MX {
+                let priority = buffer.read_u16()?;
+                let host = buffer.read_qname()?;
+                Record::MX {
                     name,
                     class,
                     priority,
                     host,
                     ttl,
-                })
+                }
             }
 
-            28 => Ok(Record::AAAA {
+            16 => {
+                let rdata_end = rdata_start + length as usize;
+                let mut data = vec![];
+                while buffer.pos < rdata_end {
+                    let string_length = buffer.read_u8()? as usize;
+                    data.push(buffer.read_bytes_from(string_length, buffer.pos)?.to_vec());
+                    buffer.skip(string_length)?;
+                }
+                Record::TXT {
+                    name,
+                    class,
+                    ttl,
+                    data,
+                }
+            }
+
+            28 => Record::AAAA {
                 name,
                 class,
                 ttl,
                 ip: Ipv6Addr::new(
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
-                    buffer.read_u16().unwrap(),
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
+                    buffer.read_u16()?,
                 ),
-            }),
+            },
 
-            _ => Ok(Record::UNKNOWN {
+            33 => Record::SRV {
                 name,
-                rtype,
                 class,
                 ttl,
-                length,
-            }),
+                priority: buffer.read_u16()?,
+                weight: buffer.read_u16()?,
+                port: buffer.read_u16()?,
+                target: buffer.read_qname()?,
+            },
+
+            41 => {
+                let rdata_end = rdata_start + length as usize;
+                let mut options = vec![];
+                while buffer.pos < rdata_end {
+                    let code = buffer.read_u16()?;
+                    let option_length = buffer.read_u16()? as usize;
+                    let data = buffer.read_bytes_from(option_length, buffer.pos)?.to_vec();
+                    buffer.skip(option_length)?;
+                    options.push(EdnsOption { code, data });
+                }
+
+                Record::OPT {
+                    udp_payload_size: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    dnssec_ok: ttl & 0x8000 != 0,
+                    options,
+                }
+            }
+
+            _ => {
+                let data = buffer
+                    .read_bytes_from(length as usize, rdata_start)?
+                    .to_vec();
+                buffer.skip(length as usize)?;
+                Record::UNKNOWN {
+                    name,
+                    rtype,
+                    class,
+                    ttl,
+                    data,
+                }
+            }
+        };
+
+        let consumed = buffer.pos - rdata_start;
+        if consumed != length as usize {
+            return Err(ParseError::RdLengthMismatch {
+                offset: rdata_start,
+                expected: length,
+                actual: consumed,
+            });
         }
+
+        Ok(record)
     }
 
     fn parse_ip(ip: u32) -> [u8; 4] {
@@ -266,112 +579,180 @@ impl Record {
 
 impl Record {
     fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        let start = buffer.pos;
+        buffer.write_qname(self.name())?;
+        buffer.write_u16(self.to_num())?;
+        buffer.write_u16(self.class())?;
+        buffer.write_u32(self.ttl())?;
+
+        let pos = buffer.pos;
+        buffer.write_u16(0)?; // length, filled in once rdata is written
+
         match self {
-            Record::A {
-                name,
-                class,
-                ttl,
-                ip,
-            } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(self.to_num())?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
-                buffer.write_u16(4)?;
+            Record::A { ip, .. } => {
                 buffer.write_u8(ip[0])?;
                 buffer.write_u8(ip[1])?;
                 buffer.write_u8(ip[2])?;
                 buffer.write_u8(ip[3])?;
             }
 
-            Record::NS {
-                name,
-                class,
-                ttl,
-                host,
-            } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(self.to_num())?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
+            Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+                buffer.write_qname(host)?;
+            }
 
-                let pos = buffer.pos;
-                buffer.write_u16(0)?;
-                buffer.write_qname(&host)?;
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(*serial)?;
+                buffer.write_u32(*refresh)?;
+                buffer.write_u32(*retry)?;
+                buffer.write_u32(*expire)?;
+                buffer.write_u32(*minimum)?;
+            }
 
-                let size = buffer.pos - pos;
-                buffer.set_u16(size as u16, pos)?;
+            Record::MX { priority, host, .. } => {
+                buffer.write_u16(*priority)?;
+                buffer.write_qname(host)?;
             }
 
-            Record::CNAME {
-                name,
-                class,
-                host,
-                ttl,
-            } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(self.to_num())?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
-
-                let pos = buffer.pos;
-                buffer.write_u16(0)?; //length
-                buffer.write_qname(&host)?;
-                let size = buffer.pos - pos - 2;
-                buffer.set_u16(size as u16, pos)?;
+            Record::TXT { data, .. } => {
+                for string in data.iter() {
+                    if string.len() > 255 {
+                        return Err("txt character-string longer than 255 bytes".into());
+                    }
+                    buffer.write_u8(string.len() as u8)?;
+                    for x in string.iter() {
+                        buffer.write_u8(*x)?;
+                    }
+                }
             }
 
-            Record::MX {
-                name,
-                class,
+            Record::SRV {
                 priority,
-                host,
-                ttl,
+                weight,
+                port,
+                target,
+                ..
             } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(self.to_num())?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
-
-                let pos = buffer.pos;
-                buffer.write_u16(0)?;
                 buffer.write_u16(*priority)?;
-                buffer.write_qname(&host)?;
+                buffer.write_u16(*weight)?;
+                buffer.write_u16(*port)?;
+                // RFC 2782: the target must not be compressed
+                buffer.write_qname_uncompressed(target)?;
+            }
 
-                let size = buffer.pos - pos - 2;
-                buffer.set_u16(size as u16, pos)?;
+            Record::AAAA { ip, .. } => {
+                for x in ip.octets().iter() {
+                    buffer.write_u8(*x)?;
+                }
             }
 
-            Record::AAAA {
-                name,
-                class,
-                ttl,
-                ip,
-            } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
-                ip.octets()
-                    .iter()
-                    .for_each(|x| buffer.write_u8(*x).unwrap());
+            Record::OPT { options, .. } => {
+                for option in options.iter() {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for x in option.data.iter() {
+                        buffer.write_u8(*x)?;
+                    }
+                }
             }
 
-            Record::UNKNOWN {
-                name,
-                rtype,
-                class,
-                ttl,
-                length,
+            Record::UNKNOWN { data, .. } => {
+                for x in data.iter() {
+                    buffer.write_u8(*x)?;
+                }
+            }
+        }
+
+        let size = buffer.pos - pos - 2;
+        buffer.set_u16(size as u16, pos)?;
+
+        Ok(buffer.pos - start)
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Record::A { name, .. }
+            | Record::NS { name, .. }
+            | Record::CNAME { name, .. }
+            | Record::SOA { name, .. }
+            | Record::PTR { name, .. }
+            | Record::MX { name, .. }
+            | Record::TXT { name, .. }
+            | Record::AAAA { name, .. }
+            | Record::SRV { name, .. }
+            | Record::UNKNOWN { name, .. } => name,
+            Record::OPT { .. } => "",
+        }
+    }
+
+    pub fn class(&self) -> u16 {
+        match *self {
+            Record::A { class, .. }
+            | Record::NS { class, .. }
+            | Record::CNAME { class, .. }
+            | Record::SOA { class, .. }
+            | Record::PTR { class, .. }
+            | Record::MX { class, .. }
+            | Record::TXT { class, .. }
+            | Record::AAAA { class, .. }
+            | Record::SRV { class, .. }
+            | Record::UNKNOWN { class, .. } => class,
+            Record::OPT {
+                udp_payload_size, ..
+            } => udp_payload_size,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::TXT { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::SRV { ttl, .. }
+            | Record::UNKNOWN { ttl, .. } => ttl,
+            Record::OPT {
+                extended_rcode,
+                version,
+                dnssec_ok,
+                ..
             } => {
-                buffer.write_qname(&name)?;
-                buffer.write_u16(*rtype)?;
-                buffer.write_u16(*class)?;
-                buffer.write_u32(*ttl)?;
-                buffer.write_u16(*length)?;
+                let do_bit = if dnssec_ok { 0x8000 } else { 0 };
+                ((extended_rcode as u32) << 24) | ((version as u32) << 16) | do_bit
             }
         }
+    }
 
-        Ok(0)
+    /// changes the ttl, e.g. to count it down while the record is cached. OPT
+    /// records are left alone since their ttl field holds flags.
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::TXT { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::SRV { ttl, .. }
+            | Record::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+            Record::OPT { .. } => {}
+        }
     }
 
     pub fn to_num(&self) -> u16 {
@@ -379,14 +760,232 @@ impl Record {
             Record::A { .. } => 1,
             Record::NS { .. } => 2,
             Record::CNAME { .. } => 5,
+            Record::SOA { .. } => 6,
+            Record::PTR { .. } => 12,
             Record::MX { .. } => 15,
+            Record::TXT { .. } => 16,
             Record::AAAA { .. } => 28,
+            Record::SRV { .. } => 33,
+            Record::OPT { .. } => 41,
             Record::UNKNOWN { rtype, .. } => rtype,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// presentation format, as used in zone files (RFC 1035 section 5), e.g.
+/// `example.com. 300 IN A 1.2.3.4`
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Record::OPT {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok,
+            options,
+        } = self
+        {
+            // not a real record, so shown the way dig does
+            let flags = if *dnssec_ok { " do" } else { "" };
+            write!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                version, flags, udp_payload_size
+            )?;
+            if *extended_rcode != 0 {
+                write!(f, "; extended rcode: {}", extended_rcode)?;
+            }
+            for option in options.iter() {
+                write!(f, "; option {}: {}", option.code, hex(&option.data))?;
+            }
+            return Ok(());
+        }
+
+        write!(
+            f,
+            "{} {} {} {}",
+            display_name(self.name()),
+            self.ttl(),
+            class_name(self.class()),
+            QueryType::from_num(self.to_num())
+        )?;
+
+        let data = self.data_text();
+        if !data.is_empty() {
+            write!(f, " {}", data)?;
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    /// the rdata in presentation format, everything after the type
+    pub fn data_text(&self) -> String {
+        match self {
+            Record::A { ip, .. } => format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]),
+            Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+                display_name(host).to_string()
+            }
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                display_name(mname),
+                display_name(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            Record::MX { priority, host, .. } => format!("{} {}", priority, display_name(host)),
+            Record::TXT { data, .. } => data
+                .iter()
+                .map(|x| format!("\"{}\"", escape_text(x)))
+                .collect::<Vec<String>>()
+                .join(" "),
+            Record::AAAA { ip, .. } => ip.to_string(),
+            Record::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => format!("{} {} {} {}", priority, weight, port, display_name(target)),
+            Record::UNKNOWN { data, .. } => {
+                // RFC 3597 generic rdata
+                if data.is_empty() {
+                    "\\# 0".to_string()
+                } else {
+                    format!("\\# {} {}", data.len(), hex(data))
+                }
+            }
+            Record::OPT { .. } => String::new(),
+        }
+    }
+}
+
+/// reads a record in presentation format, the way Display writes it. Names
+/// have to be absolute, and the ttl and class may be left out, for 0 and IN.
+impl FromStr for Record {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Record, String> {
+        zone::parse_record(s, "")
+    }
+}
+
+/// lowercases the name and adds the trailing dot, so names can be compared.
+/// The root stays an empty string.
+pub fn normalize_name(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if name.is_empty() || is_absolute(&name) {
+        name
+    } else {
+        name + "."
+    }
+}
+
+/// whether the name ends with a dot that isn't escaped
+pub fn is_absolute(name: &str) -> bool {
+    match name.strip_suffix('.') {
+        Some(rest) => rest
+            .bytes()
+            .rev()
+            .take_while(|x| *x == b'\\')
+            .count()
+            .is_multiple_of(2),
+        None => false,
+    }
+}
+
+/// the labels of a name as text, split on the dots that aren't escaped
+pub fn labels(name: &str) -> Vec<&str> {
+    let mut labels = vec![];
+    if name.is_empty() || name == "." {
+        return labels;
+    }
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in name.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '.' => {
+                labels.push(&name[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < name.len() {
+        labels.push(&name[start..]);
+    }
+    labels
+}
+
+/// the name with its first label removed, or None for the root
+pub fn parent_name(name: &str) -> Option<&str> {
+    match labels(name).first() {
+        None => None,
+        Some(first) if first.len() + 1 < name.len() => Some(&name[first.len() + 1..]),
+        Some(_) => Some(""),
+    }
+}
+
+/// whether `name` is `zone` itself or somewhere below it
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = normalize_name(name);
+    let zone = normalize_name(zone);
+    labels(&name).ends_with(&labels(&zone))
+}
+
+/// the root is stored as an empty string, but shown as "."
+pub fn display_name(name: &str) -> &str {
+    if name.is_empty() {
+        "."
+    } else {
+        name
+    }
+}
+
+pub fn class_name(class: u16) -> String {
+    match class {
+        1 => String::from("IN"),
+        3 => String::from("CH"),
+        4 => String::from("HS"),
+        255 => String::from("ANY"),
+        x => format!("CLASS{}", x),
+    }
+}
+
+/// escapes a character-string for use inside double quotes
+pub(crate) fn escape_text(string: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &x in string.iter() {
+        match x {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(x as char);
+            }
+            0x20..=0x7e => escaped.push(x as char),
+            _ => escaped += &format!("\\{:03}", x),
+        }
+    }
+    escaped
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Header {
     pub id: u16,
     pub qr: PacketType,
@@ -422,28 +1021,41 @@ impl Header {
         }
     }
 
-    fn read_header(&mut self, buffer: &mut BytePacketBuffer) {
-        self.id = buffer.read_u16().unwrap();
-        let flags = buffer.read_u16().unwrap();
+    fn read_header(&mut self, buffer: &mut BytePacketBuffer) -> ParseResult<()> {
+        if buffer.size < 12 {
+            return Err(ParseError::TruncatedHeader {
+                offset: buffer.size,
+            });
+        }
+        self.id = buffer.read_u16()?;
+        let flags = buffer.read_u16()?;
         self.qr = ((flags >> 15) & 1).into();
         self.opcode = ((flags << 1) >> 12) as u8;
+        if !matches!(self.opcode, 0 | 1 | 2 | 4 | 5) {
+            return Err(ParseError::UnknownOpcode {
+                offset: 2,
+                opcode: self.opcode,
+            });
+        }
         self.authoritative = (flags << 5) >> 15 == 1;
         self.is_truncated = (flags << 6) >> 15 == 1;
         self.recursion_desired = (flags << 7) >> 15 == 1;
         self.recursion_available = (flags << 8) >> 15 == 1;
         self.reserved = ((flags << 9) >> 13) as u8;
-        self.rcode = ((flags << 12) >> 12).into();
+        let rcode = (flags << 12) >> 12;
+        self.rcode = ResponseCode::from(rcode);
 
-        self.ques_c = buffer.read_u16().unwrap();
-        self.ans_c = buffer.read_u16().unwrap();
-        self.auth_c = buffer.read_u16().unwrap();
-        self.addi_c = buffer.read_u16().unwrap();
+        self.ques_c = buffer.read_u16()?;
+        self.ans_c = buffer.read_u16()?;
+        self.auth_c = buffer.read_u16()?;
+        self.addi_c = buffer.read_u16()?;
+        Ok(())
     }
 }
 
 impl Header {
-    fn write(&self, buffer: &mut BytePacketBuffer) {
-        let _ = buffer.write_u16(self.id);
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16(self.id)?;
 
         let mut flags = u16::from(&self.qr) << 15;
         flags = ((flags >> 11) | self.opcode as u16) << 11;
@@ -452,13 +1064,15 @@ impl Header {
         flags = ((flags >> 8) | self.to_u16(self.recursion_desired)) << 8;
         flags = ((flags >> 7) | self.to_u16(self.recursion_available)) << 7;
         flags = ((flags >> 4) | self.reserved as u16) << 4;
-        flags |= u16::from(&self.rcode);
-        let _ = buffer.write_u16(flags);
+        // codes above 15 need the extended bits of an OPT record
+        flags |= u16::from(&self.rcode) & 0xf;
+        buffer.write_u16(flags)?;
 
-        let _ = buffer.write_u16(self.ques_c);
-        let _ = buffer.write_u16(self.ans_c);
-        let _ = buffer.write_u16(self.auth_c);
-        let _ = buffer.write_u16(self.addi_c);
+        buffer.write_u16(self.ques_c)?;
+        buffer.write_u16(self.ans_c)?;
+        buffer.write_u16(self.auth_c)?;
+        buffer.write_u16(self.addi_c)?;
+        Ok(())
     }
 
     fn to_u16(&self, val: bool) -> u16 {
@@ -470,7 +1084,41 @@ impl Header {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// e.g. `;; opcode: QUERY, status: NOERROR, id: 4660` and the flags which
+/// are set on a second line
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = match self.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            x => format!("OPCODE{}", x),
+        };
+        writeln!(
+            f,
+            ";; opcode: {}, status: {}, id: {}",
+            opcode, self.rcode, self.id
+        )?;
+        let flags = [
+            ("qr", self.qr == PacketType::Response),
+            ("aa", self.authoritative),
+            ("tc", self.is_truncated),
+            ("rd", self.recursion_desired),
+            ("ra", self.recursion_available),
+        ];
+        f.write_str(";; flags:")?;
+        for (name, set) in flags.iter() {
+            if *set {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum PacketType {
     Query,    // 0
     Response, // 1
@@ -478,10 +1126,11 @@ pub enum PacketType {
 
 impl From<u16> for PacketType {
     fn from(val: u16) -> Self {
-        match val {
-            0 => PacketType::Query,
-            1 => PacketType::Response,
-            _ => panic!("unexpected value"),
+        // only the lowest bit carries the QR flag
+        if val & 1 == 0 {
+            PacketType::Query
+        } else {
+            PacketType::Response
         }
     }
 }
@@ -495,7 +1144,7 @@ impl From<&PacketType> for u16 {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum ResponseCode {
     no_error, // no eror condition
@@ -505,6 +1154,8 @@ pub enum ResponseCode {
     not_imp,
     refused,
     no_data,
+    /// any other code, kept as it was so the message still parses
+    unknown(u16),
 }
 
 impl From<u16> for ResponseCode {
@@ -517,11 +1168,32 @@ impl From<u16> for ResponseCode {
             4 => ResponseCode::not_imp,
             5 => ResponseCode::refused,
             6 => ResponseCode::no_data,
-            _ => panic!("invalid response code"),
+            x => ResponseCode::unknown(x),
         }
     }
 }
 
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResponseCode::no_error => "NOERROR",
+            ResponseCode::format_err => "FORMERR",
+            ResponseCode::serv_fail => "SERVFAIL",
+            ResponseCode::nx_domain => "NXDOMAIN",
+            ResponseCode::not_imp => "NOTIMP",
+            ResponseCode::refused => "REFUSED",
+            ResponseCode::no_data => "YXDOMAIN",
+            ResponseCode::unknown(7) => "YXRRSET",
+            ResponseCode::unknown(8) => "NXRRSET",
+            ResponseCode::unknown(9) => "NOTAUTH",
+            ResponseCode::unknown(10) => "NOTZONE",
+            ResponseCode::unknown(16) => "BADVERS",
+            ResponseCode::unknown(x) => return write!(f, "RCODE{}", x),
+        };
+        f.write_str(name)
+    }
+}
+
 impl From<&ResponseCode> for u16 {
     fn from(val: &ResponseCode) -> u16 {
         match val {
@@ -532,6 +1204,7 @@ impl From<&ResponseCode> for u16 {
             ResponseCode::not_imp => 4,
             ResponseCode::refused => 5,
             ResponseCode::no_data => 6,
+            ResponseCode::unknown(x) => *x,
         }
     }
 }
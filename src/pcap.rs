@@ -0,0 +1,714 @@
+use super::buffer::{BytePacketBuffer, ParseError, Result};
+use super::lookup::Protocol;
+use super::packet::{Packet, PacketType, QueryType, Record, ResponseCode};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+
+pub const USAGE: &str = "\
+druns-pcap, reads DNS traffic from pcap and pcapng files and reports on it
+
+usage: druns-pcap [options] <file>...
+
+Messages are taken from UDP and TCP on port 53, over IPv4 and IPv6, and go
+through the same parser as the server.
+
+options:
+  -h, --help              print this help
+  -V, --version           print the version
+";
+
+const DNS_PORT: u16 = 53;
+
+/// parse failures and capture problems listed in the report, the rest are
+/// only counted
+const MAX_LISTED: usize = 20;
+
+/// a DNS message found in a capture. Over TCP it is one message of the
+/// reassembled stream, without the length prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    /// 1 for the first packet in the file. Over TCP, the packet that
+    /// completed the message.
+    pub frame: usize,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub protocol: Protocol,
+    pub data: Vec<u8>,
+}
+
+/// something in the capture that kept DNS traffic from being read
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub frame: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Capture {
+    pub frames: usize,
+    /// in frame order
+    pub payloads: Vec<Payload>,
+    pub problems: Vec<Problem>,
+}
+
+pub fn read(path: &Path) -> Result<Capture> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// the DNS payloads in a pcap or pcapng file. IP fragments are reported as
+/// problems rather than reassembled.
+pub fn parse(bytes: &[u8]) -> Result<Capture> {
+    let frames = match bytes.get(0..4) {
+        Some([0x0a, 0x0d, 0x0d, 0x0a]) => pcapng_frames(bytes)?,
+        Some(_) => pcap_frames(bytes)?,
+        None => return Err("not a pcap or pcapng file".into()),
+    };
+
+    let mut extractor = Extractor::default();
+    for frame in frames.iter() {
+        extractor.frame(frame);
+    }
+    Ok(extractor.finish(frames.len()))
+}
+
+/// a packet as captured, starting with the link layer header
+struct Frame<'a> {
+    number: usize,
+    linktype: u32,
+    data: &'a [u8],
+}
+
+fn u16_at(bytes: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let x = [*bytes.get(at)?, *bytes.get(at + 1)?];
+    Some(if big_endian {
+        u16::from_be_bytes(x)
+    } else {
+        u16::from_le_bytes(x)
+    })
+}
+
+fn u32_at(bytes: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let x = [
+        *bytes.get(at)?,
+        *bytes.get(at + 1)?,
+        *bytes.get(at + 2)?,
+        *bytes.get(at + 3)?,
+    ];
+    Some(if big_endian {
+        u32::from_be_bytes(x)
+    } else {
+        u32::from_le_bytes(x)
+    })
+}
+
+fn pcap_frames(bytes: &[u8]) -> Result<Vec<Frame<'_>>> {
+    // microsecond and nanosecond timestamps, either byte order
+    let big_endian = match u32_at(bytes, 0, false) {
+        Some(0xa1b2_c3d4) | Some(0xa1b2_3c4d) => false,
+        Some(0xd4c3_b2a1) | Some(0x4d3c_b2a1) => true,
+        _ => return Err("not a pcap or pcapng file".into()),
+    };
+    let linktype = u32_at(bytes, 20, big_endian).ok_or("truncated pcap file header")?;
+    // the upper bits say whether frames carry an FCS
+    let linktype = linktype & 0x0fff_ffff;
+
+    let mut frames = vec![];
+    let mut pos = 24;
+    while pos < bytes.len() {
+        let error = || format!("truncated packet record at offset {}", pos);
+        let length = u32_at(bytes, pos + 8, big_endian).ok_or_else(error)? as usize;
+        let data = bytes.get(pos + 16..pos + 16 + length).ok_or_else(error)?;
+        frames.push(Frame {
+            number: frames.len() + 1,
+            linktype,
+            data,
+        });
+        pos += 16 + length;
+    }
+    Ok(frames)
+}
+
+fn pcapng_frames(bytes: &[u8]) -> Result<Vec<Frame<'_>>> {
+    let mut frames = vec![];
+    // link types of the interfaces in the current section
+    let mut interfaces: Vec<u32> = vec![];
+    let mut big_endian = false;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let error = || format!("truncated block at offset {}", pos);
+        let block_type = u32_at(bytes, pos, big_endian).ok_or_else(error)?;
+        if block_type == 0x0a0d_0d0a {
+            // section header, whose byte order magic sets the order of
+            // everything up to the next one
+            big_endian = match u32_at(bytes, pos + 8, false) {
+                Some(0x1a2b_3c4d) => false,
+                Some(0x4d3c_2b1a) => true,
+                _ => return Err(format!("bad byte order magic at offset {}", pos + 8).into()),
+            };
+            interfaces.clear();
+        }
+        let length = u32_at(bytes, pos + 4, big_endian).ok_or_else(error)? as usize;
+        if length < 12 || !length.is_multiple_of(4) {
+            return Err(format!("bad block length {} at offset {}", length, pos).into());
+        }
+        let block = bytes.get(pos..pos + length).ok_or_else(error)?;
+
+        let packet = match block_type {
+            // interface description
+            1 => {
+                interfaces.push(u16_at(block, 8, big_endian).ok_or_else(error)? as u32);
+                None
+            }
+            // enhanced packet
+            6 => {
+                let interface = u32_at(block, 8, big_endian).ok_or_else(error)?;
+                let captured = u32_at(block, 20, big_endian).ok_or_else(error)? as usize;
+                let data = block.get(28..28 + captured).ok_or_else(error)?;
+                Some((linktype(&interfaces, interface, pos)?, data))
+            }
+            // simple packet, always from the first interface
+            3 => {
+                let original = u32_at(block, 8, big_endian).ok_or_else(error)? as usize;
+                let captured = original.min(length.saturating_sub(16));
+                Some((linktype(&interfaces, 0, pos)?, &block[12..12 + captured]))
+            }
+            // the obsolete packet block
+            2 => {
+                let interface = u16_at(block, 8, big_endian).ok_or_else(error)?;
+                let captured = u32_at(block, 20, big_endian).ok_or_else(error)? as usize;
+                let data = block.get(28..28 + captured).ok_or_else(error)?;
+                Some((linktype(&interfaces, interface as u32, pos)?, data))
+            }
+            _ => None,
+        };
+        if let Some((linktype, data)) = packet {
+            frames.push(Frame {
+                number: frames.len() + 1,
+                linktype,
+                data,
+            });
+        }
+        pos += length;
+    }
+    Ok(frames)
+}
+
+fn linktype(interfaces: &[u32], interface: u32, pos: usize) -> Result<u32> {
+    interfaces
+        .get(interface as usize)
+        .copied()
+        .ok_or_else(|| format!("unknown interface {} in block at offset {}", interface, pos).into())
+}
+
+/// whether a UDP or TCP header is for the DNS port, either way
+fn is_dns(header: &[u8]) -> bool {
+    u16_at(header, 0, true) == Some(DNS_PORT) || u16_at(header, 2, true) == Some(DNS_PORT)
+}
+
+/// one direction of a TCP connection
+type Flow = (SocketAddr, SocketAddr);
+
+#[derive(Default)]
+struct Stream {
+    /// sequence number of the first byte, from the SYN or else the first
+    /// segment seen
+    start: Option<u32>,
+    /// data by offset from `start`, with the frame it came in
+    segments: BTreeMap<u32, (usize, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct Extractor {
+    payloads: Vec<Payload>,
+    problems: Vec<Problem>,
+    streams: HashMap<Flow, Stream>,
+    /// order the streams were first seen in, so problems come out the same
+    /// way on every run
+    flows: Vec<Flow>,
+    unsupported: Vec<u32>,
+}
+
+impl Extractor {
+    fn problem(&mut self, frame: usize, message: String) {
+        self.problems.push(Problem { frame, message });
+    }
+
+    fn frame(&mut self, frame: &Frame<'_>) {
+        let data = frame.data;
+        let ip = match frame.linktype {
+            // BSD loopback, the family is in the byte order of the machine
+            // that made the capture
+            0 | 108 => data.get(4..).filter(|_| {
+                let family = u32_at(data, 0, frame.linktype == 108).unwrap_or(0);
+                let family = if family > 0xffff {
+                    family.swap_bytes()
+                } else {
+                    family
+                };
+                matches!(family, 2 | 24 | 28 | 30)
+            }),
+            // ethernet, possibly with VLAN tags
+            1 => {
+                let mut pos = 12;
+                while matches!(u16_at(data, pos, true), Some(0x8100) | Some(0x88a8)) {
+                    pos += 4;
+                }
+                match u16_at(data, pos, true) {
+                    Some(0x0800) | Some(0x86dd) => data.get(pos + 2..),
+                    _ => None,
+                }
+            }
+            // raw IP
+            12 | 14 | 101 | 228 | 229 => Some(data),
+            // linux cooked capture, v1 and v2
+            113 => match u16_at(data, 14, true) {
+                Some(0x0800) | Some(0x86dd) => data.get(16..),
+                _ => None,
+            },
+            276 => match u16_at(data, 0, true) {
+                Some(0x0800) | Some(0x86dd) => data.get(20..),
+                _ => None,
+            },
+            linktype => {
+                if !self.unsupported.contains(&linktype) {
+                    self.unsupported.push(linktype);
+                    self.problem(frame.number, format!("unsupported link type {}", linktype));
+                }
+                None
+            }
+        };
+        if let Some(ip) = ip {
+            self.ip(frame.number, ip);
+        }
+    }
+
+    fn ip(&mut self, frame: usize, data: &[u8]) {
+        let version = data.first().map(|x| x >> 4);
+        let (source, destination, protocol, payload) = match version {
+            Some(4) if data.len() >= 20 => {
+                let header = (data[0] & 0x0f) as usize * 4;
+                let total = u16_at(data, 2, true).unwrap_or(0) as usize;
+                let flags = u16_at(data, 6, true).unwrap_or(0);
+                let protocol = data[9];
+                if !matches!(protocol, 6 | 17) {
+                    return;
+                }
+                // only the first fragment has the ports, so the others are
+                // left alone
+                if flags & 0x1fff != 0 {
+                    return;
+                }
+                if header < 20 || total < header || header > data.len() {
+                    return self.problem(frame, "bad IPv4 header".to_string());
+                }
+                let fragmented = flags & 0x2000 != 0;
+                let source = IpAddr::from(Ipv4Addr::new(data[12], data[13], data[14], data[15]));
+                let destination =
+                    IpAddr::from(Ipv4Addr::new(data[16], data[17], data[18], data[19]));
+                if fragmented {
+                    if is_dns(&data[header..]) {
+                        self.problem(
+                            frame,
+                            format!("fragmented IPv4 packet from {}, skipped", source),
+                        );
+                    }
+                    return;
+                }
+                if total > data.len() {
+                    return self.problem(
+                        frame,
+                        format!("captured {} of {} IPv4 bytes", data.len(), total),
+                    );
+                }
+                (source, destination, protocol, &data[header..total])
+            }
+            Some(6) if data.len() >= 40 => {
+                let total = 40 + u16_at(data, 4, true).unwrap_or(0) as usize;
+                let mut source = [0; 16];
+                source.copy_from_slice(&data[8..24]);
+                let source = IpAddr::from(Ipv6Addr::from(source));
+                let mut destination = [0; 16];
+                destination.copy_from_slice(&data[24..40]);
+                let destination = IpAddr::from(Ipv6Addr::from(destination));
+                if total > data.len() {
+                    return self.problem(
+                        frame,
+                        format!("captured {} of {} IPv6 bytes", data.len(), total),
+                    );
+                }
+
+                // walk the extension headers
+                let mut next = data[6];
+                let mut pos = 40;
+                loop {
+                    match next {
+                        6 | 17 => break,
+                        // hop by hop, routing, destination options
+                        0 | 43 | 60 => {
+                            let length = match data.get(pos + 1) {
+                                Some(x) => (*x as usize + 1) * 8,
+                                None => return,
+                            };
+                            next = data[pos];
+                            pos += length;
+                        }
+                        // authentication header
+                        51 => {
+                            let length = match data.get(pos + 1) {
+                                Some(x) => (*x as usize + 2) * 4,
+                                None => return,
+                            };
+                            next = data[pos];
+                            pos += length;
+                        }
+                        44 => {
+                            let (offset, more) = match u16_at(data, pos + 2, true) {
+                                Some(x) => (x >> 3, x & 1 != 0),
+                                None => return,
+                            };
+                            if offset == 0 && more && is_dns(&data[(pos + 8).min(total)..]) {
+                                self.problem(
+                                    frame,
+                                    format!("fragmented IPv6 packet from {}, skipped", source),
+                                );
+                            }
+                            if offset != 0 || more {
+                                return;
+                            }
+                            next = data[pos];
+                            pos += 8;
+                        }
+                        _ => return,
+                    }
+                    if pos > total {
+                        return;
+                    }
+                }
+                (source, destination, next, &data[pos..total])
+            }
+            _ => return,
+        };
+
+        match protocol {
+            17 => self.udp(frame, source, destination, payload),
+            _ => self.tcp(frame, source, destination, payload),
+        }
+    }
+
+    fn udp(&mut self, frame: usize, source: IpAddr, destination: IpAddr, data: &[u8]) {
+        if data.len() < 8 || !is_dns(data) {
+            return;
+        }
+        let length = (u16_at(data, 4, true).unwrap_or(0) as usize).clamp(8, data.len());
+        self.payloads.push(Payload {
+            frame,
+            source: SocketAddr::new(source, u16_at(data, 0, true).unwrap_or(0)),
+            destination: SocketAddr::new(destination, u16_at(data, 2, true).unwrap_or(0)),
+            protocol: Protocol::Udp,
+            data: data[8..length].to_vec(),
+        });
+    }
+
+    fn tcp(&mut self, frame: usize, source: IpAddr, destination: IpAddr, data: &[u8]) {
+        if data.len() < 20 || !is_dns(data) {
+            return;
+        }
+        let flow = (
+            SocketAddr::new(source, u16_at(data, 0, true).unwrap_or(0)),
+            SocketAddr::new(destination, u16_at(data, 2, true).unwrap_or(0)),
+        );
+        let seq = u32_at(data, 4, true).unwrap_or(0);
+        let header = (data[12] >> 4) as usize * 4;
+        let syn = data[13] & 0x02 != 0;
+        let payload = match data.get(header..) {
+            Some(payload) if header >= 20 => payload,
+            _ => return self.problem(frame, format!("bad TCP header from {}", flow.0)),
+        };
+
+        if syn {
+            // a new connection on the same ports ends the old one
+            if let Some(stream) = self.streams.remove(&flow) {
+                self.flush(flow, stream);
+            }
+            let stream = Stream {
+                start: Some(seq.wrapping_add(1)),
+                ..Stream::default()
+            };
+            self.streams.insert(flow, stream);
+            self.flows.retain(|x| *x != flow);
+            self.flows.push(flow);
+        }
+        if payload.is_empty() {
+            return;
+        }
+        if !self.streams.contains_key(&flow) {
+            self.flows.push(flow);
+        }
+        let stream = self.streams.entry(flow).or_default();
+        let start = *stream.start.get_or_insert(seq);
+        let offset = seq.wrapping_sub(start);
+        // retransmitted data from before the first segment seen
+        if offset > u32::MAX / 2 {
+            return;
+        }
+        let segment = stream
+            .segments
+            .entry(offset)
+            .or_insert_with(|| (frame, vec![]));
+        if payload.len() > segment.1.len() {
+            *segment = (frame, payload.to_vec());
+        }
+    }
+
+    /// splits the data of a stream into length prefixed messages
+    fn flush(&mut self, flow: Flow, stream: Stream) {
+        let mut data: Vec<u8> = vec![];
+        // byte range of `data` each frame supplied
+        let mut frames: Vec<(usize, usize, usize)> = vec![];
+        for (offset, (frame, segment)) in stream.segments.iter() {
+            let offset = *offset as usize;
+            if offset > data.len() {
+                let message = format!(
+                    "gap in TCP stream {} -> {} after {} bytes",
+                    flow.0,
+                    flow.1,
+                    data.len()
+                );
+                self.problem(*frame, message);
+                break;
+            }
+            if offset + segment.len() > data.len() {
+                let start = data.len();
+                data.extend_from_slice(&segment[start - offset..]);
+                frames.push((start, data.len(), *frame));
+            }
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let length = match u16_at(&data, pos, true) {
+                Some(length) if pos + 2 + length as usize <= data.len() => length as usize,
+                _ => {
+                    let frame = frames.last().map(|x| x.2).unwrap_or(0);
+                    let message = format!(
+                        "incomplete message at the end of TCP stream {} -> {}",
+                        flow.0, flow.1
+                    );
+                    self.problem(frame, message);
+                    break;
+                }
+            };
+            let end = pos + 2 + length;
+            // segments can arrive out of order, the message is complete
+            // with the latest one up to its end
+            let frame = frames
+                .iter()
+                .filter(|x| x.0 < end)
+                .map(|x| x.2)
+                .max()
+                .unwrap_or(0);
+            self.payloads.push(Payload {
+                frame,
+                source: flow.0,
+                destination: flow.1,
+                protocol: Protocol::Tcp,
+                data: data[pos + 2..end].to_vec(),
+            });
+            pos = end;
+        }
+    }
+
+    fn finish(mut self, frames: usize) -> Capture {
+        for flow in std::mem::take(&mut self.flows) {
+            if let Some(stream) = self.streams.remove(&flow) {
+                self.flush(flow, stream);
+            }
+        }
+        self.payloads.sort_by_key(|x| x.frame);
+        self.problems.sort_by_key(|x| x.frame);
+        Capture {
+            frames,
+            payloads: self.payloads,
+            problems: self.problems,
+        }
+    }
+}
+
+/// a payload that `Packet::read` rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub frame: usize,
+    pub source: SocketAddr,
+    pub protocol: Protocol,
+    pub error: ParseError,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub frames: usize,
+    pub queries: usize,
+    pub responses: usize,
+    pub failures: Vec<Failure>,
+    pub problems: Vec<Problem>,
+    /// by type number, of the questions of queries
+    pub query_types: BTreeMap<u16, usize>,
+    /// by type number, of the records in every section of every message
+    pub record_types: BTreeMap<u16, usize>,
+    /// by rcode number, of responses, with the extended bits from EDNS
+    pub rcodes: BTreeMap<u16, usize>,
+    /// of responses, smallest first
+    pub response_sizes: Vec<usize>,
+}
+
+/// runs every payload through `Packet::read` and tallies the results
+pub fn report(capture: &Capture) -> Report {
+    let mut report = Report {
+        frames: capture.frames,
+        problems: capture.problems.clone(),
+        ..Report::default()
+    };
+    for payload in capture.payloads.iter() {
+        let packet = match Packet::read(&mut BytePacketBuffer::from_bytes(&payload.data)) {
+            Ok(packet) => packet,
+            Err(error) => {
+                report.failures.push(Failure {
+                    frame: payload.frame,
+                    source: payload.source,
+                    protocol: payload.protocol,
+                    error,
+                });
+                continue;
+            }
+        };
+
+        if packet.header.qr == PacketType::Response {
+            report.responses += 1;
+            // the OPT record holds the upper 8 of the 12 bits
+            let rcode = match packet.edns() {
+                Some(Record::OPT { extended_rcode, .. }) => {
+                    (u16::from(*extended_rcode) << 4) | u16::from(&packet.header.rcode)
+                }
+                _ => u16::from(&packet.header.rcode),
+            };
+            *report.rcodes.entry(rcode).or_default() += 1;
+            report.response_sizes.push(payload.data.len());
+        } else {
+            report.queries += 1;
+            for question in packet.questions.iter() {
+                *report
+                    .query_types
+                    .entry(question.qtype.to_num())
+                    .or_default() += 1;
+            }
+        }
+        for record in packet
+            .answers
+            .iter()
+            .chain(packet.authority.iter())
+            .chain(packet.additional.iter())
+        {
+            *report.record_types.entry(record.to_num()).or_default() += 1;
+        }
+    }
+    report.response_sizes.sort_unstable();
+    report
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "frames: {}, messages: {} ({} queries, {} responses, {} unparsed)",
+            self.frames,
+            self.queries + self.responses + self.failures.len(),
+            self.queries,
+            self.responses,
+            self.failures.len()
+        )?;
+
+        writeln!(f, "\nparse failures: {}", self.failures.len())?;
+        for failure in self.failures.iter().take(MAX_LISTED) {
+            let protocol = match failure.protocol {
+                Protocol::Udp => "udp",
+                Protocol::Tcp => "tcp",
+            };
+            writeln!(
+                f,
+                "  frame {} ({} from {}): {}",
+                failure.frame, protocol, failure.source, failure.error
+            )?;
+        }
+        more(f, self.failures.len())?;
+
+        if !self.problems.is_empty() {
+            writeln!(f, "\ncapture problems: {}", self.problems.len())?;
+            for problem in self.problems.iter().take(MAX_LISTED) {
+                writeln!(f, "  frame {}: {}", problem.frame, problem.message)?;
+            }
+            more(f, self.problems.len())?;
+        }
+
+        let type_name = |x: &u16| QueryType::from_num(*x).to_string();
+        let rcode_name = |x: &u16| ResponseCode::from(*x).to_string();
+        table(f, "query types", &self.query_types, type_name)?;
+        table(f, "record types", &self.record_types, type_name)?;
+        table(f, "response codes", &self.rcodes, rcode_name)?;
+
+        let sizes = &self.response_sizes;
+        writeln!(f, "\nresponse sizes:")?;
+        if sizes.is_empty() {
+            return writeln!(f, "  none");
+        }
+        writeln!(
+            f,
+            "  min {}, median {}, max {}, mean {:.1}",
+            sizes[0],
+            sizes[sizes.len() / 2],
+            sizes[sizes.len() - 1],
+            sizes.iter().sum::<usize>() as f64 / sizes.len() as f64
+        )?;
+        // the classic UDP limit, the EDNS default and the common maximum
+        let mut low = 0;
+        for high in [512, 1232, 4096, usize::MAX].iter() {
+            let count = sizes.iter().filter(|x| **x > low && *x <= high).count();
+            if *high == usize::MAX {
+                writeln!(f, "  {:>5}+      {}", low + 1, count)?;
+            } else {
+                writeln!(f, "  {:>5}-{:<5} {}", low + 1, high, count)?;
+            }
+            low = *high;
+        }
+        Ok(())
+    }
+}
+
+fn more(f: &mut fmt::Formatter<'_>, count: usize) -> fmt::Result {
+    if count > MAX_LISTED {
+        writeln!(f, "  ... and {} more", count - MAX_LISTED)?;
+    }
+    Ok(())
+}
+
+/// counts by name, most common first
+fn table(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    counts: &BTreeMap<u16, usize>,
+    name: impl Fn(&u16) -> String,
+) -> fmt::Result {
+    writeln!(f, "\n{}:", title)?;
+    if counts.is_empty() {
+        return writeln!(f, "  none");
+    }
+    let mut rows: Vec<(String, usize)> = counts.iter().map(|(k, v)| (name(k), *v)).collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    for (name, count) in rows {
+        writeln!(f, "  {:<10} {}", name, count)?;
+    }
+    Ok(())
+}
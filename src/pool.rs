@@ -0,0 +1,162 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// jobs waiting for a worker, kept per client and handed out round robin, so
+/// a client with many slow queries only ever holds up its own. A client also
+/// gets only so many workers at once, or its slow jobs could take them all.
+struct Queue {
+    jobs: HashMap<IpAddr, VecDeque<Job>>,
+    /// clients with waiting jobs, the next one to be served first
+    order: VecDeque<IpAddr>,
+    len: usize,
+    capacity: usize,
+    per_client: usize,
+    /// jobs each client has with a worker
+    running: HashMap<IpAddr, usize>,
+    running_per_client: usize,
+    /// set once the pool is dropped, workers leave after the remaining jobs
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, client: IpAddr, job: Job) -> bool {
+        if self.len >= self.capacity {
+            return false;
+        }
+        let jobs = self.jobs.entry(client).or_default();
+        if jobs.len() >= self.per_client {
+            return false;
+        }
+        if jobs.is_empty() {
+            self.order.push_back(client);
+        }
+        jobs.push_back(job);
+        self.len += 1;
+        true
+    }
+
+    /// the next job of the first client in turn that may run another one
+    fn pop(&mut self) -> Option<(IpAddr, Job)> {
+        let running = &self.running;
+        let limit = self.running_per_client;
+        let i = self
+            .order
+            .iter()
+            .position(|x| running.get(x).copied().unwrap_or(0) < limit)?;
+        let client = self.order.remove(i)?;
+        let jobs = self.jobs.get_mut(&client)?;
+        let job = jobs.pop_front()?;
+        if jobs.is_empty() {
+            self.jobs.remove(&client);
+        } else {
+            self.order.push_back(client);
+        }
+        self.len -= 1;
+        *self.running.entry(client).or_default() += 1;
+        Some((client, job))
+    }
+
+    fn finished(&mut self, client: IpAddr) {
+        if let Some(running) = self.running.get_mut(&client) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(&client);
+            }
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// fixed number of worker threads, which caps how many jobs run at once
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// starts `workers` threads. At most `capacity` jobs wait for them, and
+    /// no more than `per_client` of those from a single client, which may have
+    /// `running_per_client` jobs with a worker at once.
+    pub fn new(
+        workers: usize,
+        capacity: usize,
+        per_client: usize,
+        running_per_client: usize,
+    ) -> Pool {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: HashMap::new(),
+                order: VecDeque::new(),
+                len: 0,
+                capacity,
+                per_client,
+                running: HashMap::new(),
+                running_per_client: running_per_client.max(1),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        for _ in 0..workers {
+            let shared = shared.clone();
+            thread::spawn(move || work(&shared));
+        }
+        Pool { shared }
+    }
+
+    /// queues `job` on behalf of `client`, false if there is no room for it
+    pub fn submit(&self, client: IpAddr, job: Job) -> bool {
+        let queued = self.shared.queue.lock().unwrap().push(client, job);
+        if queued {
+            self.shared.ready.notify_one();
+        }
+        queued
+    }
+
+    /// number of jobs waiting for a worker
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let (client, job) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                match queue.pop() {
+                    Some(x) => break x,
+                    None if queue.closed && queue.len == 0 => return,
+                    None => queue = shared.ready.wait(queue).unwrap(),
+                }
+            }
+        };
+        // a panicking job shouldn't take the worker down with it
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("job panicked");
+        }
+        shared.queue.lock().unwrap().finished(client);
+        // the client may have jobs that were waiting on this one
+        shared.ready.notify_all();
+    }
+}
@@ -0,0 +1,77 @@
+use super::buffer::{BytePacketBuffer, Result};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+/// Reads one message framed with the two byte length prefix used over TCP
+/// (RFC 1035 4.2.2). Returns None once the peer closes the connection, or
+/// the read times out, between messages.
+pub fn read_message<R: Read>(stream: &mut R) -> Result<Option<BytePacketBuffer>> {
+    let mut length = [0u8; 2];
+    if let Err(err) = stream.read_exact(&mut length) {
+        return match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut => Ok(None),
+            _ => Err(err.into()),
+        };
+    }
+
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(Some(BytePacketBuffer::from_bytes(&message)))
+}
+
+/// same as read_message, but the whole message has to arrive within
+/// `timeout`. A timeout on each read would let a client which sends a byte
+/// now and then hold on to the connection forever.
+pub fn read_message_within(
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> Result<Option<BytePacketBuffer>> {
+    read_message(&mut Deadline {
+        stream,
+        deadline: Instant::now() + timeout,
+    })
+}
+
+/// a stream whose reads time out at `deadline`, however many there are
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self
+            .deadline
+            .checked_duration_since(Instant::now())
+            .filter(|x| !x.is_zero())
+            .ok_or_else(|| io::Error::from(ErrorKind::TimedOut))?;
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+pub fn write_message<W: Write>(stream: &mut W, buffer: &BytePacketBuffer) -> Result<()> {
+    // single write, so the prefix and message don't go out as separate segments
+    let mut message = Vec::with_capacity(buffer.size + 2);
+    message.extend_from_slice(&(buffer.size as u16).to_be_bytes());
+    message.extend_from_slice(&buffer[0..buffer.size]);
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+/// sends `request` to `server` over a fresh connection and waits for the
+/// reply. `timeout` applies to connecting and to each read and write.
+pub fn exchange(
+    server: SocketAddr,
+    request: &BytePacketBuffer,
+    timeout: Duration,
+) -> Result<BytePacketBuffer> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write_message(&mut stream, request)?;
+    read_message(&mut stream)?.ok_or_else(|| format!("{} closed the connection", server).into())
+}
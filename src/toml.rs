@@ -0,0 +1,273 @@
+//! a deliberate subset of TOML, only the part a config file needs: tables,
+//! arrays of tables, and keys holding strings, integers, booleans or arrays
+//! of those. Floats, dates, literal and multi-line strings, inline tables
+//! and dotted keys are left out on purpose, it is meant for config.rs and
+//! not as a general TOML parser.
+
+use std::{collections::BTreeMap, error::Error, fmt};
+
+/// the value of a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// a key's value along with its line, so later checks can point at it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    /// line of the table header, 0 for the top level
+    pub line: usize,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// a parsed file. Keys before the first header live in the table named "".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Document {
+    pub tables: BTreeMap<String, Table>,
+    pub arrays: BTreeMap<String, Vec<Table>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SyntaxError {}
+
+fn syntax_error<T>(line: usize, message: String) -> Result<T, SyntaxError> {
+    Err(SyntaxError { line, message })
+}
+
+enum Current {
+    Table(String),
+    Array(String),
+}
+
+pub fn parse(text: &str) -> Result<Document, SyntaxError> {
+    let mut doc = Document::default();
+    doc.tables.insert(String::new(), Table::default());
+    let mut current = Current::Table(String::new());
+
+    let mut lines = text.lines().enumerate().map(|(i, x)| (i + 1, x));
+    while let Some((line, raw)) = lines.next() {
+        let content = strip_comment(raw).trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = content.strip_prefix("[[") {
+            let name = match header.strip_suffix("]]") {
+                Some(name) => table_name(name, line)?,
+                None => return syntax_error(line, "missing ]] after table name".to_string()),
+            };
+            if doc.tables.contains_key(&name) {
+                return syntax_error(line, format!("[{}] is already a table", name));
+            }
+            doc.arrays.entry(name.clone()).or_default().push(Table {
+                line,
+                entries: BTreeMap::new(),
+            });
+            current = Current::Array(name);
+            continue;
+        }
+        if let Some(header) = content.strip_prefix('[') {
+            let name = match header.strip_suffix(']') {
+                Some(name) => table_name(name, line)?,
+                None => return syntax_error(line, "missing ] after table name".to_string()),
+            };
+            if doc.tables.contains_key(&name) || doc.arrays.contains_key(&name) {
+                return syntax_error(line, format!("[{}] is defined more than once", name));
+            }
+            doc.tables.insert(
+                name.clone(),
+                Table {
+                    line,
+                    entries: BTreeMap::new(),
+                },
+            );
+            current = Current::Table(name);
+            continue;
+        }
+
+        let eq = match content.find('=') {
+            Some(eq) => eq,
+            None => {
+                return syntax_error(line, format!("expected key = value, found {:?}", content))
+            }
+        };
+        let key = bare_key(content[..eq].trim(), line)?;
+        let mut value_text = content[eq + 1..].trim().to_string();
+        // arrays may go on over several lines
+        while value_text.starts_with('[') && !brackets_closed(&value_text) {
+            match lines.next() {
+                Some((_, more)) => {
+                    value_text.push(' ');
+                    value_text.push_str(strip_comment(more).trim());
+                }
+                None => return syntax_error(line, format!("array for {} is never closed", key)),
+            }
+        }
+
+        let (value, rest) = parse_value(&value_text, line)?;
+        if !rest.trim().is_empty() {
+            return syntax_error(line, format!("unexpected {:?} after value", rest.trim()));
+        }
+        let table = match &current {
+            Current::Table(name) => doc.tables.get_mut(name),
+            Current::Array(name) => doc.arrays.get_mut(name).and_then(|x| x.last_mut()),
+        }
+        .expect("current table exists");
+        if table.entries.contains_key(&key) {
+            return syntax_error(line, format!("{} is set more than once", key));
+        }
+        table.entries.insert(key, Entry { value, line });
+    }
+    Ok(doc)
+}
+
+fn table_name(name: &str, line: usize) -> Result<String, SyntaxError> {
+    let parts: Vec<String> = name
+        .split('.')
+        .map(|x| bare_key(x.trim(), line))
+        .collect::<Result<_, _>>()?;
+    Ok(parts.join("."))
+}
+
+fn bare_key(key: &str, line: usize) -> Result<String, SyntaxError> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(key.to_string())
+    } else {
+        syntax_error(line, format!("invalid key {:?}", key))
+    }
+}
+
+/// the line up to a `#` which isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn brackets_closed(text: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' if !in_string => depth += 1,
+            ']' if !in_string => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+/// parses the value at the start of `text`, returning what's left after it
+fn parse_value(text: &str, line: usize) -> Result<(Value, &str), SyntaxError> {
+    let text = text.trim_start();
+    if let Some(rest) = text.strip_prefix('"') {
+        return parse_string(rest, line);
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest, line)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return syntax_error(line, "expected , or ] in array".to_string());
+            }
+        }
+    }
+
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    match word {
+        "true" => Ok((Value::Boolean(true), rest)),
+        "false" => Ok((Value::Boolean(false), rest)),
+        "" => syntax_error(line, "missing value".to_string()),
+        _ => match word.replace('_', "").parse::<i64>() {
+            Ok(n) => Ok((Value::Integer(n), rest)),
+            Err(_) => syntax_error(
+                line,
+                format!("invalid value {:?}, strings need double quotes", word),
+            ),
+        },
+    }
+}
+
+/// basic string after its opening quote
+fn parse_string(text: &str, line: usize) -> Result<(Value, &str), SyntaxError> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Value::String(value), &text[i + 1..])),
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'r')) => '\r',
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, c)) => return syntax_error(line, format!("unknown escape \\{}", c)),
+                    None => break,
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+    syntax_error(line, "string is never closed".to_string())
+}
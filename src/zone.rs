@@ -0,0 +1,886 @@
+use super::buffer::{canonical_name, BytePacketBuffer, Result};
+use super::packet::{
+    is_absolute, is_subdomain, labels, normalize_name, parent_name, QueryType, Record, ResponseCode,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    fmt, fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+/// how deep $INCLUDE files may include other files
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// longest CNAME chain followed within a zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// the records of a zone loaded from a master file, keyed by owner
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    /// lowercase with a trailing dot, "" for the root
+    pub origin: String,
+    records: BTreeMap<String, Vec<Record>>,
+    /// every owner along with the names between it and the origin, so empty
+    /// non-terminals exist as well
+    nodes: HashSet<String>,
+}
+
+/// what a zone has to say about a question
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lookup {
+    pub rcode: ResponseCode,
+    /// unset for referrals, where the answer belongs to another zone
+    pub authoritative: bool,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Zone {
+    /// reads a master file (RFC 1035 section 5) for the zone `origin`.
+    /// $INCLUDE paths are relative to the file including them.
+    pub fn load(path: &Path, origin: &str) -> Result<Zone> {
+        let mut parser = Parser::new(origin);
+        parser.read_file(path, 0)?;
+        Zone::new(origin, parser.records).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// same as load, for a file which is already read. $INCLUDE paths are
+    /// relative to the working directory.
+    pub fn parse(text: &str, origin: &str) -> Result<Zone> {
+        Zone::new(origin, parse_records(text, origin)?)
+    }
+
+    /// checks the records make up a zone: one SOA at the origin, nothing
+    /// outside of it, and no other data next to a CNAME
+    pub fn new(origin: &str, records: Vec<Record>) -> Result<Zone> {
+        let origin = zone_name(origin);
+        let mut zone = Zone {
+            origin: origin.clone(),
+            records: BTreeMap::new(),
+            nodes: HashSet::new(),
+        };
+        for record in records.into_iter() {
+            let owner = normalize_name(record.name());
+            if !is_subdomain(&owner, &origin) {
+                return Err(format!("{} is outside of the zone", display(&owner)).into());
+            }
+            let rrset = zone.records.entry(owner.clone()).or_default();
+            if !rrset.contains(&record) {
+                rrset.push(record);
+            }
+
+            let mut node = Some(owner.as_str());
+            while let Some(name) = node {
+                if !zone.nodes.insert(name.to_string()) || name == origin {
+                    break;
+                }
+                node = parent_name(name);
+            }
+        }
+
+        for (owner, rrset) in zone.records.iter() {
+            let soa_count = rrset.iter().filter(|x| is_type(x, QueryType::SOA)).count();
+            if soa_count > 0 && *owner != origin {
+                return Err(
+                    format!("SOA record for {}, not the zone itself", display(owner)).into(),
+                );
+            }
+            if soa_count > 1 {
+                return Err("more than one SOA record".into());
+            }
+            let cnames = rrset
+                .iter()
+                .filter(|x| is_type(x, QueryType::CNAME))
+                .count();
+            if cnames > 0 && rrset.len() > 1 {
+                return Err(
+                    format!("{} has a CNAME along with other records", display(owner)).into(),
+                );
+            }
+        }
+        if zone.soa().is_none() {
+            return Err(format!("no SOA record for {}", display(&origin)).into());
+        }
+        Ok(zone)
+    }
+
+    /// writes the zone out as a master file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    pub fn soa(&self) -> Option<&Record> {
+        self.records
+            .get(&self.origin)?
+            .iter()
+            .find(|x| is_type(x, QueryType::SOA))
+    }
+
+    /// every record, the SOA first and the rest by owner, with the labels
+    /// compared from the right so names follow their parents
+    pub fn records(&self) -> Vec<&Record> {
+        let mut owners: Vec<&String> = self.records.keys().collect();
+        owners.sort_by_key(|x| labels(x).into_iter().rev().collect::<Vec<&str>>());
+        let mut records: Vec<&Record> = self.soa().into_iter().collect();
+        records.extend(
+            owners
+                .into_iter()
+                .flat_map(|x| self.records[x].iter())
+                .filter(|x| !is_type(x, QueryType::SOA)),
+        );
+        records
+    }
+
+    /// answers `qtype` questions for `name` (RFC 1034 section 4.3.2),
+    /// following CNAMEs as long as they stay in the zone
+    pub fn lookup(&self, name: &str, qtype: QueryType) -> Lookup {
+        let mut result = Lookup {
+            rcode: ResponseCode::no_error,
+            authoritative: true,
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        };
+        let mut name = normalize_name(name);
+        let mut seen = HashSet::new();
+
+        while is_subdomain(&name, &self.origin) && seen.insert(name.clone()) {
+            if let Some(cut) = self.delegation(&name) {
+                // the name belongs to a child zone, all we know is who serves it
+                result.authoritative = !result.answers.is_empty();
+                result.authority = cut
+                    .iter()
+                    .filter(|x| is_type(x, QueryType::NS))
+                    .cloned()
+                    .collect();
+                result.additional = self.addresses(&result.authority);
+                return result;
+            }
+
+            let records = match self.records.get(&name) {
+                Some(records) => records.clone(),
+                None if self.nodes.contains(&name) => vec![],
+                None => match self.wildcard(&name) {
+                    Some(records) => records,
+                    None => {
+                        result.rcode = ResponseCode::nx_domain;
+                        result.authority = self.negative_soa();
+                        return result;
+                    }
+                },
+            };
+
+            let found: Vec<Record> = records
+                .iter()
+                .filter(|x| x.to_num() == qtype.to_num() || qtype.to_num() == 255)
+                .cloned()
+                .collect();
+            if !found.is_empty() {
+                result.additional = self.addresses(&found);
+                result.answers.extend(found);
+                return result;
+            }
+            match records.iter().find(|x| is_type(x, QueryType::CNAME)) {
+                Some(cname @ Record::CNAME { host, .. })
+                    if result.answers.len() < MAX_CNAME_CHAIN =>
+                {
+                    result.answers.push(cname.clone());
+                    name = normalize_name(host);
+                }
+                Some(_) => return result,
+                None => {
+                    result.authority = self.negative_soa();
+                    return result;
+                }
+            }
+        }
+        // the chain left the zone, or went round in circles
+        result
+    }
+
+    /// NS records of the delegation `name` falls under, if any
+    fn delegation(&self, name: &str) -> Option<&Vec<Record>> {
+        let mut ancestors = vec![];
+        let mut node = Some(name);
+        while let Some(current) = node {
+            if current == self.origin {
+                break;
+            }
+            ancestors.push(current);
+            node = parent_name(current);
+        }
+        // the cut closest to the origin hides everything below it
+        ancestors.iter().rev().find_map(|x| {
+            self.records
+                .get(*x)
+                .filter(|records| records.iter().any(|r| is_type(r, QueryType::NS)))
+        })
+    }
+
+    /// records of the wildcard covering `name`, which doesn't exist, with the
+    /// owner changed to `name` (RFC 4592)
+    fn wildcard(&self, name: &str) -> Option<Vec<Record>> {
+        let mut encloser = parent_name(name)?;
+        while !self.nodes.contains(encloser) {
+            encloser = parent_name(encloser)?;
+        }
+        let wildcard = if encloser.is_empty() {
+            "*.".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+        let records = self.records.get(&wildcard)?;
+        Some(records.iter().map(|x| with_name(x, name)).collect())
+    }
+
+    /// the SOA for the authority section of negative answers, with its ttl
+    /// capped at the minimum field (RFC 2308 section 3)
+    fn negative_soa(&self) -> Vec<Record> {
+        match self.soa() {
+            Some(soa @ Record::SOA { ttl, minimum, .. }) => {
+                let mut soa = soa.clone();
+                soa.set_ttl((*ttl).min(*minimum));
+                vec![soa]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// A and AAAA records in the zone for the hosts `records` point at, e.g.
+    /// the glue of a delegation
+    fn addresses(&self, records: &[Record]) -> Vec<Record> {
+        let mut addresses = vec![];
+        for record in records.iter() {
+            let host = match record {
+                Record::NS { host, .. } | Record::MX { host, .. } => host,
+                Record::SRV { target, .. } => target,
+                _ => continue,
+            };
+            if let Some(rrset) = self.records.get(&normalize_name(host)) {
+                addresses.extend(
+                    rrset
+                        .iter()
+                        .filter(|x| matches!(x, Record::A { .. } | Record::AAAA { .. }))
+                        .filter(|x| !addresses.contains(*x))
+                        .cloned()
+                        .collect::<Vec<Record>>(),
+                );
+            }
+        }
+        addresses
+    }
+}
+
+/// the zone as a master file, which parses back into the same zone
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "$ORIGIN {}", display(&self.origin))?;
+        for record in self.records() {
+            writeln!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+/// zones served locally, looked up by the most specific one holding a name
+#[derive(Debug, Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn insert(&mut self, zone: Zone) {
+        self.zones.retain(|x| x.origin != zone.origin);
+        self.zones.push(zone);
+        // most specific origin first
+        self.zones
+            .sort_by_key(|x| std::cmp::Reverse(labels(&x.origin).len()));
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        self.zones.iter().find(|x| is_subdomain(name, &x.origin))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+}
+
+fn is_type(record: &Record, qtype: QueryType) -> bool {
+    record.to_num() == qtype.to_num()
+}
+
+fn zone_name(name: &str) -> String {
+    match normalize_name(name).as_str() {
+        "." => String::new(),
+        name => name.to_string(),
+    }
+}
+
+fn display(name: &str) -> &str {
+    if name.is_empty() {
+        "."
+    } else {
+        name
+    }
+}
+
+fn with_name(record: &Record, new_name: &str) -> Record {
+    let mut record = record.clone();
+    match &mut record {
+        Record::A { name, .. }
+        | Record::NS { name, .. }
+        | Record::CNAME { name, .. }
+        | Record::SOA { name, .. }
+        | Record::PTR { name, .. }
+        | Record::MX { name, .. }
+        | Record::TXT { name, .. }
+        | Record::AAAA { name, .. }
+        | Record::SRV { name, .. }
+        | Record::UNKNOWN { name, .. } => *name = new_name.to_string(),
+        Record::OPT { .. } => {}
+    }
+    record
+}
+
+/// the tokens of one record or directive, which parentheses may spread over
+/// several lines
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// starts with blanks, so the owner of the previous record carries over
+    blank_owner: bool,
+    /// words with quotes removed but escapes kept as they are
+    tokens: Vec<String>,
+}
+
+/// error message along with the line it's about
+type LineResult<T> = std::result::Result<T, (usize, String)>;
+
+fn entries(text: &str) -> LineResult<Vec<Entry>> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut line = 1;
+    let mut depth = 0;
+    let mut line_start = true;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if line_start && depth == 0 {
+            entries.push(Entry {
+                line,
+                blank_owner: c == ' ' || c == '\t',
+                tokens: vec![],
+            });
+        }
+        line_start = false;
+        let entry = entries.last_mut().expect("an entry was started");
+
+        match c {
+            '\n' => {
+                line += 1;
+                line_start = true;
+            }
+            ' ' | '\t' | '\r' => {}
+            ';' => while chars.next_if(|x| *x != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err((line, "unexpected )".to_string())),
+            ')' => depth -= 1,
+            '"' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            if let Some(x) = chars.next() {
+                                text.push(x);
+                            }
+                        }
+                        Some(x) => {
+                            if x == '\n' {
+                                line += 1;
+                            }
+                            text.push(x);
+                        }
+                        None => return Err((start, "string is never closed".to_string())),
+                    }
+                }
+                entry.tokens.push(text);
+            }
+            _ => {
+                let mut text = c.to_string();
+                if c == '\\' {
+                    text.extend(chars.next_if(|x| *x != '\n'));
+                }
+                while let Some(x) =
+                    chars.next_if(|x| !x.is_whitespace() && !matches!(x, ';' | '(' | ')' | '"'))
+                {
+                    text.push(x);
+                    if x == '\\' {
+                        text.extend(chars.next_if(|x| *x != '\n'));
+                    }
+                }
+                entry.tokens.push(text);
+            }
+        }
+    }
+
+    if depth > 0 {
+        let start = entries.last().map_or(line, |x| x.line);
+        return Err((start, "( is never closed".to_string()));
+    }
+    entries.retain(|x| !x.tokens.is_empty());
+    Ok(entries)
+}
+
+/// keeps the state master files carry from one line to the next
+struct Parser {
+    origin: String,
+    /// set by $TTL
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    records: Vec<Record>,
+}
+
+impl Parser {
+    fn new(origin: &str) -> Parser {
+        Parser {
+            origin: zone_name(origin),
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+            records: vec![],
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.read(&text, dir, depth).map_err(|e| match e {
+            Error::Line(line, message) => format!("{}:{}: {}", path.display(), line, message),
+            Error::Include(message) => message,
+        })?;
+        Ok(())
+    }
+
+    fn read(&mut self, text: &str, dir: &Path, depth: usize) -> std::result::Result<(), Error> {
+        for entry in entries(text).map_err(|(line, message)| Error::Line(line, message))? {
+            if entry.tokens[0].starts_with('$') && !entry.blank_owner {
+                self.directive(&entry.tokens, entry.line, dir, depth)?;
+            } else {
+                self.record(&entry)
+                    .map_err(|message| Error::Line(entry.line, message))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn directive(
+        &mut self,
+        tokens: &[String],
+        line: usize,
+        dir: &Path,
+        depth: usize,
+    ) -> std::result::Result<(), Error> {
+        let error = |message: String| Error::Line(line, message);
+        let args: Vec<&str> = tokens[1..].iter().map(String::as_str).collect();
+        match (tokens[0].to_ascii_uppercase().as_str(), args.as_slice()) {
+            ("$ORIGIN", [origin]) => {
+                self.origin = normalize_name(&absolute_name(origin, &self.origin).map_err(error)?);
+                Ok(())
+            }
+            ("$TTL", [ttl]) => {
+                self.default_ttl = Some(parse_ttl(ttl).map_err(error)?);
+                Ok(())
+            }
+            ("$INCLUDE", [file, rest @ ..]) if rest.len() <= 1 => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(error(format!(
+                        "$INCLUDE nested more than {} deep",
+                        MAX_INCLUDE_DEPTH
+                    )));
+                }
+                let path: PathBuf = dir.join(file);
+                // the included file may have its own origin, which doesn't
+                // carry over to the rest of this one
+                let origin = self.origin.clone();
+                let last_owner = self.last_owner.clone();
+                if let Some(new_origin) = rest.first() {
+                    self.origin =
+                        normalize_name(&absolute_name(new_origin, &origin).map_err(error)?);
+                }
+                let result = self.read_file(&path, depth + 1);
+                self.origin = origin;
+                self.last_owner = last_owner;
+                result.map_err(|e| Error::Include(e.to_string()))
+            }
+            ("$ORIGIN", _) | ("$TTL", _) | ("$INCLUDE", _) => Err(error(format!(
+                "wrong number of arguments for {}",
+                tokens[0]
+            ))),
+            (name, _) => Err(error(format!("unknown directive {}", name))),
+        }
+    }
+
+    fn record(&mut self, entry: &Entry) -> std::result::Result<(), String> {
+        let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or("no owner name, and no record before it to take it from")?
+        } else {
+            let owner = tokens.next().expect("entries have tokens");
+            absolute_name(owner, &self.origin)?
+        };
+
+        // ttl and class may come in either order, both are optional
+        let mut ttl = None;
+        let mut class = None;
+        for _ in 0..2 {
+            match tokens.peek() {
+                Some(x) if ttl.is_none() && x.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(parse_ttl(x)?);
+                }
+                Some(x) if class.is_none() && parse_class(x).is_some() => class = parse_class(x),
+                _ => break,
+            }
+            tokens.next();
+        }
+        let rtype = tokens.next().ok_or("missing record type")?;
+        let rtype = parse_type(rtype).ok_or_else(|| format!("unknown record type {}", rtype))?;
+        let rdata: Vec<&str> = tokens.collect();
+
+        let mut record = parse_rdata(&owner, class.unwrap_or(1), 0, rtype, &rdata, &self.origin)?;
+        let ttl = match (ttl, self.default_ttl, self.last_ttl, &record) {
+            (Some(ttl), ..) => {
+                self.last_ttl = Some(ttl);
+                ttl
+            }
+            (None, Some(ttl), ..) | (None, None, Some(ttl), _) => ttl,
+            (None, None, None, Record::SOA { minimum, .. }) => *minimum,
+            _ => return Err("no ttl, and no $TTL before it".to_string()),
+        };
+        record.set_ttl(ttl);
+        self.last_owner = Some(owner);
+        self.records.push(record);
+        Ok(())
+    }
+}
+
+enum Error {
+    Line(usize, String),
+    /// already says which file and line it's about
+    Include(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Line(line, message) => write!(f, "line {}: {}", line, message),
+            Error::Include(message) => f.write_str(message),
+        }
+    }
+}
+
+/// the records of a master file, without checking they make up a zone, for
+/// files like root hints which have no SOA
+pub fn parse_records(text: &str, origin: &str) -> Result<Vec<Record>> {
+    let mut parser = Parser::new(origin);
+    parser
+        .read(text, Path::new(""), 0)
+        .map_err(|e| e.to_string())?;
+    Ok(parser.records)
+}
+
+/// a single record in presentation format, relative names being under
+/// `origin`. A missing ttl means 0.
+pub fn parse_record(text: &str, origin: &str) -> std::result::Result<Record, String> {
+    let entries = entries(text).map_err(|(_, message)| message)?;
+    let entry = match entries.as_slice() {
+        [entry] => entry,
+        [] => return Err("no record".to_string()),
+        _ => return Err("more than one record".to_string()),
+    };
+    if entry.blank_owner {
+        return Err("missing owner name".to_string());
+    }
+    if entry.tokens[0].starts_with('$') {
+        return Err(format!("{} is a directive, not a record", entry.tokens[0]));
+    }
+    let mut parser = Parser::new(origin);
+    parser.default_ttl = Some(0);
+    parser.record(entry)?;
+    Ok(parser.records.remove(0))
+}
+
+/// `name` as an absolute name, relative ones being under `origin`, with its
+/// escapes written the same way as names read off the wire
+pub fn absolute_name(name: &str, origin: &str) -> std::result::Result<String, String> {
+    let name = if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        String::new()
+    } else if is_absolute(name) {
+        name.to_string()
+    } else if origin.is_empty() {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}", name, origin)
+    };
+    canonical_name(&name).map_err(|e| format!("invalid name {:?}: {}", name, e))
+}
+
+/// seconds, or a BIND style duration such as `1h30m` or `2w`
+pub fn parse_ttl(text: &str) -> std::result::Result<u32, String> {
+    let error = || format!("invalid ttl {:?}", text);
+    if let Ok(ttl) = text.parse::<u32>() {
+        return Ok(ttl);
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit: u64 = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(error()),
+        };
+        let value: u64 = number.parse().map_err(|_| error())?;
+        total += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(error());
+    }
+    u32::try_from(total).map_err(|_| error())
+}
+
+pub fn parse_class(text: &str) -> Option<u16> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(1),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        "ANY" => Some(255),
+        x => x.strip_prefix("CLASS")?.parse().ok(),
+    }
+}
+
+pub fn parse_type(text: &str) -> Option<QueryType> {
+    let qtype = match text.to_ascii_uppercase().as_str() {
+        "A" => QueryType::A,
+        "NS" => QueryType::NS,
+        "CNAME" => QueryType::CNAME,
+        "SOA" => QueryType::SOA,
+        "PTR" => QueryType::PTR,
+        "MX" => QueryType::MX,
+        "TXT" => QueryType::TXT,
+        "AAAA" => QueryType::AAAA,
+        "SRV" => QueryType::SRV,
+        x => QueryType::from_num(x.strip_prefix("TYPE")?.parse().ok()?),
+    };
+    Some(qtype)
+}
+
+/// builds a record from the fields after its type
+fn parse_rdata(
+    name: &str,
+    class: u16,
+    ttl: u32,
+    rtype: QueryType,
+    fields: &[&str],
+    origin: &str,
+) -> std::result::Result<Record, String> {
+    let name = name.to_string();
+    let host = |x: &str| absolute_name(x, origin);
+    let number = |x: &str| {
+        x.parse::<u16>()
+            .map_err(|_| format!("invalid number {:?}", x))
+    };
+
+    if let ["\\#", length, hex @ ..] = fields {
+        // RFC 3597 generic rdata, which any type may use
+        let data = generic_rdata(length, hex)?;
+        return match rtype {
+            QueryType::UNKNOWN(rtype) => Ok(Record::UNKNOWN {
+                name,
+                rtype,
+                class,
+                ttl,
+                data,
+            }),
+            _ => known_rdata(&name, class, ttl, rtype, &data),
+        };
+    }
+
+    let record = match (rtype, fields) {
+        (QueryType::A, [ip]) => Record::A {
+            name,
+            class,
+            ttl,
+            ip: ip
+                .parse::<Ipv4Addr>()
+                .map_err(|_| format!("invalid ipv4 address {:?}", ip))?
+                .octets(),
+        },
+        (QueryType::AAAA, [ip]) => Record::AAAA {
+            name,
+            class,
+            ttl,
+            ip: ip
+                .parse::<Ipv6Addr>()
+                .map_err(|_| format!("invalid ipv6 address {:?}", ip))?,
+        },
+        (QueryType::NS, [x]) => Record::NS {
+            name,
+            class,
+            ttl,
+            host: host(x)?,
+        },
+        (QueryType::CNAME, [x]) => Record::CNAME {
+            name,
+            class,
+            ttl,
+            host: host(x)?,
+        },
+        (QueryType::PTR, [x]) => Record::PTR {
+            name,
+            class,
+            ttl,
+            host: host(x)?,
+        },
+        (QueryType::MX, [priority, x]) => Record::MX {
+            name,
+            class,
+            ttl,
+            priority: number(priority)?,
+            host: host(x)?,
+        },
+        (QueryType::SRV, [priority, weight, port, target]) => Record::SRV {
+            name,
+            class,
+            ttl,
+            priority: number(priority)?,
+            weight: number(weight)?,
+            port: number(port)?,
+            target: host(target)?,
+        },
+        (QueryType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => Record::SOA {
+            name,
+            class,
+            ttl,
+            mname: host(mname)?,
+            rname: host(rname)?,
+            serial: serial
+                .parse()
+                .map_err(|_| format!("invalid serial {:?}", serial))?,
+            refresh: parse_ttl(refresh)?,
+            retry: parse_ttl(retry)?,
+            expire: parse_ttl(expire)?,
+            minimum: parse_ttl(minimum)?,
+        },
+        (QueryType::TXT, [_, ..]) => Record::TXT {
+            name,
+            class,
+            ttl,
+            data: fields
+                .iter()
+                .map(|x| character_string(x))
+                .collect::<std::result::Result<_, _>>()?,
+        },
+        (QueryType::UNKNOWN(_), _) => {
+            return Err(format!("{} records need generic \\# rdata", rtype))
+        }
+        _ => return Err(format!("wrong number of fields for a {} record", rtype)),
+    };
+    Ok(record)
+}
+
+/// the bytes of `\# length hex...`, checked against the length
+fn generic_rdata(length: &str, hex: &[&str]) -> std::result::Result<Vec<u8>, String> {
+    let length: usize = length
+        .parse()
+        .map_err(|_| format!("invalid rdata length {:?}", length))?;
+    let data = parse_hex(&hex.concat())?;
+    if data.len() != length {
+        return Err(format!(
+            "rdata is {} bytes long, not {}",
+            data.len(),
+            length
+        ));
+    }
+    Ok(data)
+}
+
+/// a record of a type we know, from rdata given in the generic form. It goes
+/// through the wire format reader, so it ends up the same as if it had been
+/// written out in full.
+fn known_rdata(
+    name: &str,
+    class: u16,
+    ttl: u32,
+    rtype: QueryType,
+    data: &[u8],
+) -> std::result::Result<Record, String> {
+    // the root as owner, the real one is put in afterwards
+    let mut wire = vec![0];
+    wire.extend_from_slice(&rtype.to_num().to_be_bytes());
+    wire.extend_from_slice(&class.to_be_bytes());
+    wire.extend_from_slice(&ttl.to_be_bytes());
+    wire.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    wire.extend_from_slice(data);
+    // offsets in the reader's errors would point into our made up message
+    let record = Record::read(&mut BytePacketBuffer::from_bytes(&wire))
+        .map_err(|_| format!("invalid {} rdata", rtype))?;
+    Ok(with_name(&record, name))
+}
+
+/// a character-string with its \X and \DDD escapes resolved
+fn character_string(text: &str) -> std::result::Result<Vec<u8>, String> {
+    let mut data = vec![];
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            data.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let digits = &bytes[i + 1..bytes.len().min(i + 4)];
+        if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+            let value: u16 = std::str::from_utf8(digits).unwrap().parse().unwrap();
+            data.push(u8::try_from(value).map_err(|_| format!("invalid escape \\{}", value))?);
+            i += 4;
+        } else if let Some(x) = bytes.get(i + 1) {
+            data.push(*x);
+            i += 2;
+        } else {
+            return Err("string ends with a lone \\".to_string());
+        }
+    }
+    if data.len() > 255 {
+        return Err(format!("string of {} bytes, the limit is 255", data.len()));
+    }
+    Ok(data)
+}
+
+pub(crate) fn parse_hex(text: &str) -> std::result::Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex {:?}", text));
+    }
+    Ok((0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect())
+}
@@ -0,0 +1,131 @@
+use druns::cache::{Cache, Negative};
+use druns::packet::{QueryType, Record};
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+fn a(name: &str, ttl: u32, ip: [u8; 4]) -> Record {
+    Record::A {
+        name: name.to_string(),
+        class: 1,
+        ttl,
+        ip,
+    }
+}
+
+fn ns(zone: &str, host: &str) -> Record {
+    Record::NS {
+        name: zone.to_string(),
+        class: 1,
+        ttl: 3600,
+        host: host.to_string(),
+    }
+}
+
+#[test]
+fn test_insert_and_get() {
+    let mut cache = Cache::new(10);
+    cache.insert(&[
+        a("example.com.", 300, [1, 2, 3, 4]),
+        a("example.com.", 300, [1, 2, 3, 5]),
+        ns("example.com.", "ns1.example.com."),
+    ]);
+    assert_eq!(cache.len(), 2);
+
+    // lookups ignore case and the trailing dot
+    let records = cache.get("EXAMPLE.com", QueryType::A, 1).unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(cache.get("example.com.", QueryType::MX, 1).is_none());
+    assert!(cache.get("example.com.", QueryType::A, 3).is_none());
+}
+
+#[test]
+fn test_ttl_counts_down() {
+    let mut cache = Cache::new(10);
+    cache.insert(&[a("short.example.", 1, [1, 1, 1, 1])]);
+    cache.insert(&[a("long.example.", 300, [2, 2, 2, 2])]);
+    cache.insert(&[a("zero.example.", 0, [3, 3, 3, 3])]);
+    assert!(cache.get("zero.example.", QueryType::A, 1).is_none());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(cache.get("short.example.", QueryType::A, 1).is_none());
+    let records = cache.get("long.example.", QueryType::A, 1).unwrap();
+    assert_eq!(records[0].ttl(), 299);
+}
+
+#[test]
+fn test_lru_eviction() {
+    let mut cache = Cache::new(2);
+    cache.insert(&[a("one.example.", 300, [1, 1, 1, 1])]);
+    cache.insert(&[a("two.example.", 300, [2, 2, 2, 2])]);
+    // one becomes the most recently used
+    assert!(cache.get("one.example.", QueryType::A, 1).is_some());
+    cache.insert(&[a("three.example.", 300, [3, 3, 3, 3])]);
+
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get("two.example.", QueryType::A, 1).is_none());
+    assert!(cache.get("one.example.", QueryType::A, 1).is_some());
+    assert!(cache.get("three.example.", QueryType::A, 1).is_some());
+}
+
+#[test]
+fn test_closest_delegation() {
+    let mut cache = Cache::new(10);
+    cache.insert(&[
+        ns("com.", "a.gtld.net."),
+        a("a.gtld.net.", 300, [192, 5, 6, 30]),
+    ]);
+    // NS without any cached address doesn't count
+    cache.insert(&[ns("example.com.", "ns1.example.net.")]);
+
+    let (zone, servers) = cache.closest_delegation("www.example.com.").unwrap();
+    assert_eq!(zone, "com.");
+    assert_eq!(servers, vec![Ipv4Addr::new(192, 5, 6, 30)]);
+    assert!(cache.closest_delegation("example.org.").is_none());
+}
+
+#[test]
+fn test_negative_entries() {
+    let mut cache = Cache::new(10);
+    let soa = Record::SOA {
+        name: "example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        mname: "ns1.example.com.".to_string(),
+        rname: "hostmaster.example.com.".to_string(),
+        serial: 1,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum: 300,
+    };
+    cache.insert_negative(
+        "nope.example.com.",
+        QueryType::A,
+        1,
+        Negative::NxDomain,
+        &soa,
+    );
+    cache.insert_negative("www.example.com.", QueryType::MX, 1, Negative::NoData, &soa);
+
+    let (negative, records) = cache
+        .get_negative("nope.example.com.", QueryType::TXT, 1)
+        .unwrap();
+    assert_eq!(negative, Negative::NxDomain);
+    assert_eq!(records[0].ttl(), 300);
+    assert!(cache.get("nope.example.com.", QueryType::A, 1).is_none());
+
+    let (negative, _) = cache
+        .get_negative("www.example.com.", QueryType::MX, 1)
+        .unwrap();
+    assert_eq!(negative, Negative::NoData);
+    assert!(cache
+        .get_negative("www.example.com.", QueryType::A, 1)
+        .is_none());
+
+    // learning that the name exists drops the NXDOMAIN
+    cache.insert(&[a("nope.example.com.", 300, [1, 1, 1, 1])]);
+    assert!(cache
+        .get_negative("nope.example.com.", QueryType::TXT, 1)
+        .is_none());
+}
@@ -0,0 +1,107 @@
+use druns::cli::{parse_args, Command, Options, UsageError};
+use druns::config::Mode;
+use druns::log::Level;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+fn options(args: &[&str]) -> Options {
+    match parse_args(args.iter().copied()) {
+        Ok(Command::Run(options)) => options,
+        x => panic!("unexpected result {:?}", x),
+    }
+}
+
+fn error(args: &[&str]) -> String {
+    match parse_args(args.iter().copied()) {
+        Err(UsageError(message)) => message,
+        x => panic!("expected an error, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_defaults() {
+    // anything left out is up to the config file
+    assert_eq!(options(&[]), Options::default());
+    assert_eq!(Options::default().port, None);
+}
+
+#[test]
+fn test_all_options() {
+    let options = options(&[
+        "--listen",
+        "127.0.0.1",
+        "-p",
+        "5353",
+        "--cache-size=500",
+        "--root-hints",
+        "named.root",
+        "--log-level",
+        "info",
+        "-c",
+        "druns.toml",
+    ]);
+    assert_eq!(options.listen, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    assert_eq!(options.port, Some(5353));
+    assert_eq!(options.cache_size, Some(500));
+    assert_eq!(options.root_hints, Some(PathBuf::from("named.root")));
+    assert_eq!(options.log_level, Some(Level::Info));
+    assert_eq!(options.config, Some(PathBuf::from("druns.toml")));
+}
+
+#[test]
+fn test_forwarder_upstreams() {
+    let options = options(&[
+        "--mode",
+        "forwarder",
+        "-u",
+        "10.0.0.1",
+        "--upstream=[::1]:5353",
+    ]);
+    assert_eq!(options.mode, Some(Mode::Forwarder));
+    assert_eq!(
+        options.upstreams,
+        vec![
+            "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            "[::1]:5353".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_verbosity() {
+    assert_eq!(options(&["-v"]).log_level, Some(Level::Info));
+    assert_eq!(options(&["-vv"]).log_level, Some(Level::Debug));
+    assert_eq!(options(&["-v", "--verbose"]).log_level, Some(Level::Debug));
+    assert_eq!(options(&["-q"]).log_level, Some(Level::Error));
+    assert_eq!(options(&["-q", "-v"]).log_level, Some(Level::Warn));
+}
+
+#[test]
+fn test_help_and_version() {
+    assert_eq!(parse_args(vec!["-h"]), Ok(Command::Help));
+    // nothing after --help is looked at
+    assert_eq!(parse_args(vec!["--help", "--port", "0"]), Ok(Command::Help));
+    assert_eq!(parse_args(vec!["-V"]), Ok(Command::Version));
+}
+
+#[test]
+fn test_invalid_values() {
+    assert_eq!(
+        error(&["--port", "0"]),
+        "invalid port \"0\", expected a number from 1 to 65535"
+    );
+    assert!(error(&["--port", "70000"]).starts_with("invalid port"));
+    assert!(error(&["--listen", "localhost"]).starts_with("invalid listen address"));
+    assert!(error(&["--cache-size", "0"]).starts_with("invalid cache size"));
+    assert!(error(&["--mode", "stub"]).starts_with("unknown mode"));
+    assert!(error(&["--log-level", "loud"]).starts_with("unknown log level"));
+    assert!(error(&["-m", "forwarder", "-u", "10.0.0.1:0"]).starts_with("invalid upstream"));
+}
+
+#[test]
+fn test_usage_errors() {
+    assert_eq!(error(&["--port"]), "--port needs a value");
+    assert_eq!(error(&["--bogus"]), "unknown option --bogus");
+    assert_eq!(error(&["extra"]), "unexpected argument \"extra\"");
+    assert_eq!(error(&["--quiet=yes"]), "--quiet doesn't take a value");
+}
@@ -0,0 +1,218 @@
+// helpers shared by the resolver tests: mock upstream servers listening on
+// loopback addresses, and builders for the packets they send back
+#![allow(dead_code)]
+
+use druns::buffer::BytePacketBuffer;
+use druns::lookup::{create_request_packet, process_query, Protocol, Resolver};
+use druns::packet::{Packet, PacketType, QueryType, Record, ResponseCode};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+pub type Handler = Box<dyn Fn(&Packet) -> Option<Packet> + Send>;
+
+/// port which is free on 127.0.0.1, every mock server of a test listens on it
+pub fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// answers every query it receives with `handler`, which may also drop the
+/// query by returning None. Returns a counter of the queries received.
+pub fn spawn_server(ip: &str, port: u16, handler: Handler) -> Arc<AtomicUsize> {
+    let socket = UdpSocket::bind((ip, port)).unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::with_max_size(4096);
+        let (size, src) = match socket.recv_from(&mut buffer) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        buffer.size = size;
+        counter.fetch_add(1, Ordering::SeqCst);
+        let request = Packet::read(&mut buffer).unwrap();
+        if let Some(response) = handler(&request) {
+            let mut response_buf = BytePacketBuffer::with_max_size(4096);
+            response.write(&mut response_buf).unwrap();
+            socket
+                .send_to(&response_buf[0..response_buf.size], src)
+                .unwrap();
+        }
+    });
+    queries
+}
+
+pub fn count(counter: &Arc<AtomicUsize>) -> usize {
+    counter.load(Ordering::SeqCst)
+}
+
+/// resolver which treats 127.0.0.1 as the only root server
+pub fn resolver(port: u16) -> Resolver {
+    let mut resolver = Resolver::new(vec![Ipv4Addr::new(127, 0, 0, 1)], 1000);
+    resolver.upstream_port = port;
+    resolver
+}
+
+/// sends a query through the resolver, as if it came from a client
+pub fn query(resolver: &Resolver, name: &str, qtype: QueryType) -> Packet {
+    try_query(resolver, name, qtype)
+        .unwrap()
+        .expect("no response sent")
+}
+
+pub fn try_query(
+    resolver: &Resolver,
+    name: &str,
+    qtype: QueryType,
+) -> druns::buffer::Result<Option<Packet>> {
+    send(resolver, &create_request_packet(name, qtype))
+}
+
+/// sends `request` through the resolver as it is
+pub fn send(resolver: &Resolver, request: &Packet) -> druns::buffer::Result<Option<Packet>> {
+    let mut buffer = BytePacketBuffer::new_empty();
+    request.write(&mut buffer)?;
+    send_bytes(resolver, &buffer[0..buffer.size])
+}
+
+/// sends a raw message through the resolver, which doesn't have to parse
+pub fn send_bytes(resolver: &Resolver, bytes: &[u8]) -> druns::buffer::Result<Option<Packet>> {
+    let mut buffer = BytePacketBuffer::from_bytes(bytes);
+    match process_query(resolver, &mut buffer, Protocol::Udp)? {
+        Some(mut response) => Ok(Some(Packet::read(&mut response)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn response(request: &Packet) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.id = request.header.id;
+    packet.header.qr = PacketType::Response;
+    packet.questions = request.questions.clone();
+    packet
+}
+
+pub fn answer(request: &Packet, answers: Vec<Record>) -> Packet {
+    let mut packet = response(request);
+    packet.header.authoritative = true;
+    packet.answers = answers;
+    packet.update_counts();
+    packet
+}
+
+/// referral to `zone`, served by `host` at `ip`
+pub fn referral(request: &Packet, zone: &str, host: &str, ip: [u8; 4]) -> Packet {
+    let mut packet = response(request);
+    packet.authority = vec![ns(zone, host)];
+    packet.additional = vec![a(host, ip)];
+    packet.update_counts();
+    packet
+}
+
+pub fn a(name: &str, ip: [u8; 4]) -> Record {
+    Record::A {
+        name: name.to_string(),
+        class: 1,
+        ttl: 300,
+        ip,
+    }
+}
+
+pub fn ns(zone: &str, host: &str) -> Record {
+    Record::NS {
+        name: zone.to_string(),
+        class: 1,
+        ttl: 3600,
+        host: host.to_string(),
+    }
+}
+
+pub fn cname(name: &str, host: &str) -> Record {
+    Record::CNAME {
+        name: name.to_string(),
+        class: 1,
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+pub fn soa(zone: &str, ttl: u32, minimum: u32) -> Record {
+    Record::SOA {
+        name: zone.to_string(),
+        class: 1,
+        ttl,
+        mname: format!("ns1.{}", zone),
+        rname: format!("hostmaster.{}", zone),
+        serial: 1,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum,
+    }
+}
+
+/// NXDOMAIN, or NODATA when `rcode` is no_error, with the zone's SOA
+pub fn negative(request: &Packet, rcode: ResponseCode, zone: &str) -> Packet {
+    let mut packet = response(request);
+    packet.header.authoritative = true;
+    packet.header.rcode = rcode;
+    packet.authority = vec![soa(zone, 3600, 600)];
+    packet.update_counts();
+    packet
+}
+
+pub fn question_name(request: &Packet) -> String {
+    request.questions[0].name.clone()
+}
+
+/// root at 127.0.0.1 delegating com. to 127.0.0.2, which delegates
+/// example.com. to 127.0.0.3. Every name in example.com. has an A record.
+/// Names under net. are sent to 127.0.0.4, see spawn_net.
+pub fn spawn_hierarchy(port: u16) -> [Arc<AtomicUsize>; 3] {
+    spawn_hierarchy_with(
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [10, 0, 0, 1])]))
+        }),
+    )
+}
+
+/// same delegations as spawn_hierarchy, with `example` serving example.com.
+pub fn spawn_hierarchy_with(port: u16, example: Handler) -> [Arc<AtomicUsize>; 3] {
+    let root = spawn_server(
+        "127.0.0.1",
+        port,
+        Box::new(|request| {
+            if question_name(request).ends_with(".net.") {
+                Some(referral(request, "net.", "a.gtld.net.", [127, 0, 0, 4]))
+            } else {
+                Some(referral(request, "com.", "a.gtld.com.", [127, 0, 0, 2]))
+            }
+        }),
+    );
+    let com = spawn_server(
+        "127.0.0.2",
+        port,
+        Box::new(|request| {
+            Some(referral(
+                request,
+                "example.com.",
+                "ns1.example.com.",
+                [127, 0, 0, 3],
+            ))
+        }),
+    );
+    let example = spawn_server("127.0.0.3", port, example);
+    [root, com, example]
+}
+
+/// authoritative server for all of net., at 127.0.0.4
+pub fn spawn_net(port: u16, handler: Handler) -> Arc<AtomicUsize> {
+    spawn_server("127.0.0.4", port, handler)
+}
@@ -0,0 +1,313 @@
+use druns::cli::Options;
+use druns::config::{parse_duration, Acl, Config, Mode, Network};
+use druns::log::Level;
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+fn error(text: &str) -> String {
+    Config::from_str(text).unwrap_err().to_string()
+}
+
+#[test]
+fn test_example_config() {
+    let config = Config::from_str(include_str!("../druns.example.toml")).unwrap();
+    assert_eq!(
+        config.listen,
+        vec![
+            "0.0.0.0:34254".parse::<SocketAddr>().unwrap(),
+            "[::]:34254".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+    assert_eq!(config.mode, Mode::Recursive);
+    assert_eq!(config.timeout, Duration::from_secs(2));
+    assert_eq!(config.acl.allow.len(), 4);
+    assert_eq!(config.log_level, Level::Warn);
+}
+
+#[test]
+fn test_empty_config_is_default() {
+    assert_eq!(Config::from_str("").unwrap(), Config::default());
+    assert_eq!(
+        Config::from_str("# nothing\n\n").unwrap(),
+        Config::default()
+    );
+}
+
+#[test]
+fn test_all_sections() {
+    let config = Config::from_str(
+        r#"
+[server]
+listen = [
+    "127.0.0.1",        # default port
+    "[::1]:5353",
+]
+mode = "forwarder"
+workers = 4
+queue_size = 100
+tcp_connections = 10
+tcp_connections_per_client = 2
+
+[resolver]
+upstreams = ["10.0.0.1", "10.0.0.2:5353"]
+
+[timeouts]
+query = "500ms"
+retries = 0
+deadline = "1m"
+tcp_idle = "30s"
+
+[cache]
+size = 1_000
+
+[acl]
+allow = ["10.0.0.0/8"]
+deny = ["10.1.0.0/16"]
+
+[[zone]]
+name = "corp.example"
+file = "corp.zone"
+
+[[zone]]
+name = "lab.example."
+file = "/etc/druns/lab.zone"
+
+[log]
+level = "debug"
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.listen,
+        vec![
+            "127.0.0.1:34254".parse::<SocketAddr>().unwrap(),
+            "[::1]:5353".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+    assert_eq!(config.mode, Mode::Forwarder);
+    assert_eq!((config.workers, config.queue_size), (4, 100));
+    assert_eq!(config.tcp_connections, 10);
+    assert_eq!(config.tcp_connections_per_client, 2);
+    assert_eq!(
+        config.upstreams,
+        vec![
+            "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:5353".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+    assert_eq!(config.timeout, Duration::from_millis(500));
+    assert_eq!(config.retries, 0);
+    assert_eq!(config.deadline, Duration::from_secs(60));
+    assert_eq!(config.tcp_idle_timeout, Duration::from_secs(30));
+    assert_eq!(config.cache_size, 1000);
+    assert_eq!(config.zones.len(), 2);
+    assert_eq!(config.zones[0].name, "corp.example.");
+    assert_eq!(config.zones[1].file, PathBuf::from("/etc/druns/lab.zone"));
+    assert_eq!(config.log_level, Level::Debug);
+}
+
+#[test]
+fn test_errors_point_at_line_and_key() {
+    assert_eq!(
+        error("[cache]\nsize = 0\n"),
+        "2: cache.size: expected a number of at least 1"
+    );
+    assert_eq!(
+        error("[cache]\n\nsize = \"big\"\n"),
+        "3: cache.size: expected an integer, found a string"
+    );
+    assert_eq!(
+        error("[server]\nlisten = [\"nowhere\"]\n"),
+        "2: server.listen: invalid address \"nowhere\", expected ip or ip:port"
+    );
+    assert_eq!(
+        error("[timeouts]\nquery = \"2 seconds\"\n"),
+        "2: timeouts.query: invalid duration \"2 seconds\", expected e.g. 500ms, 2s or 1m"
+    );
+    assert_eq!(
+        error("[acl]\nallow = [\"10.0.0.0/33\"]\n"),
+        "2: acl.allow: invalid network \"10.0.0.0/33\", expected ip or ip/prefix"
+    );
+    assert_eq!(
+        error("[log]\nlevel = \"loud\"\n"),
+        "2: log.level: unknown log level \"loud\", expected one of off, error, warn, info, debug"
+    );
+}
+
+#[test]
+fn test_unknown_keys_and_tables() {
+    assert_eq!(
+        error("[server]\nmode = \"recursive\"\nlisen = []\n"),
+        "3: server.lisen: unknown key"
+    );
+    assert_eq!(error("\n[servers]\n"), "2: servers: unknown table");
+    assert_eq!(error("\n\n[[zones]]\n"), "3: zones: unknown table");
+    assert_eq!(error("port = 53\n"), "1: port: unknown key");
+    assert_eq!(
+        error("[[zone]]\nname = \"a.example\"\n"),
+        "1: zone.file: missing"
+    );
+}
+
+#[test]
+fn test_syntax_errors() {
+    assert_eq!(error("[server\n"), "1: missing ] after table name");
+    assert_eq!(
+        error("[log]\nlevel = warn\n"),
+        "2: invalid value \"warn\", strings need double quotes"
+    );
+    assert_eq!(
+        error("[log]\nlevel = \"warn\n"),
+        "2: string is never closed"
+    );
+    assert_eq!(
+        error("[cache]\nsize = 1\nsize = 2\n"),
+        "3: size is set more than once"
+    );
+    assert_eq!(
+        error("[cache]\n[cache]\n"),
+        "2: [cache] is defined more than once"
+    );
+    assert_eq!(
+        error("[server]\nlisten = [\"127.0.0.1\",\n"),
+        "2: array for listen is never closed"
+    );
+    assert_eq!(
+        error("[log]\n\"level\"\n"),
+        "2: expected key = value, found \"\\\"level\\\"\""
+    );
+}
+
+#[test]
+fn test_mode_combinations() {
+    assert_eq!(
+        error("[server]\nmode = \"forwarder\"\n"),
+        "resolver.upstreams: forwarder mode needs at least one upstream"
+    );
+    assert_eq!(
+        error("[resolver]\nupstreams = [\"10.0.0.1\"]\n"),
+        "2: resolver.upstreams: upstreams only apply to forwarder mode"
+    );
+}
+
+#[test]
+fn test_options_override_file() {
+    let mut config =
+        Config::from_str("[server]\nlisten = [\"127.0.0.1:5300\"]\n[cache]\nsize = 10\n").unwrap();
+    let options = Options {
+        port: Some(5353),
+        cache_size: Some(20),
+        log_level: Some(Level::Info),
+        ..Options::default()
+    };
+    config.apply(&options).unwrap();
+    assert_eq!(
+        config.listen,
+        vec!["127.0.0.1:5353".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(config.cache_size, 20);
+    assert_eq!(config.log_level, Level::Info);
+
+    let options = Options {
+        mode: Some(Mode::Forwarder),
+        ..Options::default()
+    };
+    assert_eq!(
+        config.apply(&options).unwrap_err().to_string(),
+        "resolver.upstreams: forwarder mode needs at least one upstream"
+    );
+}
+
+#[test]
+fn test_load_from_file() {
+    let dir = std::env::temp_dir().join(format!("druns-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("druns.toml");
+    fs::write(
+        &path,
+        "[resolver]\nroot_hints = \"named.root\"\n\n[[zone]]\nname = \"corp.example\"\nfile = \"corp.zone\"\n",
+    )
+    .unwrap();
+
+    // relative paths are taken from the directory of the file
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.root_hints, Some(dir.join("named.root")));
+    assert_eq!(config.zones[0].file, dir.join("corp.zone"));
+
+    fs::write(&path, "[cache]\nsize = -1\n").unwrap();
+    assert_eq!(
+        Config::load(&path).unwrap_err().to_string(),
+        format!(
+            "{}:2: cache.size: expected a number of at least 1",
+            path.display()
+        )
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_acl() {
+    let acl = Acl {
+        allow: vec![
+            Network::from_str("10.0.0.0/8").unwrap(),
+            Network::from_str("::1").unwrap(),
+        ],
+        deny: vec![Network::from_str("10.1.0.0/16").unwrap()],
+    };
+    let ip = |x: &str| IpAddr::from_str(x).unwrap();
+    assert!(acl.permits(ip("10.2.3.4")));
+    assert!(acl.permits(ip("::1")));
+    assert!(!acl.permits(ip("10.1.3.4")));
+    assert!(!acl.permits(ip("192.168.1.1")));
+    assert!(!acl.permits(ip("::2")));
+    assert!(Acl::default().permits(ip("192.168.1.1")));
+
+    let net = Network::from_str("192.168.4.0/22").unwrap();
+    assert!(net.contains(ip("192.168.7.255")));
+    assert!(!net.contains(ip("192.168.8.0")));
+    assert!(Network::from_str("0.0.0.0/0")
+        .unwrap()
+        .contains(ip("8.8.8.8")));
+}
+
+#[test]
+fn test_durations() {
+    assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+    assert_eq!(parse_duration("3s"), Ok(Duration::from_secs(3)));
+    assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    assert!(parse_duration("0s").is_err());
+    assert!(parse_duration("5").is_err());
+    assert!(parse_duration("5h").is_err());
+}
+
+#[test]
+fn test_forward_rules() {
+    let config = Config::from_str(
+        "[[forward]]\nzone = \"corp.example\"\nupstreams = [\"10.1.0.53\", \"10.2.0.53:5353\"]\n",
+    )
+    .unwrap();
+    assert_eq!(config.mode, Mode::Recursive);
+    assert_eq!(config.forward[0].zone, "corp.example.");
+    assert_eq!(
+        config.forward[0].upstreams,
+        vec![
+            "10.1.0.53:53".parse::<SocketAddr>().unwrap(),
+            "10.2.0.53:5353".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+
+    assert_eq!(
+        error("[[forward]]\nzone = \"corp.example\"\nupstreams = []\n"),
+        "3: forward.upstreams: at least one upstream is needed"
+    );
+    assert_eq!(
+        error("\n[[forward]]\nupstreams = [\"10.1.0.53\"]\n"),
+        "2: forward.zone: missing"
+    );
+}
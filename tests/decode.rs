@@ -0,0 +1,207 @@
+use druns::buffer::ParseError;
+use druns::cli::UsageError;
+use druns::decode::{self, Command, Field, Format, Options};
+use std::fs;
+
+fn field(offset: usize, length: usize, description: &str) -> Field {
+    Field {
+        offset,
+        length,
+        description: description.to_string(),
+    }
+}
+
+#[test]
+fn test_args() {
+    assert_eq!(
+        decode::parse_args(Vec::<String>::new()).unwrap(),
+        Command::Run(Options::default())
+    );
+    assert_eq!(
+        decode::parse_args(vec!["-f", "base64", "-n", "a.txt", "-"]).unwrap(),
+        Command::Run(Options {
+            format: Some(Format::Base64),
+            dump: false,
+            files: vec!["a.txt".to_string(), "-".to_string()],
+        })
+    );
+    assert_eq!(decode::parse_args(vec!["--help"]).unwrap(), Command::Help);
+    assert_eq!(
+        decode::parse_args(vec!["--format", "pcap"]),
+        Err(UsageError("unknown format \"pcap\"".to_string()))
+    );
+    assert_eq!(
+        decode::parse_args(vec!["-f"]),
+        Err(UsageError("-f needs a value".to_string()))
+    );
+}
+
+#[test]
+fn test_input_formats() {
+    let raw = fs::read("tests/google_response.txt").unwrap();
+    let hex: String = raw.iter().map(|x| format!("{:02x} ", x)).collect();
+    let base64 = "3cCBgAABAAEAAAABBmdvb2dsZQNjb20AAAEAAcAMAAEAAQAAARcABKzZp64AACkE0AAAAAAAAA==";
+
+    let decode = |input: &[u8], format| decode::decode_input(input, format).unwrap();
+    assert_eq!(decode(&raw, None), (Format::Raw, raw.clone()));
+    assert_eq!(decode(hex.as_bytes(), None), (Format::Hex, raw.clone()));
+    // line breaks, as base64 tools wrap their output
+    let wrapped = format!("{}\n{}\n", &base64[..40], &base64[40..]);
+    assert_eq!(
+        decode(wrapped.as_bytes(), None),
+        (Format::Base64, raw.clone())
+    );
+    // unpadded and URL-safe
+    let url_safe = base64
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_");
+    assert_eq!(
+        decode(url_safe.as_bytes(), None),
+        (Format::Base64, raw.clone())
+    );
+
+    // all hex digits is also valid base64, an explicit format settles it
+    assert_eq!(decode(b"abcd", None), (Format::Hex, vec![0xab, 0xcd]));
+    assert_eq!(
+        decode(b"abcd", Some(Format::Base64)),
+        (Format::Base64, vec![0x69, 0xb7, 0x1d])
+    );
+
+    assert!(decode::decode_input(&raw, Some(Format::Hex)).is_err());
+    assert!(decode::decode_input(b"abc", Some(Format::Hex)).is_err());
+    assert!(decode::decode_base64("abcde").is_err());
+    assert!(decode::decode_base64("ab*d").is_err());
+}
+
+#[test]
+fn test_annotate() {
+    let bytes = fs::read("tests/soa_response.txt").unwrap();
+    let annotation = decode::annotate(&bytes);
+    assert!(annotation.error.is_none());
+
+    let names: Vec<&str> = annotation
+        .sections
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["header", "question 1", "authority 1"]);
+    assert_eq!(
+        annotation.sections[0].fields[1],
+        field(2, 2, "flags qr rd ra, opcode QUERY, rcode NXDOMAIN")
+    );
+    assert_eq!(
+        annotation.sections[1].fields[0],
+        field(12, 12, "label \"nonexistent\"")
+    );
+
+    let authority = &annotation.sections[2].fields;
+    assert_eq!(
+        authority[0],
+        field(0x29, 2, "pointer to 0018: example.com.")
+    );
+    assert_eq!(authority[4], field(0x33, 2, "rdlength 39"));
+    // mname ns1 + pointer, rname hostmaster + pointer, then the five numbers
+    assert_eq!(authority[5], field(0x35, 4, "label \"ns1\""));
+    assert_eq!(
+        authority[6],
+        field(0x39, 2, "pointer to 0018: example.com.")
+    );
+    assert_eq!(authority[13], field(0x58, 4, "minimum 3600"));
+
+    // every byte is covered exactly once
+    let fields: Vec<&Field> = annotation.sections.iter().flat_map(|x| &x.fields).collect();
+    let mut end = 0;
+    for field in fields {
+        assert_eq!(field.offset, end);
+        end += field.length;
+    }
+    assert_eq!(end, bytes.len());
+}
+
+#[test]
+fn test_annotate_rdata_types() {
+    for file in [
+        "srv_response",
+        "txt_response",
+        "ptr_response",
+        "unknown_response",
+    ]
+    .iter()
+    {
+        let bytes = fs::read(format!("tests/{}.txt", file)).unwrap();
+        let annotation = decode::annotate(&bytes);
+        assert!(annotation.error.is_none(), "{}", file);
+        let last = annotation.sections.last().unwrap().fields.last().unwrap();
+        assert_eq!(last.offset + last.length, bytes.len(), "{}", file);
+    }
+
+    let bytes = fs::read("tests/txt_response.txt").unwrap();
+    let annotation = decode::annotate(&bytes);
+    assert!(annotation.sections[2]
+        .fields
+        .contains(&field(0x29, 12, "string \"v=spf1 -all\"")));
+}
+
+#[test]
+fn test_dump() {
+    let bytes = fs::read("tests/google_response.txt").unwrap();
+    let dump = decode::dump(&bytes);
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], ";; header");
+    assert_eq!(lines[1], "0000  dd c0                    id 56768");
+    assert!(lines.contains(&"000c  06 67 6f 6f 67 6c 65     label \"google\""));
+    assert!(lines.contains(&"001c  c0 0c                    pointer to 000c: google.com."));
+    assert!(lines.contains(&"0028  ac d9 a7 ae              address 172.217.167.174"));
+    assert!(lines.contains(&"002f  04 d0                    udp payload size 1232"));
+
+    // fields longer than a line carry on below without a description
+    let bytes = fs::read("tests/soa_response.txt").unwrap();
+    let dump = decode::dump(&bytes);
+    assert!(
+        dump.contains("000c  0b 6e 6f 6e 65 78 69 73  label \"nonexistent\"\n0014  74 65 6e 74\n")
+    );
+}
+
+#[test]
+fn test_malformed() {
+    let bytes = fs::read("tests/soa_response.txt").unwrap();
+    let annotation = decode::annotate(&bytes[..40]);
+    assert_eq!(
+        annotation.error,
+        Some(ParseError::UnexpectedEnd { offset: 40 })
+    );
+    // the fields before the cut are kept, the rest is shown as unparsed
+    assert_eq!(annotation.sections.len(), 2);
+    let dump = decode::dump(&bytes[..40]);
+    assert!(
+        dump.ends_with(";; error: unexpected end of message at offset 40\n;; unparsed\n0027  00\n")
+    );
+
+    // a pointer to itself
+    let mut bytes = fs::read("tests/google_response.txt").unwrap();
+    bytes[0x1d] = 0x1c;
+    assert_eq!(
+        decode::annotate(&bytes).error,
+        Some(ParseError::PointerLoop {
+            offset: 0x1c,
+            target: 0x1c
+        })
+    );
+
+    // unknown opcodes and rcodes are described, not rejected
+    let mut bytes = fs::read("tests/google_response.txt").unwrap();
+    bytes[2] = 0xb1;
+    bytes[3] = 0x8b;
+    let annotation = decode::annotate(&bytes);
+    assert!(annotation.error.is_none());
+    assert_eq!(
+        annotation.sections[0].fields[1].description,
+        "flags qr rd ra, opcode OPCODE6, rcode RCODE11"
+    );
+
+    assert_eq!(
+        decode::annotate(&[0; 5]).error,
+        Some(ParseError::TruncatedHeader { offset: 5 })
+    );
+}
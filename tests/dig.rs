@@ -0,0 +1,315 @@
+mod common;
+
+use common::*;
+use druns::buffer::BytePacketBuffer;
+use druns::cli::UsageError;
+use druns::dig::{self, Command, Exchange, Options};
+use druns::lookup::Protocol;
+use druns::packet::{Packet, QueryType, Record, ResponseCode};
+use druns::tcp;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+fn options(args: &[&str]) -> Options {
+    match dig::parse_args(args.iter().copied()) {
+        Ok(Command::Run(options)) => options,
+        x => panic!("unexpected result {:?}", x),
+    }
+}
+
+fn error(args: &[&str]) -> String {
+    match dig::parse_args(args.iter().copied()) {
+        Err(UsageError(message)) => message,
+        x => panic!("expected an error, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_args() {
+    // like dig, nothing at all asks for the root servers
+    let defaults = options(&[]);
+    assert_eq!(defaults, Options::default());
+    assert_eq!(
+        (defaults.name.as_str(), defaults.qtype),
+        ("", QueryType::NS)
+    );
+    assert_eq!(defaults.server, "127.0.0.1:34254".parse().unwrap());
+
+    let parsed = options(&[
+        "www.example.com",
+        "+tcp",
+        "@::1",
+        "mx",
+        "CH",
+        "+norecurse",
+        "+dnssec",
+        "+bufsize=4096",
+        "+timeout=2",
+        "+trace",
+        "+json",
+        "-p",
+        "53",
+    ]);
+    assert_eq!(
+        parsed,
+        Options {
+            server: "[::1]:53".parse().unwrap(),
+            name: "www.example.com.".to_string(),
+            qtype: QueryType::MX,
+            class: 3,
+            tcp: true,
+            recurse: false,
+            dnssec: true,
+            bufsize: Some(4096),
+            timeout: Duration::from_secs(2),
+            trace: true,
+            json: true,
+        }
+    );
+
+    assert_eq!(options(&["example.com"]).qtype, QueryType::A);
+    assert_eq!(
+        options(&["example.com", "TYPE65"]).qtype,
+        QueryType::UNKNOWN(65)
+    );
+    assert_eq!(
+        options(&["@10.0.0.1:5353", "-p", "53"]).server,
+        "10.0.0.1:5353".parse().unwrap()
+    );
+    assert_eq!(
+        options(&["-p", "53"]).server,
+        "127.0.0.1:53".parse().unwrap()
+    );
+    assert_eq!(options(&["+noedns"]).bufsize, None);
+    assert_eq!(
+        options(&["+noedns", "+edns"]).bufsize,
+        Some(dig::DEFAULT_BUFSIZE)
+    );
+    assert_eq!(dig::parse_args(vec!["-h"]), Ok(Command::Help));
+    assert_eq!(dig::parse_args(vec!["a.", "-V"]), Ok(Command::Version));
+
+    assert_eq!(error(&["+bogus"]), "unknown option +bogus");
+    assert_eq!(error(&["+bufsize=big"]), "invalid buffer size \"big\"");
+    assert_eq!(error(&["+timeout=0"]), "invalid timeout \"0\"");
+    assert_eq!(
+        error(&["@resolver.example"]),
+        "invalid server \"resolver.example\", expected ip, ip:port or [ipv6]:port"
+    );
+    assert_eq!(
+        error(&["a.", "A", "IN", "extra"]),
+        "unexpected argument \"extra\""
+    );
+    assert_eq!(error(&["-x"]), "unknown option -x");
+    assert_eq!(error(&["-p"]), "-p needs a value");
+}
+
+#[test]
+fn test_query() {
+    let port = free_port();
+    let seen: Arc<Mutex<Vec<Packet>>> = Arc::new(Mutex::new(vec![]));
+    let requests = seen.clone();
+    spawn_server(
+        "127.0.0.1",
+        port,
+        Box::new(move |request| {
+            requests.lock().unwrap().push(request.clone());
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [192, 0, 2, 1])]))
+        }),
+    );
+
+    let mut options = options(&["www.example.com", "+dnssec", "+bufsize=4096"]);
+    options.server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let exchange = dig::query(&options).unwrap();
+    assert_eq!(exchange.protocol, Protocol::Udp);
+    assert_eq!(
+        exchange.response.answers,
+        vec![a("www.example.com.", [192, 0, 2, 1])]
+    );
+
+    let request = seen.lock().unwrap()[0].clone();
+    assert!(request.header.recursion_desired);
+    assert_eq!(request.max_udp_payload(), 4096);
+    assert!(matches!(
+        request.edns(),
+        Some(Record::OPT {
+            dnssec_ok: true,
+            ..
+        })
+    ));
+
+    let text = dig::to_text(&exchange);
+    assert!(text.contains(";; ANSWER SECTION:\nwww.example.com. 300 IN A 192.0.2.1\n"));
+    assert!(text.contains(&format!(";; SERVER: 127.0.0.1:{}(udp)\n", port)));
+
+    options.recurse = false;
+    options.bufsize = None;
+    dig::query(&options).unwrap();
+    let request = seen.lock().unwrap()[1].clone();
+    assert!(!request.header.recursion_desired);
+    assert!(request.edns().is_none());
+}
+
+#[test]
+fn test_truncated_reply_retried_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn_server(
+        "127.0.0.1",
+        port,
+        Box::new(|request| {
+            let mut packet = response(request);
+            packet.header.is_truncated = true;
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut message = tcp::read_message(&mut stream).unwrap().unwrap();
+        let request = Packet::read(&mut message).unwrap();
+        let reply = answer(&request, vec![a("big.example.com.", [192, 0, 2, 9])]);
+        let mut buffer = BytePacketBuffer::new_empty();
+        reply.write(&mut buffer).unwrap();
+        tcp::write_message(&mut stream, &buffer).unwrap();
+    });
+
+    let mut options = options(&["big.example.com"]);
+    options.server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let exchange = dig::query(&options).unwrap();
+    assert_eq!(exchange.protocol, Protocol::Tcp);
+    assert_eq!(exchange.response.answers.len(), 1);
+}
+
+#[test]
+fn test_no_reply() {
+    let port = free_port();
+    spawn_server("127.0.0.1", port, Box::new(|_| None));
+    let mut options = options(&["www.example.com"]);
+    options.server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    options.timeout = Duration::from_millis(200);
+    let error = dig::query(&options).unwrap_err().to_string();
+    assert!(error.starts_with("no reply from 127.0.0.1:"), "{}", error);
+}
+
+#[test]
+fn test_trace() {
+    let port = free_port();
+    let [root, com, example] = spawn_hierarchy(port);
+    let mut options = options(&["www.example.com"]);
+    // nothing should need the server itself
+    options.server = "127.0.0.99:1".parse().unwrap();
+
+    let mut steps: Vec<Exchange> = vec![];
+    dig::trace(&options, &[IpAddr::V4(Ipv4Addr::LOCALHOST)], port, |x| {
+        steps.push(x.clone())
+    })
+    .unwrap();
+    let servers: Vec<String> = steps.iter().map(|x| x.server.ip().to_string()).collect();
+    assert_eq!(servers, vec!["127.0.0.1", "127.0.0.2", "127.0.0.3"]);
+    assert_eq!(
+        steps[2].response.answers,
+        vec![a("www.example.com.", [10, 0, 0, 1])]
+    );
+    assert_eq!((count(&root), count(&com), count(&example)), (1, 1, 1));
+}
+
+#[test]
+fn test_trace_glueless() {
+    let port = free_port();
+    spawn_server(
+        "127.0.0.1",
+        port,
+        Box::new(|request| {
+            let mut packet = response(request);
+            packet.authority = vec![ns("example.com.", "ns.example.net.")];
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    spawn_server(
+        "127.0.0.3",
+        port,
+        Box::new(|request| {
+            assert!(!request.header.recursion_desired);
+            let mut packet = response(request);
+            packet.header.rcode = ResponseCode::nx_domain;
+            packet.authority = vec![soa("example.com.", 3600, 300)];
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    // the server of the options resolves the nameserver
+    let resolver = spawn_server(
+        "127.0.0.40",
+        port,
+        Box::new(|request| {
+            assert!(request.header.recursion_desired);
+            Some(answer(request, vec![a("ns.example.net.", [127, 0, 0, 3])]))
+        }),
+    );
+    let mut options = options(&["nothing.example.com"]);
+    options.server = SocketAddr::new("127.0.0.40".parse().unwrap(), port);
+
+    let mut steps: Vec<Exchange> = vec![];
+    dig::trace(&options, &[IpAddr::V4(Ipv4Addr::LOCALHOST)], port, |x| {
+        steps.push(x.clone())
+    })
+    .unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[1].response.header.rcode, ResponseCode::nx_domain);
+    assert_eq!(count(&resolver), 1);
+}
+
+#[test]
+fn test_json() {
+    let mut request = dig::request(&options(&["www.example.com"]));
+    request.header.id = 7;
+    let mut reply = answer(&request, vec![a("www.example.com.", [192, 0, 2, 1])]);
+    reply.header.recursion_desired = true;
+    reply.additional = request.additional.clone();
+    reply.update_counts();
+    let exchange = Exchange {
+        server: "127.0.0.1:53".parse().unwrap(),
+        protocol: Protocol::Udp,
+        response: reply,
+        size: 60,
+        elapsed: Duration::from_millis(12),
+    };
+    assert_eq!(
+        dig::to_json(&exchange),
+        "{\"server\":\"127.0.0.1:53\",\"protocol\":\"udp\",\"time_ms\":12,\"size\":60,\"id\":7,\
+         \"opcode\":0,\"rcode\":\"NOERROR\",\"flags\":[\"qr\",\"aa\",\"rd\"],\
+         \"question\":[{\"name\":\"www.example.com.\",\"class\":\"IN\",\"type\":\"A\"}],\
+         \"answer\":[{\"name\":\"www.example.com.\",\"ttl\":300,\"class\":\"IN\",\"type\":\"A\",\
+         \"data\":\"192.0.2.1\"}],\"authority\":[],\"additional\":[],\
+         \"edns\":{\"version\":0,\"udp\":1232,\"do\":false,\"extended_rcode\":0}}"
+    );
+
+    let txt = Record::TXT {
+        name: "example.com.".to_string(),
+        class: 1,
+        ttl: 60,
+        data: vec![b"say \"hi\"".to_vec()],
+    };
+    let mut exchange = exchange;
+    exchange.response.answers = vec![txt];
+    assert!(dig::to_json(&exchange)
+        .contains("\"type\":\"TXT\",\"data\":\"\\\"say \\\\\\\"hi\\\\\\\"\\\"\""));
+
+    exchange.response.answers = vec![Record::UNKNOWN {
+        name: "a\\032b.example.".to_string(),
+        rtype: 65400,
+        class: 3,
+        ttl: 60,
+        data: vec![0xde, 0xad],
+    }];
+    assert!(dig::to_json(&exchange).contains(
+        "{\"name\":\"a\\\\032b.example.\",\"ttl\":60,\"class\":\"CH\",\"type\":\"TYPE65400\",\
+         \"data\":\"\\\\# 2 dead\"}"
+    ));
+}
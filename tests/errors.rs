@@ -0,0 +1,215 @@
+mod common;
+
+use common::*;
+use druns::buffer::BytePacketBuffer;
+use druns::config::{Config, Mode};
+use druns::lookup::{self, create_request_packet, process_query, Protocol};
+use druns::packet::{Packet, PacketType, QueryType, ResponseCode};
+use druns::tcp;
+use std::{
+    net::{SocketAddr, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn request_bytes(name: &str, qtype: QueryType) -> Vec<u8> {
+    let mut request = create_request_packet(name, qtype);
+    request.header.id = 4242;
+    let mut buffer = BytePacketBuffer::new_empty();
+    request.write(&mut buffer).unwrap();
+    buffer[0..buffer.size].to_vec()
+}
+
+#[test]
+fn test_servfail_when_upstream_unreachable() {
+    let port = free_port();
+    // root never answers
+    let root = spawn_server("127.0.0.1", port, Box::new(|_| None));
+    let mut resolver = resolver(port);
+    resolver.timeout = Duration::from_millis(100);
+    resolver.retries = 0;
+
+    let mut request = create_request_packet("www.example.com.", QueryType::A);
+    request.header.id = 4242;
+    let response = send(&resolver, &request).unwrap().unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+    assert_eq!(response.header.id, 4242);
+    assert_eq!(response.header.qr, PacketType::Response);
+    assert_eq!(response.questions[0].name, "www.example.com.");
+    assert_eq!(response.questions[0].qtype, QueryType::A);
+    assert_eq!(count(&root), 1);
+}
+
+#[test]
+fn test_formerr_for_malformed_query() {
+    let resolver = resolver(free_port());
+    // question is fine, but claims an answer record that isn't there
+    let mut bytes = request_bytes("www.example.com.", QueryType::A);
+    bytes[7] = 1;
+
+    let response = send_bytes(&resolver, &bytes).unwrap().unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::format_err);
+    assert_eq!(response.header.id, 4242);
+    assert_eq!(response.questions[0].name, "www.example.com.");
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn test_formerr_for_garbage() {
+    let resolver = resolver(free_port());
+    let response = send_bytes(&resolver, &[0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0xff])
+        .unwrap()
+        .unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::format_err);
+    assert_eq!(response.header.id, 0x1234);
+    assert!(response.questions.is_empty());
+}
+
+#[test]
+fn test_formerr_without_question() {
+    let resolver = resolver(free_port());
+    let mut request = create_request_packet("www.example.com.", QueryType::A);
+    request.questions.clear();
+    request.update_counts();
+
+    let response = send(&resolver, &request).unwrap().unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::format_err);
+    assert_eq!(response.header.id, request.header.id);
+}
+
+#[test]
+fn test_notimp_for_unknown_opcode() {
+    let resolver = resolver(free_port());
+    let mut bytes = request_bytes("www.example.com.", QueryType::A);
+    // opcode 3 is unassigned
+    bytes[2] = (bytes[2] & 0x87) | (3 << 3);
+
+    // the reply echoes the opcode, so it can't be parsed back either
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let response = process_query(&resolver, &mut buffer, Protocol::Udp)
+        .unwrap()
+        .unwrap();
+    assert_eq!(response.read_u16_from(0).unwrap(), 4242);
+    let flags = response.read_u16_from(2).unwrap();
+    assert_eq!(flags >> 15, 1);
+    assert_eq!((flags >> 11) & 0x0F, 3);
+    assert_eq!(flags & 0x0F, 4);
+    // question still there
+    assert_eq!(response.read_u16_from(4).unwrap(), 1);
+    assert_eq!(response.read_qname_from(12).unwrap().0, "www.example.com.");
+}
+
+#[test]
+fn test_notimp_for_unsupported_opcode() {
+    let resolver = resolver(free_port());
+    let mut request = create_request_packet("example.com.", QueryType::SOA);
+    // NOTIFY
+    request.header.opcode = 4;
+
+    let response = send(&resolver, &request).unwrap().unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::not_imp);
+    assert_eq!(response.header.opcode, 4);
+    assert_eq!(response.questions[0].name, "example.com.");
+}
+
+#[test]
+fn test_refused_zone_transfer() {
+    let port = free_port();
+    let root = spawn_server("127.0.0.1", port, Box::new(|_| None));
+    let resolver = resolver(port);
+
+    let response = try_query(&resolver, "example.com.", QueryType::UNKNOWN(252))
+        .unwrap()
+        .unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::refused);
+    assert_eq!(response.questions[0].qtype, QueryType::UNKNOWN(252));
+
+    let mut request = create_request_packet("version.bind.", QueryType::TXT);
+    // CHAOS
+    request.questions[0].class = 3;
+    let response = send(&resolver, &request).unwrap().unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::refused);
+    assert_eq!(count(&root), 0);
+}
+
+#[test]
+fn test_no_reply_to_responses() {
+    let resolver = resolver(free_port());
+    let mut request = create_request_packet("www.example.com.", QueryType::A);
+    request.header.qr = PacketType::Response;
+    assert!(send(&resolver, &request).unwrap().is_none());
+
+    let mut bytes = request_bytes("www.example.com.", QueryType::A);
+    bytes[2] |= 0x80;
+    bytes[7] = 1;
+    assert!(send_bytes(&resolver, &bytes).unwrap().is_none());
+}
+
+#[test]
+fn test_servfail_when_pool_is_full() {
+    let port = free_port();
+    // a slow upstream keeps the only worker busy
+    spawn_server(
+        "127.0.0.20",
+        port,
+        Box::new(|request| {
+            thread::sleep(Duration::from_secs(2));
+            Some(answer(
+                request,
+                vec![a(&question_name(request), [10, 0, 0, 1])],
+            ))
+        }),
+    );
+    let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = Config {
+        listen: vec![addr],
+        mode: Mode::Forwarder,
+        upstreams: vec![SocketAddr::from(([127, 0, 0, 20], port))],
+        workers: 1,
+        queue_size: 1,
+        timeout: Duration::from_secs(5),
+        ..Config::default()
+    };
+    thread::spawn(move || lookup::start(&config).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("server isn't listening: {}", e),
+        }
+    };
+    thread::sleep(Duration::from_millis(100));
+
+    // one query for the worker, one waiting, and the rest turned away
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    for i in 0..4 {
+        let mut bytes = request_bytes(&format!("host{}.example.com.", i), QueryType::A);
+        bytes[1] = i;
+        socket.send_to(&bytes, addr).unwrap();
+        if i == 0 {
+            // until the worker has taken it, it's the one waiting
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+    let mut buffer = BytePacketBuffer::with_max_size(4096);
+    let (size, _) = socket.recv_from(&mut buffer).unwrap();
+    buffer.size = size;
+    let response = Packet::read(&mut buffer).unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+    assert!(response.questions[0].name.starts_with("host"));
+
+    // over TCP the reply comes on the connection, rather than never
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let request = BytePacketBuffer::from_bytes(&request_bytes("tcp.example.com.", QueryType::A));
+    tcp::write_message(&mut stream, &request).unwrap();
+    let mut reply = tcp::read_message(&mut stream).unwrap().unwrap();
+    let response = Packet::read(&mut reply).unwrap();
+    assert_eq!(response.header.id, 4242);
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+}
@@ -0,0 +1,231 @@
+mod common;
+
+use common::*;
+use druns::forward::{Forwarder, Upstream, MAX_FAILURES};
+use druns::packet::{Packet, QueryType, ResponseCode};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+fn addr(ip: &str, port: u16) -> SocketAddr {
+    format!("{}:{}", ip, port).parse().unwrap()
+}
+
+/// recursive resolver answering everything with `ip`, which insists on RD
+fn upstream(ip: [u8; 4]) -> Handler {
+    Box::new(move |request: &Packet| {
+        assert!(request.header.recursion_desired);
+        let name = question_name(request);
+        Some(answer(request, vec![a(&name, ip)]))
+    })
+}
+
+#[test]
+fn test_forwards_instead_of_recursing() {
+    let port = free_port();
+    let root = spawn_server("127.0.0.1", port, Box::new(|_| None));
+    let upstream = spawn_server("127.0.0.20", port, upstream([10, 0, 9, 1]));
+    let mut resolver = resolver(port);
+    resolver.forwarder = Forwarder::new(vec![addr("127.0.0.20", port)]);
+
+    let mut request = druns::lookup::create_request_packet("www.example.com.", QueryType::A);
+    request.header.recursion_desired = false;
+    let response = send(&resolver, &request).unwrap().unwrap();
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 9, 1])]);
+    // RD is still echoed as the client sent it
+    assert!(!response.header.recursion_desired);
+
+    // cached like any other answer
+    query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!((count(&root), count(&upstream)), (0, 1));
+}
+
+#[test]
+fn test_failing_upstream_held_down() {
+    let port = free_port();
+    let dead = spawn_server("127.0.0.20", port, Box::new(|_| None));
+    let alive = spawn_server("127.0.0.21", port, upstream([10, 0, 9, 1]));
+    let mut resolver = resolver(port);
+    resolver.timeout = Duration::from_millis(100);
+    resolver.retries = 0;
+    resolver.forwarder = Forwarder::new(vec![addr("127.0.0.20", port), addr("127.0.0.21", port)]);
+
+    for i in 0..MAX_FAILURES + 2 {
+        let response = query(&resolver, &format!("host{}.example.com.", i), QueryType::A);
+        assert_eq!(response.answers.len(), 1);
+    }
+    // the dead one got tried until it was held down, and the first answer
+    // already moved the working one to the front
+    assert_eq!(count(&dead), 1);
+    assert_eq!(count(&alive), MAX_FAILURES as usize + 2);
+}
+
+#[test]
+fn test_servfail_upstream_skipped() {
+    let port = free_port();
+    let broken = spawn_server(
+        "127.0.0.20",
+        port,
+        Box::new(|request| {
+            let mut packet = response(request);
+            packet.header.rcode = ResponseCode::serv_fail;
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    spawn_server("127.0.0.21", port, upstream([10, 0, 9, 1]));
+    let mut resolver = resolver(port);
+    resolver.forwarder = Forwarder::new(vec![addr("127.0.0.20", port), addr("127.0.0.21", port)]);
+
+    for i in 0..MAX_FAILURES + 2 {
+        let response = query(&resolver, &format!("host{}.example.com.", i), QueryType::A);
+        assert_eq!(response.answers.len(), 1);
+    }
+    // an answer, even a SERVFAIL, isn't a failure, so it never gets held down
+    assert_eq!(count(&broken), MAX_FAILURES as usize + 2);
+}
+
+#[test]
+fn test_all_upstreams_failing() {
+    let port = free_port();
+    spawn_server("127.0.0.20", port, Box::new(|_| None));
+    let mut resolver = resolver(port);
+    resolver.timeout = Duration::from_millis(100);
+    resolver.retries = 0;
+    resolver.forwarder = Forwarder::new(vec![addr("127.0.0.20", port)]);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+}
+
+#[test]
+fn test_per_domain_rules() {
+    let port = free_port();
+    let general = spawn_server("127.0.0.20", port, upstream([10, 0, 9, 1]));
+    let corp = spawn_server("127.0.0.21", port, upstream([10, 0, 9, 2]));
+    let lab = spawn_server("127.0.0.22", port, upstream([10, 0, 9, 3]));
+    let mut resolver = resolver(port);
+    let mut forwarder = Forwarder::new(vec![addr("127.0.0.20", port)]);
+    forwarder.add_rule("corp.example", vec![addr("127.0.0.21", port)]);
+    forwarder.add_rule("lab.corp.example.", vec![addr("127.0.0.22", port)]);
+    resolver.forwarder = forwarder;
+
+    let ip = |name: &str| query(&resolver, name, QueryType::A).answers;
+    assert_eq!(
+        ip("www.example.com."),
+        vec![a("www.example.com.", [10, 0, 9, 1])]
+    );
+    // rules match whatever the case
+    assert_eq!(ip("CORP.example."), vec![a("corp.example.", [10, 0, 9, 2])]);
+    assert_eq!(
+        ip("a.corp.example."),
+        vec![a("a.corp.example.", [10, 0, 9, 2])]
+    );
+    assert_eq!(
+        ip("b.lab.corp.example."),
+        vec![a("b.lab.corp.example.", [10, 0, 9, 3])]
+    );
+    // only a whole label counts
+    assert_eq!(
+        ip("notcorp.example."),
+        vec![a("notcorp.example.", [10, 0, 9, 1])]
+    );
+    assert_eq!((count(&general), count(&corp), count(&lab)), (2, 2, 1));
+}
+
+#[test]
+fn test_rules_alongside_recursion() {
+    let port = free_port();
+    let [root, _, _] = spawn_hierarchy(port);
+    let corp = spawn_server("127.0.0.21", port, upstream([10, 0, 9, 2]));
+    let mut resolver = resolver(port);
+    resolver
+        .forwarder
+        .add_rule("corp.example.", vec![addr("127.0.0.21", port)]);
+
+    let response = query(&resolver, "www.corp.example.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![a("www.corp.example.", [10, 0, 9, 2])]
+    );
+    assert_eq!(count(&root), 0);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+    assert_eq!((count(&root), count(&corp)), (1, 1));
+}
+
+#[test]
+fn test_rule_upstream_stays_in_its_zone() {
+    let port = free_port();
+    let lied = Arc::new(AtomicBool::new(false));
+    let lying = lied.clone();
+    spawn_server(
+        "127.0.0.21",
+        port,
+        Box::new(move |request| {
+            lying.store(true, Ordering::SeqCst);
+            let name = question_name(request);
+            Some(answer(
+                request,
+                vec![
+                    a(&name, [10, 0, 9, 2]),
+                    a("www.bank.example.", [6, 6, 6, 6]),
+                ],
+            ))
+        }),
+    );
+    let mut resolver = resolver(port);
+    resolver
+        .forwarder
+        .add_rule("corp.example.", vec![addr("127.0.0.21", port)]);
+
+    let response = query(&resolver, "www.corp.example.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![a("www.corp.example.", [10, 0, 9, 2])]
+    );
+    assert!(lied.load(Ordering::SeqCst));
+    let mut cache = resolver.cache.lock().unwrap();
+    assert!(cache.get("www.bank.example.", QueryType::A, 1).is_none());
+}
+
+#[test]
+fn test_upstream_order() {
+    let upstreams = vec![
+        Upstream::new(addr("10.0.0.1", 53)),
+        Upstream::new(addr("10.0.0.2", 53)),
+        Upstream::new(addr("10.0.0.3", 53)),
+        Upstream::new(addr("10.0.0.4", 53)),
+    ];
+    upstreams[0].succeeded(Duration::from_millis(50));
+    upstreams[1].succeeded(Duration::from_millis(10));
+    for _ in 0..MAX_FAILURES {
+        upstreams[2].failed();
+    }
+    assert!(upstreams[2].is_down());
+
+    let order: Vec<SocketAddr> = Forwarder::ordered(&upstreams)
+        .iter()
+        .map(|x| x.addr)
+        .collect();
+    // never asked, then fastest first, then the one held down
+    assert_eq!(
+        order,
+        vec![
+            addr("10.0.0.4", 53),
+            addr("10.0.0.2", 53),
+            addr("10.0.0.1", 53),
+            addr("10.0.0.3", 53),
+        ]
+    );
+
+    // an answer brings it straight back
+    upstreams[2].succeeded(Duration::from_millis(20));
+    assert!(!upstreams[2].is_down());
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use common::*;
+use druns::hints;
+use druns::lookup::prime;
+use druns::packet::{Packet, QueryType, Record};
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+#[test]
+fn test_default_hints() {
+    let records = hints::default();
+    let ns: Vec<&Record> = records
+        .iter()
+        .filter(|x| matches!(x, Record::NS { .. }))
+        .collect();
+    assert_eq!(ns.len(), 13);
+    assert_eq!(
+        ns[0],
+        &Record::NS {
+            name: String::new(),
+            class: 1,
+            ttl: 3600000,
+            host: "a.root-servers.net.".to_string(),
+        }
+    );
+
+    let addresses = hints::ipv4_addresses(&records);
+    assert_eq!(addresses.len(), 13);
+    assert_eq!(addresses[0], Ipv4Addr::new(198, 41, 0, 4));
+    assert!(records.contains(&Record::AAAA {
+        name: "m.root-servers.net.".to_string(),
+        class: 1,
+        ttl: 3600000,
+        ip: "2001:dc3::35".parse::<Ipv6Addr>().unwrap(),
+    }));
+}
+
+#[test]
+fn test_parse_hints() {
+    let records = hints::parse(
+        "; local root\n\
+         $TTL 3600\n\
+         .  IN  NS  ns.root.test.   ; class is optional\n\
+         ns.root.test.  60  in  a  10.0.0.1\n\
+         unrelated.test.  60  A  10.0.0.2\n",
+    )
+    .unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].ttl(), 3600);
+    // only addresses of the root's own nameservers count
+    assert_eq!(
+        hints::ipv4_addresses(&records),
+        vec![Ipv4Addr::new(10, 0, 0, 1)]
+    );
+}
+
+#[test]
+fn test_invalid_hints() {
+    let error = |text: &str| hints::parse(text).unwrap_err().to_string();
+    assert_eq!(
+        error(". 60 NS ns.root.test.\nns.root.test. 60 A 10.0.0.300\n"),
+        "line 2: invalid ipv4 address \"10.0.0.300\""
+    );
+    assert_eq!(
+        error("com. 60 NS ns.root.test.\n"),
+        "NS record for com., not the root"
+    );
+    assert_eq!(
+        error(". 60 NS ns.root.test.\nns.root.test. 60 MX 10 mail.test.\n"),
+        "unexpected MX record for ns.root.test."
+    );
+    assert_eq!(
+        error(". 60 NS ns.root.test.\n"),
+        "no root server addresses found"
+    );
+
+    let path = std::env::temp_dir().join("druns-missing-named.root");
+    let _ = fs::remove_file(&path);
+    assert!(hints::read(&path)
+        .unwrap_err()
+        .to_string()
+        .starts_with(&path.display().to_string()));
+}
+
+/// root server which knows of 127.0.0.1 as the only root
+fn priming_server() -> Handler {
+    Box::new(|request: &Packet| {
+        assert_eq!(question_name(request), "");
+        assert_eq!(request.questions[0].qtype, QueryType::NS);
+        assert!(!request.header.recursion_desired);
+        assert!(request.edns().is_some());
+        let mut packet = answer(
+            request,
+            vec![ns("", "a.root.test."), ns("com.", "a.gtld.com.")],
+        );
+        packet.additional = vec![
+            a("a.root.test.", [127, 0, 0, 1]),
+            a("www.bank.test.", [6, 6, 6, 6]),
+        ];
+        packet.update_counts();
+        Some(packet)
+    })
+}
+
+#[test]
+fn test_priming() {
+    let port = free_port();
+    let hint = spawn_server("127.0.0.30", port, priming_server());
+    let [root, com, _] = spawn_hierarchy(port);
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 30)];
+
+    // the set is good for as long as its shortest ttl
+    assert_eq!(prime(&resolver).unwrap(), 300);
+    {
+        let mut cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.get("", QueryType::NS, 1).unwrap().len(), 1);
+        assert!(cache.get("com.", QueryType::NS, 1).is_none());
+        assert!(cache.get("www.bank.test.", QueryType::A, 1).is_none());
+    }
+
+    // resolution starts at the primed root, not the hints
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+    assert_eq!((count(&hint), count(&root), count(&com)), (1, 1, 1));
+}
+
+#[test]
+fn test_priming_failover() {
+    let port = free_port();
+    let silent = spawn_server("127.0.0.30", port, Box::new(|_| None));
+    let hint = spawn_server("127.0.0.31", port, priming_server());
+    let mut resolver = resolver(port);
+    resolver.timeout = Duration::from_millis(100);
+    resolver.retries = 0;
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 30), Ipv4Addr::new(127, 0, 0, 31)];
+
+    assert_eq!(prime(&resolver).unwrap(), 300);
+    assert_eq!((count(&silent), count(&hint)), (1, 1));
+}
+
+#[test]
+fn test_priming_fails() {
+    let port = free_port();
+    spawn_server("127.0.0.30", port, Box::new(|_| None));
+    // an answer without the root NS set is no use either
+    spawn_server(
+        "127.0.0.31",
+        port,
+        Box::new(|request| Some(answer(request, vec![ns("com.", "a.gtld.com.")]))),
+    );
+    let mut resolver = resolver(port);
+    resolver.timeout = Duration::from_millis(100);
+    resolver.retries = 0;
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 30), Ipv4Addr::new(127, 0, 0, 31)];
+
+    assert!(prime(&resolver).is_err());
+    assert!(resolver.cache.lock().unwrap().is_empty());
+}
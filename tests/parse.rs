@@ -1,11 +1,11 @@
-use druns::packet::{Packet, PacketType};
+use druns::buffer::ParseError;
+use druns::packet::{is_subdomain, Packet, PacketType, QueryType, Record};
 use druns::{buffer::BytePacketBuffer, packet::ResponseCode};
 
 #[test]
 fn test_parse_response_google() {
     let mut buffer = BytePacketBuffer::new(String::from("tests/google_response.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer).unwrap();
     assert_eq!(packet.header.ques_c, 1);
     assert_eq!(packet.header.ans_c, 1);
     assert_eq!(packet.header.rcode, ResponseCode::no_error);
@@ -23,8 +23,7 @@ fn test_parse_response_google() {
 #[test]
 fn test_parse_request_google() {
     let mut buffer = BytePacketBuffer::new(String::from("tests/google_request.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer).unwrap();
     assert_eq!(packet.header.ques_c, 1);
     assert_eq!(packet.header.ans_c, 0);
     assert_eq!(packet.header.rcode, ResponseCode::no_error);
@@ -36,8 +35,7 @@ fn test_parse_request_google() {
 #[test]
 fn test_parse_request_netflix() {
     let mut buffer = BytePacketBuffer::new(String::from("tests/netflix_request.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer).unwrap();
     assert_eq!(packet.header.ques_c, 1);
     assert_eq!(packet.header.ans_c, 0);
     assert_eq!(packet.header.rcode, ResponseCode::no_error);
@@ -48,8 +46,7 @@ fn test_parse_request_netflix() {
 #[test]
 fn test_parse_response_netflix() {
     let mut buffer = BytePacketBuffer::new(String::from("tests/netflix_response.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer).unwrap();
     assert_eq!(packet.header.ques_c, 1);
     assert_ne!(packet.header.ans_c, 0);
     assert_eq!(packet.header.rcode, ResponseCode::no_error);
@@ -57,9 +54,285 @@ fn test_parse_response_netflix() {
     check_counts(&packet);
 }
 
+#[test]
+fn test_parse_truncated_header() {
+    let mut buffer = BytePacketBuffer::from_bytes(&[0xdd, 0xc0, 0x01, 0x20, 0x00]);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(err, ParseError::TruncatedHeader { offset: 5 });
+}
+
+#[test]
+fn test_parse_truncated_question() {
+    // google request cut in the middle of the question name
+    let full = BytePacketBuffer::new(String::from("tests/google_request.txt"));
+    let mut buffer = BytePacketBuffer::from_bytes(&full[0..16]);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(err, ParseError::UnexpectedEnd { offset: 16 });
+}
+
+#[test]
+fn test_parse_pointer_loop() {
+    let mut bytes = vec![0xdd, 0xc0, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 0];
+    // question name is a pointer to itself
+    bytes.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(
+        err,
+        ParseError::PointerLoop {
+            offset: 12,
+            target: 12
+        }
+    );
+}
+
+#[test]
+fn test_parse_bad_label() {
+    let mut bytes = vec![0xdd, 0xc0, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 0];
+    bytes.extend_from_slice(&[0x41, 0x00, 0x00, 0x01, 0x00, 0x01]);
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(
+        err,
+        ParseError::BadLabel {
+            offset: 12,
+            length: 0x41
+        }
+    );
+}
+
+#[test]
+fn test_parse_binary_label() {
+    // "google" becomes go.g\xffe, a single label that is neither text nor
+    // free of dots
+    let full = BytePacketBuffer::new(String::from("tests/google_response.txt"));
+    let mut bytes = full.to_vec();
+    bytes[13..19].copy_from_slice(b"go.g\xffe");
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.questions[0].name, "go\\.g\\255e.com.");
+    assert_eq!(packet.answers[0].name(), "go\\.g\\255e.com.");
+    assert!(!is_subdomain(&packet.questions[0].name, "g\\255e.com."));
+    assert!(is_subdomain(&packet.questions[0].name, "com."));
+
+    let mut written = BytePacketBuffer::new_empty();
+    packet.write(&mut written).unwrap();
+    assert_eq!(&written[12..30], &bytes[12..30]);
+}
+
+#[test]
+fn test_parse_rdlength_mismatch() {
+    let full = BytePacketBuffer::new(String::from("tests/google_response.txt"));
+    let mut bytes = full.to_vec();
+    // A record claims 5 bytes of rdata instead of 4
+    bytes[39] = 5;
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(
+        err,
+        ParseError::RdLengthMismatch {
+            offset: 40,
+            expected: 5,
+            actual: 4
+        }
+    );
+    assert_eq!(err.offset(), 40);
+}
+
+#[test]
+fn test_parse_unknown_opcode() {
+    let full = BytePacketBuffer::new(String::from("tests/google_request.txt"));
+    let mut bytes = full.to_vec();
+    bytes[2] |= 0x7 << 3;
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let err = Packet::read(&mut buffer).unwrap_err();
+    assert_eq!(
+        err,
+        ParseError::UnknownOpcode {
+            offset: 2,
+            opcode: 7
+        }
+    );
+}
+
+#[test]
+fn test_parse_unassigned_rcode() {
+    // NOTAUTH, from RFC 2136, is a valid reply even though nothing here
+    // sends it
+    let full = BytePacketBuffer::new(String::from("tests/soa_response.txt"));
+    let mut bytes = full.to_vec();
+    bytes[3] = (bytes[3] & 0xf0) | 9;
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.header.rcode, ResponseCode::unknown(9));
+    assert_eq!(packet.header.rcode.to_string(), "NOTAUTH");
+    assert_eq!(packet.authority.len(), 1);
+    check_round_trip(&packet);
+    let mut written = BytePacketBuffer::new_empty();
+    packet.write(&mut written).unwrap();
+    assert_eq!(written[3], bytes[3]);
+
+    bytes[3] |= 0xf;
+    let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.header.rcode.to_string(), "RCODE15");
+}
+
 fn check_counts(packet: &Packet) {
     assert_eq!(packet.header.ques_c, packet.questions.len() as u16);
     assert_eq!(packet.header.ans_c, packet.answers.len() as u16);
     assert_eq!(packet.header.auth_c, packet.authority.len() as u16);
     assert_eq!(packet.header.addi_c, packet.additional.len() as u16);
 }
+
+#[test]
+fn test_parse_edns() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/netflix_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(
+        packet.edns(),
+        Some(&Record::OPT {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        })
+    );
+
+    let mut buffer = BytePacketBuffer::new(String::from("tests/google_request.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.max_udp_payload(), 4096);
+}
+
+#[test]
+fn test_parse_soa_response() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/soa_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.header.rcode, ResponseCode::nx_domain);
+    assert!(packet.answers.is_empty());
+    assert_eq!(
+        packet.authority[0],
+        Record::SOA {
+            name: String::from("example.com."),
+            class: 1,
+            ttl: 3600,
+            mname: String::from("ns1.example.com."),
+            rname: String::from("hostmaster.example.com."),
+            serial: 2021110601,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 3600,
+        }
+    );
+    assert_eq!(
+        packet.authority[0].to_string(),
+        "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 2021110601 7200 3600 1209600 3600"
+    );
+    check_counts(&packet);
+    check_round_trip(&packet);
+}
+
+#[test]
+fn test_parse_ptr_response() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/ptr_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.questions[0].qtype, QueryType::PTR);
+    assert_eq!(
+        packet.answers[0],
+        Record::PTR {
+            name: String::from("4.4.8.8.in-addr.arpa."),
+            class: 1,
+            ttl: 21599,
+            host: String::from("dns.google."),
+        }
+    );
+    assert_eq!(
+        packet.answers[0].to_string(),
+        "4.4.8.8.in-addr.arpa. 21599 IN PTR dns.google."
+    );
+    check_counts(&packet);
+    check_round_trip(&packet);
+}
+
+#[test]
+fn test_parse_txt_response() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/txt_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.answers.len(), 2);
+    match &packet.answers[1] {
+        Record::TXT { data, .. } => {
+            assert_eq!(data.len(), 2);
+            assert_eq!(data[0], b"google-site-verification=abc123".to_vec());
+            assert_eq!(data[1], b"second!".to_vec());
+        }
+        _ => panic!("unexpected record type"),
+    }
+    assert_eq!(
+        packet.answers[0].to_string(),
+        "example.com. 300 IN TXT \"v=spf1 -all\""
+    );
+    check_counts(&packet);
+    check_round_trip(&packet);
+}
+
+#[test]
+fn test_parse_srv_response() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/srv_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(
+        packet.answers[0],
+        Record::SRV {
+            name: String::from("_sip._tcp.example.com."),
+            class: 1,
+            ttl: 86400,
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            target: String::from("sipserver.example.com."),
+        }
+    );
+    assert_eq!(
+        packet.answers[1].to_string(),
+        "_sip._tcp.example.com. 86400 IN SRV 20 0 5060 backup.example.com."
+    );
+    check_counts(&packet);
+    check_round_trip(&packet);
+}
+
+fn check_round_trip(packet: &Packet) {
+    let mut buffer = BytePacketBuffer::new_empty();
+    packet.write(&mut buffer).unwrap();
+    let new_packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(packet.answers, new_packet.answers);
+    assert_eq!(packet.authority, new_packet.authority);
+    assert_eq!(packet.additional, new_packet.additional);
+}
+
+#[test]
+fn test_parse_unknown_type() {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/unknown_response.txt"));
+    let packet = Packet::read(&mut buffer).unwrap();
+    assert_eq!(
+        packet.answers[0],
+        Record::UNKNOWN {
+            name: String::from("example.com."),
+            rtype: 65,
+            class: 1,
+            ttl: 300,
+            data: vec![0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x03, 0x02, 0x68, 0x32],
+        }
+    );
+    assert_eq!(
+        packet.answers[0].to_string(),
+        "example.com. 300 IN TYPE65 \\# 10 00010000010003026832"
+    );
+    // the record after the unknown one is read from the right offset
+    assert_eq!(
+        packet.answers[1].to_string(),
+        "example.com. 300 IN A 93.184.216.34"
+    );
+    check_counts(&packet);
+    check_round_trip(&packet);
+}
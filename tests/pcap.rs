@@ -0,0 +1,450 @@
+use druns::buffer::ParseError;
+use druns::lookup::Protocol;
+use druns::packet::{QueryType, ResponseCode};
+use druns::pcap::{self, Problem};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const CLIENT4: [u8; 4] = [192, 168, 1, 10];
+const SERVER4: [u8; 4] = [192, 168, 1, 1];
+
+fn client6() -> Ipv6Addr {
+    "2001:db8::10".parse().unwrap()
+}
+
+fn server6() -> Ipv6Addr {
+    "2001:db8::1".parse().unwrap()
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    fs::read(format!("tests/{}.txt", name)).unwrap()
+}
+
+fn udp(source: u16, destination: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = vec![];
+    udp.extend_from_slice(&source.to_be_bytes());
+    udp.extend_from_slice(&destination.to_be_bytes());
+    udp.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    udp
+}
+
+/// flags 0x02 for SYN, 0x18 for PSH ACK
+fn tcp(source: u16, destination: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = vec![];
+    tcp.extend_from_slice(&source.to_be_bytes());
+    tcp.extend_from_slice(&destination.to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+    tcp
+}
+
+/// fragment holds the flags and offset field
+fn ipv4(
+    source: [u8; 4],
+    destination: [u8; 4],
+    protocol: u8,
+    fragment: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut ip = vec![0x45, 0];
+    ip.extend_from_slice(&(20 + data.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&[0, 1]);
+    ip.extend_from_slice(&fragment.to_be_bytes());
+    ip.extend_from_slice(&[64, protocol, 0, 0]);
+    ip.extend_from_slice(&source);
+    ip.extend_from_slice(&destination);
+    ip.extend_from_slice(data);
+    ip
+}
+
+/// with a hop by hop options header in front of the payload
+fn ipv6(source: Ipv6Addr, destination: Ipv6Addr, protocol: u8, data: &[u8]) -> Vec<u8> {
+    let mut ip = vec![0x60, 0, 0, 0];
+    ip.extend_from_slice(&(8 + data.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&[0, 64]);
+    ip.extend_from_slice(&source.octets());
+    ip.extend_from_slice(&destination.octets());
+    ip.extend_from_slice(&[protocol, 0, 1, 4, 0, 0, 0, 0]);
+    ip.extend_from_slice(data);
+    ip
+}
+
+fn ethernet(ethertype: u16, vlan: bool, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0xaa; 12];
+    if vlan {
+        frame.extend_from_slice(&[0x81, 0x00, 0x00, 0x64]);
+    }
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+fn pcap_file(linktype: u32, big_endian: bool, frames: &[Vec<u8>]) -> Vec<u8> {
+    let u16 = |x: u16| {
+        if big_endian {
+            x.to_be_bytes()
+        } else {
+            x.to_le_bytes()
+        }
+    };
+    let u32 = |x: u32| {
+        if big_endian {
+            x.to_be_bytes()
+        } else {
+            x.to_le_bytes()
+        }
+    };
+    let mut file = vec![];
+    file.extend_from_slice(&u32(0xa1b2_c3d4));
+    file.extend_from_slice(&u16(2));
+    file.extend_from_slice(&u16(4));
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&u32(65535));
+    file.extend_from_slice(&u32(linktype));
+    for (i, frame) in frames.iter().enumerate() {
+        file.extend_from_slice(&u32(1_600_000_000 + i as u32));
+        file.extend_from_slice(&u32(0));
+        file.extend_from_slice(&u32(frame.len() as u32));
+        file.extend_from_slice(&u32(frame.len() as u32));
+        file.extend_from_slice(frame);
+    }
+    file
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let mut body = body.to_vec();
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+    let length = (12 + body.len() as u32).to_le_bytes();
+    let mut block = block_type.to_le_bytes().to_vec();
+    block.extend_from_slice(&length);
+    block.extend_from_slice(&body);
+    block.extend_from_slice(&length);
+    block
+}
+
+/// a little endian pcapng file with one interface, holding enhanced packet
+/// blocks
+fn pcapng_file(linktype: u16, frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut shb = 0x1a2b_3c4d_u32.to_le_bytes().to_vec();
+    shb.extend_from_slice(&[1, 0, 0, 0]);
+    shb.extend_from_slice(&u64::MAX.to_le_bytes());
+    let mut idb = linktype.to_le_bytes().to_vec();
+    idb.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    let mut file = block(0x0a0d_0d0a, &shb);
+    file.extend(block(1, &idb));
+    // a name resolution block, which is skipped
+    file.extend(block(4, &[0, 0, 0, 0]));
+    for frame in frames.iter() {
+        let mut epb = vec![0; 12];
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(frame);
+        file.extend(block(6, &epb));
+    }
+    file
+}
+
+fn with_length(message: &[u8]) -> Vec<u8> {
+    let mut data = (message.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(message);
+    data
+}
+
+fn v4(ip: [u8; 4], port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::from(Ipv4Addr::from(ip)), port)
+}
+
+#[test]
+fn test_pcap_udp() {
+    let request = fixture("google_request");
+    let response = fixture("google_response");
+    let frames = vec![
+        ethernet(
+            0x0800,
+            false,
+            &ipv4(CLIENT4, SERVER4, 17, 0, &udp(5353, 53, &request)),
+        ),
+        // not DNS
+        ethernet(
+            0x0800,
+            false,
+            &ipv4(CLIENT4, SERVER4, 17, 0, &udp(5353, 123, &[1; 48])),
+        ),
+        ethernet(0x0806, false, &[0; 28]),
+        ethernet(
+            0x0800,
+            false,
+            &ipv4(SERVER4, CLIENT4, 17, 0, &udp(53, 5353, &response)),
+        ),
+    ];
+
+    for big_endian in [false, true].iter() {
+        let capture = pcap::parse(&pcap_file(1, *big_endian, &frames)).unwrap();
+        assert_eq!(capture.frames, 4);
+        assert!(capture.problems.is_empty());
+        assert_eq!(capture.payloads.len(), 2);
+        let query = &capture.payloads[0];
+        assert_eq!(query.frame, 1);
+        assert_eq!(query.source, v4(CLIENT4, 5353));
+        assert_eq!(query.destination, v4(SERVER4, 53));
+        assert_eq!(query.protocol, Protocol::Udp);
+        assert_eq!(query.data, request);
+        assert_eq!(capture.payloads[1].frame, 4);
+        assert_eq!(capture.payloads[1].data, response);
+    }
+
+    // the same packets without a link layer header
+    let raw: Vec<Vec<u8>> = frames.iter().map(|x| x[14..].to_vec()).collect();
+    let capture = pcap::parse(&pcap_file(101, false, &raw)).unwrap();
+    assert_eq!(capture.payloads.len(), 2);
+}
+
+#[test]
+fn test_pcapng_tcp_reassembly() {
+    let client = SocketAddr::new(IpAddr::from(client6()), 40000);
+    let server = SocketAddr::new(IpAddr::from(server6()), 53);
+    let request = with_length(&fixture("google_request"));
+    let mut responses = with_length(&fixture("srv_response"));
+    responses.extend(with_length(&fixture("txt_response")));
+
+    let segment = |from_server: bool, seq: u32, flags: u8, data: &[u8]| {
+        let (source, destination, ports) = if from_server {
+            (server6(), client6(), (53, 40000))
+        } else {
+            (client6(), server6(), (40000, 53))
+        };
+        let segment = tcp(ports.0, ports.1, seq, flags, data);
+        ethernet(0x86dd, true, &ipv6(source, destination, 6, &segment))
+    };
+    let frames = vec![
+        segment(false, 999, 0x02, &[]),
+        segment(true, 4999, 0x12, &[]),
+        segment(false, 1000, 0x18, &request[..10]),
+        segment(false, 1010, 0x18, &request[10..]),
+        // second half first, then the first half twice
+        segment(true, 5000 + 100, 0x18, &responses[100..]),
+        segment(true, 5000, 0x18, &responses[..100]),
+        segment(true, 5000, 0x18, &responses[..100]),
+    ];
+
+    let capture = pcap::parse(&pcapng_file(1, &frames)).unwrap();
+    assert_eq!(capture.frames, 7);
+    assert!(capture.problems.is_empty(), "{:?}", capture.problems);
+    let summary: Vec<(usize, SocketAddr, Protocol)> = capture
+        .payloads
+        .iter()
+        .map(|x| (x.frame, x.source, x.protocol))
+        .collect();
+    // each message comes from the frame that completed the stream up to its
+    // end, though the second response was all there after frame 5
+    assert_eq!(
+        summary,
+        vec![
+            (4, client, Protocol::Tcp),
+            (6, server, Protocol::Tcp),
+            (6, server, Protocol::Tcp),
+        ]
+    );
+    assert_eq!(capture.payloads[0].data, fixture("google_request"));
+    assert_eq!(capture.payloads[1].data, fixture("srv_response"));
+    assert_eq!(capture.payloads[2].data, fixture("txt_response"));
+}
+
+#[test]
+fn test_tcp_problems() {
+    let response = with_length(&fixture("google_response"));
+    let flow = |seq: u32, data: &[u8]| {
+        let segment = tcp(53, 40000, seq, 0x18, data);
+        ipv4(SERVER4, CLIENT4, 6, 0, &segment)
+    };
+
+    // no SYN, so the stream starts at the first segment seen
+    let capture = pcap::parse(&pcap_file(101, false, &[flow(7, &response[..20])])).unwrap();
+    assert!(capture.payloads.is_empty());
+    assert_eq!(
+        capture.problems,
+        vec![Problem {
+            frame: 1,
+            message: "incomplete message at the end of TCP stream \
+                      192.168.1.1:53 -> 192.168.1.10:40000"
+                .to_string()
+        }]
+    );
+
+    let frames = vec![
+        flow(7, &response),
+        flow(7 + response.len() as u32 + 5, &response),
+    ];
+    let capture = pcap::parse(&pcap_file(101, false, &frames)).unwrap();
+    assert_eq!(capture.payloads.len(), 1);
+    assert_eq!(
+        capture.problems[0].message,
+        "gap in TCP stream 192.168.1.1:53 -> 192.168.1.10:40000 after 57 bytes"
+    );
+}
+
+#[test]
+fn test_ip_problems() {
+    let response = fixture("google_response");
+    let datagram = udp(53, 5353, &response);
+    let mut truncated = ipv4(SERVER4, CLIENT4, 17, 0, &datagram);
+    truncated.truncate(40);
+    let frames = vec![
+        // more fragments, then a later fragment with no ports to go by
+        ipv4(SERVER4, CLIENT4, 17, 0x2000, &datagram[..24]),
+        ipv4(SERVER4, CLIENT4, 17, 3, &datagram[24..]),
+        truncated,
+    ];
+    let capture = pcap::parse(&pcap_file(101, false, &frames)).unwrap();
+    assert!(capture.payloads.is_empty());
+    let problems: Vec<(usize, &str)> = capture
+        .problems
+        .iter()
+        .map(|x| (x.frame, x.message.as_str()))
+        .collect();
+    assert_eq!(
+        problems,
+        vec![
+            (1, "fragmented IPv4 packet from 192.168.1.1, skipped"),
+            (3, "captured 40 of 83 IPv4 bytes"),
+        ]
+    );
+
+    // reported once, not for every frame
+    let capture = pcap::parse(&pcap_file(147, false, &[vec![0; 40], vec![0; 40]])).unwrap();
+    assert_eq!(capture.problems.len(), 1);
+    assert_eq!(capture.problems[0].message, "unsupported link type 147");
+}
+
+#[test]
+fn test_bad_files() {
+    let error = |bytes: &[u8]| pcap::parse(bytes).unwrap_err().to_string();
+    assert_eq!(error(b"ab"), "not a pcap or pcapng file");
+    assert_eq!(error(b"<html></html>"), "not a pcap or pcapng file");
+
+    let mut file = pcap_file(1, false, &[vec![0; 60]]);
+    file.truncate(file.len() - 1);
+    assert_eq!(error(&file), "truncated packet record at offset 24");
+
+    let mut file = pcapng_file(1, &[]);
+    file[8] = 0;
+    assert_eq!(error(&file), "bad byte order magic at offset 8");
+
+    // a packet before any interface was described
+    let mut file = block(0x0a0d_0d0a, &[0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0]);
+    file.extend(block(6, &[0; 20]));
+    assert_eq!(error(&file), "unknown interface 0 in block at offset 20");
+
+    assert!(pcap::read("tests/missing.pcap".as_ref())
+        .unwrap_err()
+        .to_string()
+        .starts_with("tests/missing.pcap: "));
+}
+
+#[test]
+fn test_report() {
+    let mut broken = fixture("soa_response");
+    broken.truncate(40);
+    let exchanges = [
+        ("google_request", false),
+        ("google_response", true),
+        ("soa_response", true),
+        ("srv_response", true),
+        ("txt_response", true),
+    ];
+    let mut frames: Vec<Vec<u8>> = exchanges
+        .iter()
+        .map(|(name, from_server)| {
+            let (source, destination, ports) = if *from_server {
+                (SERVER4, CLIENT4, (53, 5353))
+            } else {
+                (CLIENT4, SERVER4, (5353, 53))
+            };
+            ipv4(
+                source,
+                destination,
+                17,
+                0,
+                &udp(ports.0, ports.1, &fixture(name)),
+            )
+        })
+        .collect();
+    frames.push(ipv4(SERVER4, CLIENT4, 17, 0, &udp(53, 5353, &broken)));
+
+    let report = pcap::report(&pcap::parse(&pcap_file(101, false, &frames)).unwrap());
+    assert_eq!((report.frames, report.queries, report.responses), (6, 1, 4));
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].frame, 6);
+    assert_eq!(report.failures[0].source, v4(SERVER4, 53));
+    assert_eq!(
+        report.failures[0].error,
+        ParseError::UnexpectedEnd { offset: 40 }
+    );
+
+    let count = |map: &std::collections::BTreeMap<u16, usize>, key: u16| map.get(&key).copied();
+    assert_eq!(count(&report.query_types, QueryType::A.to_num()), Some(1));
+    assert_eq!(report.query_types.len(), 1);
+    assert_eq!(count(&report.record_types, QueryType::A.to_num()), Some(1));
+    assert_eq!(
+        count(&report.record_types, QueryType::SRV.to_num()),
+        Some(2)
+    );
+    assert_eq!(
+        count(&report.record_types, QueryType::TXT.to_num()),
+        Some(2)
+    );
+    assert_eq!(
+        count(&report.record_types, QueryType::SOA.to_num()),
+        Some(1)
+    );
+    // both google messages carry EDNS
+    assert_eq!(count(&report.record_types, 41), Some(2));
+    assert_eq!(
+        count(&report.rcodes, u16::from(&ResponseCode::no_error)),
+        Some(3)
+    );
+    assert_eq!(
+        count(&report.rcodes, u16::from(&ResponseCode::nx_domain)),
+        Some(1)
+    );
+    let mut sizes: Vec<usize> = exchanges[1..]
+        .iter()
+        .map(|(name, _)| fixture(name).len())
+        .collect();
+    sizes.sort_unstable();
+    assert_eq!(report.response_sizes, sizes);
+
+    let text = report.to_string();
+    assert!(text.starts_with(
+        "frames: 6, messages: 6 (1 queries, 4 responses, 1 unparsed)\n\nparse failures: 1\n  \
+         frame 6 (udp from 192.168.1.1:53): unexpected end of message at offset 40\n"
+    ));
+    assert!(text.contains("\nresponse codes:\n  NOERROR    3\n  NXDOMAIN   1\n"));
+    assert!(text.contains("\nrecord types:\n  OPT        2\n  SRV        2\n  TXT        2\n"));
+    assert!(text.contains("      1-512   4\n"));
+    assert!(!text.contains("capture problems"));
+}
+
+#[test]
+fn test_report_extended_rcode() {
+    // 1 in the OPT record's upper bits and 0 in the header make 16, BADVERS
+    let mut bytes = fixture("google_response");
+    let len = bytes.len();
+    assert_eq!(&bytes[len - 11..len - 8], &[0, 0, 41]);
+    bytes[len - 6] = 1;
+    let frames = vec![ipv4(SERVER4, CLIENT4, 17, 0, &udp(53, 5353, &bytes))];
+
+    let report = pcap::report(&pcap::parse(&pcap_file(101, false, &frames)).unwrap());
+    assert_eq!(report.rcodes.get(&16), Some(&1));
+    assert!(report
+        .to_string()
+        .contains("\nresponse codes:\n  BADVERS    1\n"));
+}
@@ -0,0 +1,178 @@
+use druns::pool::Pool;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Barrier, Mutex,
+    },
+    time::Duration,
+};
+
+fn client(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+}
+
+/// keeps every worker of a pool busy until the returned barrier is waited on
+fn block_workers(pool: &Pool, workers: usize) -> Arc<Barrier> {
+    let started = Arc::new(Barrier::new(workers + 1));
+    let release = Arc::new(Barrier::new(workers + 1));
+    for i in 0..workers {
+        let started = started.clone();
+        let release = release.clone();
+        assert!(pool.submit(
+            client(100 + i as u8),
+            Box::new(move || {
+                started.wait();
+                release.wait();
+            })
+        ));
+    }
+    started.wait();
+    release
+}
+
+#[test]
+fn test_jobs_run_concurrently() {
+    let pool = Pool::new(4, 16, 16, 4);
+    // every job waits for all of the others, so this only finishes if they
+    // run at the same time
+    let barrier = Arc::new(Barrier::new(4));
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..4 {
+        let barrier = barrier.clone();
+        let sender = sender.clone();
+        assert!(pool.submit(
+            client(i),
+            Box::new(move || {
+                barrier.wait();
+                sender.send(i).unwrap();
+            })
+        ));
+    }
+    for _ in 0..4 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
+
+#[test]
+fn test_in_flight_capped() {
+    let pool = Pool::new(2, 16, 16, 2);
+    let running = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..10 {
+        let running = running.clone();
+        let most = most.clone();
+        let sender = sender.clone();
+        assert!(pool.submit(
+            client(i),
+            Box::new(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                sender.send(()).unwrap();
+            })
+        ));
+    }
+    for _ in 0..10 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    assert!(most.load(Ordering::SeqCst) <= 2);
+}
+
+#[test]
+fn test_client_cannot_take_every_worker() {
+    let pool = Pool::new(4, 16, 16, 2);
+    let started = Arc::new(AtomicUsize::new(0));
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    for _ in 0..4 {
+        let started = started.clone();
+        let released = released.clone();
+        assert!(pool.submit(
+            client(1),
+            Box::new(move || {
+                started.fetch_add(1, Ordering::SeqCst);
+                released.lock().unwrap().recv().unwrap();
+            })
+        ));
+    }
+
+    // two workers are left for everyone else while client 1's jobs hang
+    let (sender, receiver) = mpsc::channel();
+    for i in 2..4 {
+        let sender = sender.clone();
+        assert!(pool.submit(client(i), Box::new(move || sender.send(i).unwrap())));
+    }
+    for _ in 2..4 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    assert_eq!(started.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.len(), 2);
+
+    // the rest of client 1's jobs run as the first ones finish
+    for _ in 0..4 {
+        release.send(()).unwrap();
+    }
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while started.load(Ordering::SeqCst) < 4 {
+        assert!(std::time::Instant::now() < deadline);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_queue_limits() {
+    let pool = Pool::new(1, 3, 2, 1);
+    let release = block_workers(&pool, 1);
+
+    assert!(pool.submit(client(1), Box::new(|| {})));
+    assert!(pool.submit(client(1), Box::new(|| {})));
+    // this client already has as many waiting as it may
+    assert!(!pool.submit(client(1), Box::new(|| {})));
+    assert!(pool.submit(client(2), Box::new(|| {})));
+    // and now the queue is full
+    assert!(!pool.submit(client(3), Box::new(|| {})));
+    assert_eq!(pool.len(), 3);
+
+    release.wait();
+}
+
+#[test]
+fn test_clients_served_in_turn() {
+    let pool = Pool::new(1, 16, 16, 1);
+    let release = block_workers(&pool, 1);
+
+    let order = Arc::new(Mutex::new(vec![]));
+    let (sender, receiver) = mpsc::channel();
+    let jobs = [1, 1, 1, 1, 2, 3];
+    for &i in jobs.iter() {
+        let order = order.clone();
+        let sender = sender.clone();
+        assert!(pool.submit(
+            client(i),
+            Box::new(move || {
+                order.lock().unwrap().push(i);
+                sender.send(()).unwrap();
+            })
+        ));
+    }
+    release.wait();
+    for _ in jobs.iter() {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    // the busy client doesn't get to go again until the others had a turn
+    assert_eq!(*order.lock().unwrap(), vec![1, 2, 3, 1, 1, 1]);
+}
+
+#[test]
+fn test_worker_survives_panic() {
+    let pool = Pool::new(1, 16, 16, 1);
+    assert!(pool.submit(client(1), Box::new(|| panic!("job failed"))));
+
+    let (sender, receiver) = mpsc::channel();
+    assert!(pool.submit(client(1), Box::new(move || sender.send(()).unwrap())));
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+}
@@ -0,0 +1,246 @@
+mod common;
+
+use common::*;
+use druns::buffer::BytePacketBuffer;
+use druns::lookup::create_request_packet;
+use druns::packet::{EdnsOption, Packet, PacketType, QueryType, Record, ResponseCode};
+use druns::zone::Zone;
+use std::{fs, str::FromStr};
+
+fn records() -> Vec<Record> {
+    vec![
+        a("www.example.com.", [192, 0, 2, 1]),
+        ns("example.com.", "ns1.example.com."),
+        cname("alias.example.com.", "www.example.com."),
+        soa("example.com.", 3600, 300),
+        Record::PTR {
+            name: "1.2.0.192.in-addr.arpa.".to_string(),
+            class: 1,
+            ttl: 86400,
+            host: "www.example.com.".to_string(),
+        },
+        Record::MX {
+            name: "example.com.".to_string(),
+            class: 1,
+            priority: 10,
+            host: "mail.example.com.".to_string(),
+            ttl: 300,
+        },
+        Record::TXT {
+            name: "example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            data: vec![b"v=spf1 -all".to_vec(), b"\"quoted\" \\ \x00\xff;".to_vec()],
+        },
+        Record::AAAA {
+            name: "www.example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            ip: "2001:db8::1".parse().unwrap(),
+        },
+        Record::SRV {
+            name: "_sip._udp.example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            target: "sip.example.com.".to_string(),
+        },
+        Record::UNKNOWN {
+            name: "example.com.".to_string(),
+            rtype: 65400,
+            class: 1,
+            ttl: 300,
+            data: vec![0xde, 0xad, 0xbe, 0xef],
+        },
+        Record::UNKNOWN {
+            name: "".to_string(),
+            rtype: 99,
+            class: 3,
+            ttl: 0,
+            data: vec![],
+        },
+    ]
+}
+
+#[test]
+fn test_record_round_trip() {
+    for record in records() {
+        let text = record.to_string();
+        assert_eq!(Record::from_str(&text), Ok(record), "{}", text);
+    }
+}
+
+#[test]
+fn test_record_text() {
+    let text: Vec<String> = records().iter().map(|x| x.to_string()).collect();
+    assert_eq!(text[0], "www.example.com. 300 IN A 192.0.2.1");
+    assert_eq!(
+        text[3],
+        "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 1 7200 3600 1209600 300"
+    );
+    assert_eq!(
+        text[6],
+        r#"example.com. 300 IN TXT "v=spf1 -all" "\"quoted\" \\ \000\255;""#
+    );
+    assert_eq!(text[9], r"example.com. 300 IN TYPE65400 \# 4 deadbeef");
+    assert_eq!(text[10], r". 0 CH TYPE99 \# 0");
+}
+
+#[test]
+fn test_record_from_str() {
+    // ttl and class are optional, and in either order
+    assert_eq!(
+        "www.example.com. IN 300 A 192.0.2.1".parse(),
+        Ok(a("www.example.com.", [192, 0, 2, 1]))
+    );
+    assert_eq!(
+        "www.example.com. a 192.0.2.1"
+            .parse::<Record>()
+            .unwrap()
+            .ttl(),
+        0
+    );
+    // the way dig prints it, tabs and all
+    assert_eq!(
+        "example.com.\t\t3600\tIN\tNS\tns1.example.com.".parse(),
+        Ok(ns("example.com.", "ns1.example.com."))
+    );
+    assert_eq!(
+        "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. ( 1 2h 1h 2w 5m )"
+            .parse(),
+        Ok(soa("example.com.", 3600, 300))
+    );
+
+    let error = |text: &str| Record::from_str(text).unwrap_err();
+    assert_eq!(error(""), "no record");
+    assert_eq!(error("  IN A 192.0.2.1"), "missing owner name");
+    assert_eq!(error("$TTL 300"), "$TTL is a directive, not a record");
+    assert_eq!(
+        error("www. 300 IN A"),
+        "wrong number of fields for a A record"
+    );
+    assert_eq!(
+        error("www. 300 IN A 192.0.2.1\nwww. 300 IN A 192.0.2.2"),
+        "more than one record"
+    );
+    assert_eq!(error("www. 300 IN BOGUS x"), "unknown record type BOGUS");
+}
+
+#[test]
+fn test_escaped_names() {
+    // a\032b is a single label holding a space, however it's written
+    let record: Record = r"a\032b.example. 300 IN A 192.0.2.1".parse().unwrap();
+    assert_eq!(record.name(), r"a\032b.example.");
+    assert_eq!(record.to_string(), r"a\032b.example. 300 IN A 192.0.2.1");
+    assert_eq!(Record::from_str(&record.to_string()), Ok(record.clone()));
+    assert_eq!(
+        r"a\ b.example. 300 IN A 192.0.2.1".parse(),
+        Ok(record.clone())
+    );
+
+    let mut packet = Packet::new();
+    packet.answers.push(record.clone());
+    packet.update_counts();
+    let mut buffer = BytePacketBuffer::new_empty();
+    packet.write(&mut buffer).unwrap();
+    assert_eq!(&buffer[12..16], b"\x03a b");
+    buffer.reset_for_read();
+    assert_eq!(Packet::read(&mut buffer).unwrap().answers, vec![record]);
+
+    // characters which mean something in a master file come back escaped
+    let zone = Zone::parse(
+        "$TTL 300\n\
+         @ SOA ns1 hostmaster 1 2h 15m 1w 300\n\
+         semi\\;colon A 192.0.2.1\n\
+         dot\\.ted CNAME \\040paren\\)\n",
+        "example.",
+    )
+    .unwrap();
+    let text = zone.to_string();
+    assert!(text.contains(r"semi\;colon.example. 300 IN A 192.0.2.1"));
+    assert!(text.contains(r"dot\.ted.example. 300 IN CNAME \(paren\).example."));
+    assert_eq!(Zone::parse(&text, "example.").unwrap(), zone);
+}
+
+#[test]
+fn test_packet_text() {
+    let mut packet = Packet::new();
+    packet.header.id = 4660;
+    packet.header.qr = PacketType::Response;
+    packet.header.recursion_desired = true;
+    packet.header.recursion_available = true;
+    packet.header.rcode = ResponseCode::nx_domain;
+    packet.questions = create_request_packet("www.example.com.", QueryType::A).questions;
+    packet.authority = vec![soa("example.com.", 3600, 300)];
+    packet.set_edns(Some(Record::OPT {
+        udp_payload_size: 1232,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok: true,
+        options: vec![EdnsOption {
+            code: 10,
+            data: vec![1, 2],
+        }],
+    }));
+
+    assert_eq!(
+        packet.to_string(),
+        "\
+;; opcode: QUERY, status: NXDOMAIN, id: 4660
+;; flags: qr rd ra
+;; QUERY: 1, ANSWER: 0, AUTHORITY: 1, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags: do; udp: 1232; option 10: 0102
+
+;; QUESTION SECTION:
+;www.example.com. IN A
+
+;; AUTHORITY SECTION:
+example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 1 7200 3600 1209600 300
+"
+    );
+}
+
+const ZONE: &str = "
+$ORIGIN example.com.
+$TTL 300
+@       SOA     ns1 hostmaster 1 7200 900 604800 300
+        NS      ns1
+        MX      10 mail
+ns1     A       192.0.2.1
+mail    A       192.0.2.2
+        TXT     \"a \\\"quoted\\\" string\"
+*.dyn   AAAA    2001:db8::5
+sub     NS      ns.sub
+ns.sub  A       192.0.2.8
+";
+
+#[test]
+fn test_zone_round_trip() {
+    let zone = Zone::parse(ZONE, "example.com.").unwrap();
+    let text = zone.to_string();
+    assert_eq!(
+        text,
+        "\
+$ORIGIN example.com.
+example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 1 7200 900 604800 300
+example.com. 300 IN NS ns1.example.com.
+example.com. 300 IN MX 10 mail.example.com.
+*.dyn.example.com. 300 IN AAAA 2001:db8::5
+mail.example.com. 300 IN A 192.0.2.2
+mail.example.com. 300 IN TXT \"a \\\"quoted\\\" string\"
+ns1.example.com. 300 IN A 192.0.2.1
+sub.example.com. 300 IN NS ns.sub.example.com.
+ns.sub.example.com. 300 IN A 192.0.2.8
+"
+    );
+    assert_eq!(Zone::parse(&text, "example.com.").unwrap(), zone);
+
+    let path = std::env::temp_dir().join(format!("druns-{}.zone", std::process::id()));
+    zone.save(&path).unwrap();
+    assert_eq!(Zone::load(&path, "example.com.").unwrap(), zone);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,481 @@
+mod common;
+
+use common::*;
+use druns::buffer::BytePacketBuffer;
+use druns::packet::{Packet, QueryType, Record, ResponseCode};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn test_answers_served_from_cache() {
+    let port = free_port();
+    let [root, com, example] = spawn_hierarchy(port);
+    let resolver = resolver(port);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+    assert_eq!((count(&root), count(&com), count(&example)), (1, 1, 1));
+
+    // nothing goes upstream the second time
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    match &response.answers[0] {
+        Record::A { ip, ttl, .. } => {
+            assert_eq!(ip, &[10, 0, 0, 1]);
+            assert!(*ttl <= 300);
+        }
+        _ => panic!("unexpected record type"),
+    }
+    assert_eq!((count(&root), count(&com), count(&example)), (1, 1, 1));
+}
+
+#[test]
+fn test_resolution_starts_at_cached_delegation() {
+    let port = free_port();
+    let [root, com, example] = spawn_hierarchy(port);
+    let resolver = resolver(port);
+
+    query(&resolver, "www.example.com.", QueryType::A);
+    let response = query(&resolver, "mail.example.com.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![a("mail.example.com.", [10, 0, 0, 1])]
+    );
+    // example.com. servers were cached from the first referral chain
+    assert_eq!((count(&root), count(&com), count(&example)), (1, 1, 2));
+}
+
+fn negative_zone() -> Handler {
+    Box::new(|request| {
+        let name = question_name(request);
+        if name.starts_with("nope.") {
+            Some(negative(request, ResponseCode::nx_domain, "example.com."))
+        } else if request.questions[0].qtype == QueryType::MX {
+            Some(negative(request, ResponseCode::no_error, "example.com."))
+        } else {
+            Some(answer(request, vec![a(&name, [10, 0, 0, 1])]))
+        }
+    })
+}
+
+#[test]
+fn test_nxdomain_cached() {
+    let port = free_port();
+    let [_, _, example] = spawn_hierarchy_with(port, negative_zone());
+    let resolver = resolver(port);
+
+    let response = query(&resolver, "nope.example.com.", QueryType::A);
+    assert_eq!(response.header.rcode, ResponseCode::nx_domain);
+    assert_eq!(count(&example), 1);
+
+    // NXDOMAIN covers every type of the name
+    let response = query(&resolver, "nope.example.com.", QueryType::AAAA);
+    assert_eq!(response.header.rcode, ResponseCode::nx_domain);
+    assert!(response.answers.is_empty());
+    match &response.authority[0] {
+        // ttl is capped by the SOA minimum
+        Record::SOA { name, ttl, .. } => {
+            assert_eq!(name, "example.com.");
+            assert!(*ttl <= 600);
+        }
+        _ => panic!("expected SOA in authority"),
+    }
+    assert_eq!(count(&example), 1);
+}
+
+#[test]
+fn test_nodata_cached() {
+    let port = free_port();
+    let [_, _, example] = spawn_hierarchy_with(port, negative_zone());
+    let resolver = resolver(port);
+
+    for _ in 0..2 {
+        let response = query(&resolver, "www.example.com.", QueryType::MX);
+        assert_eq!(response.header.rcode, ResponseCode::no_error);
+        assert!(response.answers.is_empty());
+        assert!(matches!(response.authority[0], Record::SOA { .. }));
+    }
+    assert_eq!(count(&example), 1);
+
+    // only MX is known to be missing
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(count(&example), 2);
+}
+
+fn alias_zone() -> Handler {
+    Box::new(|request| {
+        let name = question_name(request);
+        let records = match name.as_str() {
+            // target in another zone, so it isn't part of the answer
+            "www.example.com." => vec![cname(&name, "alias.example.net.")],
+            // target in the same zone comes along
+            "in.example.com." => vec![
+                cname(&name, "host.example.com."),
+                a("host.example.com.", [10, 0, 0, 2]),
+            ],
+            "loop1.example.com." => vec![cname(&name, "loop2.example.com.")],
+            "loop2.example.com." => vec![cname(&name, "loop1.example.com.")],
+            _ => vec![a(&name, [10, 0, 0, 1])],
+        };
+        Some(answer(request, records))
+    })
+}
+
+fn net_zone() -> Handler {
+    Box::new(|request| {
+        let name = question_name(request);
+        Some(answer(request, vec![a(&name, [10, 0, 1, 1])]))
+    })
+}
+
+#[test]
+fn test_cname_across_zones() {
+    let port = free_port();
+    let [_, _, example] = spawn_hierarchy_with(port, alias_zone());
+    let net = spawn_net(port, net_zone());
+    let resolver = resolver(port);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![
+            cname("www.example.com.", "alias.example.net."),
+            a("alias.example.net.", [10, 0, 1, 1]),
+        ]
+    );
+    assert_eq!((count(&example), count(&net)), (1, 1));
+
+    // whole chain comes from the cache the second time
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers.len(), 2);
+    assert_eq!((count(&example), count(&net)), (1, 1));
+}
+
+#[test]
+fn test_cname_within_answer() {
+    let port = free_port();
+    let [_, _, example] = spawn_hierarchy_with(port, alias_zone());
+    let resolver = resolver(port);
+
+    let response = query(&resolver, "in.example.com.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![
+            cname("in.example.com.", "host.example.com."),
+            a("host.example.com.", [10, 0, 0, 2]),
+        ]
+    );
+    assert_eq!(count(&example), 1);
+}
+
+#[test]
+fn test_cname_partly_cached() {
+    let port = free_port();
+    let [_, _, example] = spawn_hierarchy_with(port, alias_zone());
+    let net = spawn_net(port, net_zone());
+    let resolver = resolver(port);
+
+    // only the alias itself ends up in the cache
+    let response = query(&resolver, "www.example.com.", QueryType::CNAME);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!((count(&example), count(&net)), (1, 0));
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers.len(), 2);
+    assert_eq!((count(&example), count(&net)), (1, 1));
+}
+
+#[test]
+fn test_cname_loop() {
+    let port = free_port();
+    spawn_hierarchy_with(port, alias_zone());
+    let resolver = resolver(port);
+    let response = query(&resolver, "loop1.example.com.", QueryType::A);
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+}
+
+fn glueless_referral(request: &Packet, zone: &str, host: &str) -> Packet {
+    let mut packet = referral(request, zone, host, [0, 0, 0, 0]);
+    packet.additional.clear();
+    packet.update_counts();
+    packet
+}
+
+#[test]
+fn test_glueless_delegation() {
+    let port = free_port();
+    // root hands out glueless.com. with a nameserver under net. and no glue
+    let root = spawn_server(
+        "127.0.0.5",
+        port,
+        Box::new(|request| {
+            if question_name(request).ends_with(".net.") {
+                Some(referral(request, "net.", "a.gtld.net.", [127, 0, 0, 4]))
+            } else {
+                Some(glueless_referral(
+                    request,
+                    "glueless.com.",
+                    "ns1.example.net.",
+                ))
+            }
+        }),
+    );
+    let net = spawn_net(
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [127, 0, 0, 6])]))
+        }),
+    );
+    let glueless = spawn_server(
+        "127.0.0.6",
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [10, 0, 2, 1])]))
+        }),
+    );
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 5)];
+
+    let response = query(&resolver, "www.glueless.com.", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![a("www.glueless.com.", [10, 0, 2, 1])]
+    );
+    assert_eq!((count(&root), count(&net), count(&glueless)), (2, 1, 1));
+}
+
+#[test]
+fn test_glueless_loop() {
+    let port = free_port();
+    // a.com. is served by ns.b.com. and b.com. by ns.a.com., neither with glue
+    spawn_server(
+        "127.0.0.5",
+        port,
+        Box::new(|request| {
+            if question_name(request).ends_with("a.com.") {
+                Some(glueless_referral(request, "a.com.", "ns.b.com."))
+            } else {
+                Some(glueless_referral(request, "b.com.", "ns.a.com."))
+            }
+        }),
+    );
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 5)];
+
+    let response = query(&resolver, "www.a.com.", QueryType::A);
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+}
+
+fn silent() -> Handler {
+    Box::new(|_| None)
+}
+
+#[test]
+fn test_failover_after_timeout() {
+    let port = free_port();
+    let dead = spawn_server("127.0.0.7", port, silent());
+    let [root, _, _] = spawn_hierarchy(port);
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 7), Ipv4Addr::new(127, 0, 0, 1)];
+    resolver.timeout = Duration::from_millis(200);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+    // first try plus one retry before moving on
+    assert_eq!((count(&dead), count(&root)), (2, 1));
+}
+
+#[test]
+fn test_failover_after_servfail() {
+    let port = free_port();
+    let broken = spawn_server(
+        "127.0.0.8",
+        port,
+        Box::new(|request| {
+            let mut packet = response(request);
+            packet.header.rcode = ResponseCode::serv_fail;
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    let [root, _, _] = spawn_hierarchy(port);
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 8), Ipv4Addr::new(127, 0, 0, 1)];
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!((count(&broken), count(&root)), (1, 1));
+}
+
+#[test]
+fn test_deadline_exceeded() {
+    let port = free_port();
+    spawn_server("127.0.0.7", port, silent());
+    spawn_server("127.0.0.8", port, silent());
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 7), Ipv4Addr::new(127, 0, 0, 8)];
+    resolver.timeout = Duration::from_millis(200);
+    resolver.retries = 10;
+    resolver.deadline = Duration::from_millis(500);
+
+    let start = Instant::now();
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.header.rcode, ResponseCode::serv_fail);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn test_forged_replies_ignored() {
+    let port = free_port();
+    let server = UdpSocket::bind(("127.0.0.9", port)).unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.10:0").unwrap();
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::with_max_size(4096);
+        let (size, src) = server.recv_from(&mut buffer).unwrap();
+        buffer.size = size;
+        let request = Packet::read(&mut buffer).unwrap();
+        let name = question_name(&request);
+
+        let send = |socket: &UdpSocket, packet: Packet| {
+            let mut buffer = BytePacketBuffer::with_max_size(4096);
+            packet.write(&mut buffer).unwrap();
+            socket.send_to(&buffer[0..buffer.size], src).unwrap();
+        };
+        let forged = || answer(&request, vec![a(&name, [6, 6, 6, 6])]);
+        // right ID and question, wrong source
+        send(&spoofer, forged());
+        // wrong ID
+        let mut packet = forged();
+        packet.header.id = request.header.id.wrapping_add(1);
+        send(&server, packet);
+        // wrong question
+        let mut packet = forged();
+        packet.questions[0].qtype = QueryType::AAAA;
+        send(&server, packet);
+
+        send(&server, answer(&request, vec![a(&name, [10, 0, 0, 1])]));
+    });
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 9)];
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+}
+
+#[test]
+fn test_upstream_ids_vary() {
+    let port = free_port();
+    let ids = Arc::new(Mutex::new(HashSet::new()));
+    let seen = ids.clone();
+    spawn_server(
+        "127.0.0.5",
+        port,
+        Box::new(move |request| {
+            seen.lock().unwrap().insert(request.header.id);
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [10, 0, 0, 1])]))
+        }),
+    );
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 5)];
+
+    for i in 0..8 {
+        query(&resolver, &format!("host{}.example.com.", i), QueryType::A);
+    }
+    assert!(ids.lock().unwrap().len() > 1);
+}
+
+#[test]
+fn test_out_of_bailiwick_dropped() {
+    let port = free_port();
+    spawn_hierarchy_with(
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            let mut packet = answer(
+                request,
+                vec![a(&name, [10, 0, 0, 1]), a("www.victim.org.", [6, 6, 6, 6])],
+            );
+            // claims a zone above its own, with glue for a name it doesn't serve
+            packet.authority = vec![ns("com.", "ns.victim.org.")];
+            packet.additional = vec![
+                a("ns.victim.org.", [6, 6, 6, 6]),
+                a("ns1.example.com.", [6, 6, 6, 6]),
+            ];
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    let resolver = resolver(port);
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+
+    let mut cache = resolver.cache.lock().unwrap();
+    assert!(cache.get("www.victim.org.", QueryType::A, 1).is_none());
+    assert!(cache.get("ns.victim.org.", QueryType::A, 1).is_none());
+    assert_eq!(
+        cache.get("com.", QueryType::NS, 1),
+        Some(vec![ns("com.", "a.gtld.com.")])
+    );
+    // glue only counts when an NS record in the same response asks for it
+    assert_eq!(
+        cache.get("ns1.example.com.", QueryType::A, 1),
+        Some(vec![a("ns1.example.com.", [127, 0, 0, 3])])
+    );
+}
+
+#[test]
+fn test_referral_glue_must_match_ns() {
+    let port = free_port();
+    // com. tries to point example.com. at a server through glue for another name
+    spawn_server(
+        "127.0.0.5",
+        port,
+        Box::new(|request| {
+            if question_name(request).ends_with(".net.") {
+                return Some(referral(request, "net.", "a.gtld.net.", [127, 0, 0, 4]));
+            }
+            let mut packet = referral(request, "example.com.", "ns1.example.net.", [0, 0, 0, 0]);
+            packet.additional = vec![a("evil.example.com.", [127, 0, 0, 9])];
+            packet.update_counts();
+            Some(packet)
+        }),
+    );
+    let evil = spawn_server(
+        "127.0.0.9",
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [6, 6, 6, 6])]))
+        }),
+    );
+    spawn_net(
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [127, 0, 0, 6])]))
+        }),
+    );
+    spawn_server(
+        "127.0.0.6",
+        port,
+        Box::new(|request| {
+            let name = question_name(request);
+            Some(answer(request, vec![a(&name, [10, 0, 0, 1])]))
+        }),
+    );
+    let mut resolver = resolver(port);
+    resolver.root_servers = vec![Ipv4Addr::new(127, 0, 0, 5)];
+
+    let response = query(&resolver, "www.example.com.", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com.", [10, 0, 0, 1])]);
+    assert_eq!(count(&evil), 0);
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use druns::buffer::{BytePacketBuffer, Result};
+use druns::config::{Config, Mode};
+use druns::lookup::{self, create_request_packet};
+use druns::packet::{Packet, QueryType};
+use druns::tcp;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_framing_round_trip() -> Result<()> {
+    let request = BytePacketBuffer::new(String::from("tests/google_request.txt"));
+    let mut stream = vec![];
+    tcp::write_message(&mut stream, &request)?;
+    tcp::write_message(&mut stream, &request)?;
+    assert_eq!(stream.len(), 2 * (request.size + 2));
+    assert_eq!(&stream[0..2], &(request.size as u16).to_be_bytes());
+
+    let mut cursor = Cursor::new(stream);
+    for _ in 0..2 {
+        let mut message = tcp::read_message(&mut cursor)?.unwrap();
+        let packet = Packet::read(&mut message)?;
+        assert_eq!(packet.questions[0].name, "google.com.");
+    }
+    assert!(tcp::read_message(&mut cursor)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_truncated_frame() {
+    // prefix says 39 bytes but only 3 follow
+    let mut cursor = Cursor::new(vec![0, 39, 1, 2, 3]);
+    assert!(tcp::read_message(&mut cursor).is_err());
+}
+
+#[test]
+fn test_several_queries_per_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    // echoes every message back with the QR bit set
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut served = 0;
+        while let Some(mut message) = tcp::read_message(&mut stream).unwrap() {
+            let mut packet = Packet::read(&mut message).unwrap();
+            packet.header.qr = druns::packet::PacketType::Response;
+            let mut response = BytePacketBuffer::new_empty();
+            packet.write(&mut response).unwrap();
+            tcp::write_message(&mut stream, &response).unwrap();
+            served += 1;
+        }
+        served
+    });
+
+    let mut stream = TcpStream::connect(addr)?;
+    for (i, name) in ["a.example.", "b.example.", "c.example."]
+        .iter()
+        .enumerate()
+    {
+        let mut request = create_request_packet(name, QueryType::A);
+        request.header.id = i as u16;
+        let mut buffer = BytePacketBuffer::new_empty();
+        request.write(&mut buffer)?;
+        tcp::write_message(&mut stream, &buffer)?;
+
+        let mut response = tcp::read_message(&mut stream)?.unwrap();
+        let response = Packet::read(&mut response)?;
+        assert_eq!(response.header.id, i as u16);
+        assert_eq!(response.questions[0].name, *name);
+    }
+    drop(stream);
+    assert_eq!(server.join().unwrap(), 3);
+    Ok(())
+}
+
+/// whether the server keeps `stream` open, rather than closing it straight
+/// away
+fn held_open(stream: &mut TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    match stream.read(&mut [0; 2]) {
+        Err(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        Ok(read) => read > 0,
+    }
+}
+
+/// starts a forwarder with `config` on a free port, and returns its address
+fn start_server(config: Config) -> SocketAddr {
+    let addr = SocketAddr::from(([127, 0, 0, 1], common::free_port()));
+    let config = Config {
+        listen: vec![addr],
+        mode: Mode::Forwarder,
+        upstreams: vec![SocketAddr::from(([127, 0, 0, 1], common::free_port()))],
+        ..config
+    };
+    thread::spawn(move || lookup::start(&config).unwrap());
+    addr
+}
+
+/// retries until the server is listening
+fn connect(addr: SocketAddr) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return stream,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("server isn't listening: {}", e),
+        }
+    }
+}
+
+#[test]
+fn test_connections_capped() {
+    let addr = start_server(Config {
+        tcp_connections: 2,
+        ..Config::default()
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut first = connect(addr);
+    let mut second = connect(addr);
+    assert!(held_open(&mut first));
+    assert!(held_open(&mut second));
+    // the third one gets closed instead of a thread of its own
+    assert!(!held_open(&mut connect(addr)));
+
+    // and once a connection goes away there is room again
+    drop(first);
+    loop {
+        if held_open(&mut connect(addr)) {
+            break;
+        }
+        assert!(Instant::now() < deadline);
+    }
+}
+
+#[test]
+fn test_connections_capped_per_client() {
+    let addr = start_server(Config {
+        tcp_connections: 10,
+        tcp_connections_per_client: 2,
+        ..Config::default()
+    });
+    let mut first = connect(addr);
+    let mut second = connect(addr);
+    assert!(held_open(&mut first));
+    assert!(held_open(&mut second));
+    // room for others, but not for this client
+    assert!(!held_open(&mut connect(addr)));
+}
+
+#[test]
+fn test_slow_query_times_out() {
+    let addr = start_server(Config {
+        tcp_idle_timeout: Duration::from_millis(500),
+        ..Config::default()
+    });
+    let mut stream = connect(addr);
+    let request = create_request_packet("www.example.com.", QueryType::A);
+    let mut buffer = BytePacketBuffer::new_empty();
+    request.write(&mut buffer).unwrap();
+    let mut message = (buffer.size as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&buffer[0..buffer.size]);
+
+    // a byte every 100ms keeps every read short, but not the whole message
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        for x in message.iter() {
+            thread::sleep(Duration::from_millis(100));
+            if writer.write_all(&[*x]).is_err() {
+                return;
+            }
+        }
+    });
+    let start = Instant::now();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .unwrap();
+    match stream.read(&mut [0; 2]) {
+        Ok(read) => assert_eq!(read, 0),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -1,12 +1,13 @@
-use druns::buffer::{BytePacketBuffer, Result};
-use druns::packet::Packet;
+use druns::buffer::{BufferOverflow, BytePacketBuffer, Result};
+use druns::lookup::{create_request_packet, write_response};
+use druns::packet::{EdnsOption, Packet, QueryType, Record, ResponseCode};
 
 #[test]
 fn test_write_string1() -> Result<()> {
     // start of buffer
     let mut buffer = BytePacketBuffer::new_empty();
     buffer.write_string("hello")?;
-    assert_eq!(buffer.read_string_from(5, 0), "hello");
+    assert_eq!(buffer.read_string_from(5, 0)?, "hello");
     Ok(())
 }
 
@@ -20,21 +21,19 @@ fn test_write_string2() -> Result<()> {
     buffer.write_u32(12333)?;
     let pos = (4 * 32) / 8;
     buffer.write_string("hello_world")?;
-    assert_eq!(buffer.read_string_from(11, pos), "hello_world");
+    assert_eq!(buffer.read_string_from(11, pos)?, "hello_world");
     Ok(())
 }
 
 #[test]
 fn test_read_and_write1() -> Result<()> {
     let mut buffer = BytePacketBuffer::new(String::from("tests/google_request.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer)?;
 
     let mut secondary_buffer = BytePacketBuffer::new_empty();
-    packet.write(&mut secondary_buffer);
+    packet.write(&mut secondary_buffer)?;
 
-    let mut new_packet = Packet::new();
-    new_packet.read(&mut secondary_buffer);
+    let new_packet = Packet::read(&mut secondary_buffer)?;
     assert_eq!(packet.header, new_packet.header);
 
     Ok(())
@@ -43,15 +42,193 @@ fn test_read_and_write1() -> Result<()> {
 #[test]
 fn test_read_and_write2() -> Result<()> {
     let mut buffer = BytePacketBuffer::new(String::from("tests/google_response.txt"));
-    let mut packet = Packet::new();
-    packet.read(&mut buffer);
+    let packet = Packet::read(&mut buffer)?;
 
     let mut secondary_buffer = BytePacketBuffer::new_empty();
-    packet.write(&mut secondary_buffer);
+    packet.write(&mut secondary_buffer)?;
 
-    let mut new_packet = Packet::new();
-    new_packet.read(&mut secondary_buffer);
+    let new_packet = Packet::read(&mut secondary_buffer)?;
     assert_eq!(packet.header, new_packet.header);
 
     Ok(())
 }
+
+#[test]
+fn test_compression_round_trip() -> Result<()> {
+    let mut buffer = BytePacketBuffer::new(String::from("tests/netflix_response.txt"));
+    let packet = Packet::read(&mut buffer)?;
+
+    let mut secondary_buffer = BytePacketBuffer::new_empty();
+    packet.write(&mut secondary_buffer)?;
+    // every answer owner name should be a pointer to the question, same as
+    // the original message
+    assert_eq!(secondary_buffer.size, buffer.size);
+
+    let new_packet = Packet::read(&mut secondary_buffer)?;
+    assert_eq!(packet.answers, new_packet.answers);
+    Ok(())
+}
+
+#[test]
+fn test_compression_rdata_names() -> Result<()> {
+    let mut packet = create_request_packet("example.com.", QueryType::NS);
+    packet.answers = vec![
+        Record::NS {
+            name: "example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            host: "ns1.example.com.".to_string(),
+        },
+        Record::NS {
+            name: "example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            host: "ns2.example.com.".to_string(),
+        },
+        Record::MX {
+            name: "example.com.".to_string(),
+            class: 1,
+            priority: 10,
+            host: "mail.example.com.".to_string(),
+            ttl: 300,
+        },
+    ];
+    packet.header.ans_c = 3;
+
+    let mut buffer = BytePacketBuffer::new_empty();
+    packet.write(&mut buffer)?;
+    // 152 bytes when every name is written in full
+    assert_eq!(buffer.size, 86);
+    // owner name of the first answer points at the question
+    assert_eq!(buffer.read_u16_from(29)?, 0xC00C);
+
+    let new_packet = Packet::read(&mut buffer)?;
+    assert_eq!(packet.answers, new_packet.answers);
+    Ok(())
+}
+
+#[test]
+fn test_write_name_limits() -> Result<()> {
+    // 4 labels of 62 bytes and one of 1 make 255 bytes with the lengths and
+    // the root label
+    let label = "x".repeat(62);
+    let longest = format!("{0}.{0}.{0}.{0}.a.", label);
+    let mut buffer = BytePacketBuffer::new_empty();
+    buffer.write_qname(&longest)?;
+    assert_eq!(buffer.size, 255);
+    buffer.reset_for_read();
+    assert_eq!(buffer.read_qname()?, longest);
+
+    let mut buffer = BytePacketBuffer::new_empty();
+    let err = buffer
+        .write_qname(&format!("{0}.{0}.{0}.{0}.ab.", label))
+        .unwrap_err();
+    assert!(err.to_string().starts_with("name of 256 bytes"));
+    assert_eq!(buffer.size, 0);
+
+    let err = buffer
+        .write_qname(&format!("{}.example.", "x".repeat(64)))
+        .unwrap_err();
+    assert!(err.to_string().starts_with("label of 64 bytes"));
+    assert_eq!(buffer.size, 0);
+    Ok(())
+}
+
+#[test]
+fn test_write_up_to_limit() -> Result<()> {
+    let mut buffer = BytePacketBuffer::new_empty();
+    (0..512).try_for_each(|i| buffer.write_u8(i as u8))?;
+    // the last byte of a full 512 byte message is readable
+    assert_eq!(buffer.read_u8_from(511)?, 255);
+
+    let err = buffer.write_u8(0).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BufferOverflow>(),
+        Some(&BufferOverflow { max_size: 512 })
+    );
+    assert_eq!(buffer.size, 512);
+    Ok(())
+}
+
+#[test]
+fn test_write_large_packet() -> Result<()> {
+    let mut packet = create_request_packet("example.com.", QueryType::A);
+    packet.answers = (0..64)
+        .map(|i| Record::A {
+            name: "example.com.".to_string(),
+            class: 1,
+            ttl: 300,
+            ip: [10, 0, 0, i],
+        })
+        .collect();
+    packet.header.ans_c = 64;
+
+    let mut small_buffer = BytePacketBuffer::new_empty();
+    let err = packet.write(&mut small_buffer).unwrap_err();
+    assert!(err.is::<BufferOverflow>());
+
+    let mut buffer = BytePacketBuffer::with_max_size(4096);
+    packet.write(&mut buffer)?;
+    assert_eq!(buffer.size, 29 + 64 * 16);
+    let new_packet = Packet::read(&mut buffer)?;
+    assert_eq!(packet.answers, new_packet.answers);
+
+    // too large for a plain UDP client, so only the question is sent back
+    let mut truncated = write_response(&packet, 512)?;
+    let truncated = Packet::read(&mut truncated)?;
+    assert!(truncated.header.is_truncated);
+    assert_eq!(truncated.header.ques_c, 1);
+    assert!(truncated.answers.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_unwritable_response() -> Result<()> {
+    let mut packet = create_request_packet("example.com.", QueryType::A);
+    packet.answers = vec![Record::A {
+        name: format!("{}.example.com.", "x".repeat(64)),
+        class: 1,
+        ttl: 300,
+        ip: [10, 0, 0, 1],
+    }];
+    packet.header.ans_c = 1;
+    assert!(packet.write(&mut BytePacketBuffer::new_empty()).is_err());
+
+    // the client still gets an answer, just not that one
+    let mut written = write_response(&packet, 512)?;
+    let written = Packet::read(&mut written)?;
+    assert_eq!(written.header.id, packet.header.id);
+    assert_eq!(written.header.rcode, ResponseCode::serv_fail);
+    assert_eq!(written.questions[0].name, "example.com.");
+    assert!(written.answers.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_edns_round_trip() -> Result<()> {
+    let mut packet = create_request_packet("example.com.", QueryType::A);
+    let opt = Record::OPT {
+        udp_payload_size: 1232,
+        extended_rcode: 1,
+        version: 0,
+        dnssec_ok: true,
+        options: vec![EdnsOption {
+            code: 10,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        }],
+    };
+    packet.set_edns(Some(opt.clone()));
+    assert_eq!(packet.header.addi_c, 1);
+
+    let mut buffer = BytePacketBuffer::new_empty();
+    packet.write(&mut buffer)?;
+    let new_packet = Packet::read(&mut buffer)?;
+    assert_eq!(new_packet.edns(), Some(&opt));
+    assert_eq!(new_packet.max_udp_payload(), 1232);
+
+    // OPT survives truncation so the client still sees our payload size
+    let truncated = new_packet.truncated();
+    assert_eq!(truncated.edns(), Some(&opt));
+    assert_eq!(truncated.header.addi_c, 1);
+    Ok(())
+}
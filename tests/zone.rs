@@ -0,0 +1,483 @@
+mod common;
+
+use common::*;
+use druns::packet::{QueryType, Record, ResponseCode};
+use druns::zone::{Zone, Zones};
+use std::{fs, net::Ipv6Addr};
+
+const EXAMPLE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+        2024010101  ; serial
+        2h          ; refresh
+        15m         ; retry
+        1w          ; expire
+        300 )       ; minimum
+    IN  NS  ns1
+    IN  NS  ns2.example.net.
+    IN  MX  10 mail
+ns1         A       192.0.2.1
+mail  600   IN A    192.0.2.2
+            AAAA    2001:db8::2
+www         CNAME   web
+web         A       192.0.2.3
+alias       CNAME   www
+away        CNAME   www.example.net.
+text        TXT     "hello world" "quote \" and \\ and \007" bare
+_sip._udp   SRV     10 60 5060 sip
+sip         A       192.0.2.4
+2.2         PTR     mail
+blob        TYPE65400 \# 3 0a0b 0c
+*.wild      A       192.0.2.5
+host.wild   A       192.0.2.6
+a.b.deep    A       192.0.2.7
+sub         NS      ns.sub
+ns.sub      A       192.0.2.8
+"#;
+
+fn example() -> Zone {
+    Zone::parse(EXAMPLE, "example.com").unwrap()
+}
+
+#[test]
+fn test_parse_zone() {
+    let zone = example();
+    assert_eq!(zone.origin, "example.com.");
+    let records = zone.records();
+    assert_eq!(
+        records[0],
+        &Record::SOA {
+            name: "example.com.".to_string(),
+            class: 1,
+            ttl: 3600,
+            mname: "ns1.example.com.".to_string(),
+            rname: "hostmaster.example.com.".to_string(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+        }
+    );
+    assert_eq!(records.len(), 21);
+
+    let has = |record: Record| assert!(records.contains(&&record), "{:?}", record);
+    has(Record::NS {
+        name: "example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        host: "ns2.example.net.".to_string(),
+    });
+    has(Record::MX {
+        name: "example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        priority: 10,
+        host: "mail.example.com.".to_string(),
+    });
+    // blank owner carries the last one over, and $TTL still applies
+    has(Record::AAAA {
+        name: "mail.example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        ip: "2001:db8::2".parse::<Ipv6Addr>().unwrap(),
+    });
+    has(Record::A {
+        name: "mail.example.com.".to_string(),
+        class: 1,
+        ttl: 600,
+        ip: [192, 0, 2, 2],
+    });
+    has(Record::TXT {
+        name: "text.example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        data: vec![
+            b"hello world".to_vec(),
+            b"quote \" and \\ and \x07".to_vec(),
+            b"bare".to_vec(),
+        ],
+    });
+    has(Record::SRV {
+        name: "_sip._udp.example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        priority: 10,
+        weight: 60,
+        port: 5060,
+        target: "sip.example.com.".to_string(),
+    });
+    has(Record::PTR {
+        name: "2.2.example.com.".to_string(),
+        class: 1,
+        ttl: 3600,
+        host: "mail.example.com.".to_string(),
+    });
+    has(Record::UNKNOWN {
+        name: "blob.example.com.".to_string(),
+        rtype: 65400,
+        class: 1,
+        ttl: 3600,
+        data: vec![10, 11, 12],
+    });
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("druns-zone-{}", std::process::id()));
+    fs::create_dir_all(dir.join("hosts")).unwrap();
+    fs::write(
+        dir.join("corp.zone"),
+        "$TTL 300\n\
+         @ SOA ns hostmaster 1 2 3 4 5\n\
+         \tNS ns\n\
+         $INCLUDE hosts/office.zone office\n\
+         $INCLUDE hosts/servers.zone\n\
+         after A 10.0.0.9\n",
+    )
+    .unwrap();
+    fs::write(dir.join("hosts/office.zone"), "printer A 10.0.1.1\n").unwrap();
+    fs::write(dir.join("hosts/servers.zone"), "ns A 10.0.0.1\n").unwrap();
+
+    let zone = Zone::load(&dir.join("corp.zone"), "corp.example").unwrap();
+    let names: Vec<&str> = zone.records().iter().map(|x| x.name()).collect();
+    // the origin of an included file doesn't leak back into its parent
+    assert_eq!(
+        names,
+        vec![
+            "corp.example.",
+            "corp.example.",
+            "after.corp.example.",
+            "ns.corp.example.",
+            "printer.office.corp.example.",
+        ]
+    );
+
+    fs::write(dir.join("hosts/servers.zone"), "ns A 10.0.0.300\n").unwrap();
+    let error = Zone::load(&dir.join("corp.zone"), "corp.example")
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        format!(
+            "{}:1: invalid ipv4 address \"10.0.0.300\"",
+            dir.join("hosts/servers.zone").display()
+        )
+    );
+
+    fs::write(dir.join("loop.zone"), "$INCLUDE loop.zone\n").unwrap();
+    let error = Zone::load(&dir.join("loop.zone"), "corp.example")
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.ends_with("$INCLUDE nested more than 8 deep"),
+        "{}",
+        error
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_invalid_zones() {
+    let error = |text: &str| Zone::parse(text, "example.com.").unwrap_err().to_string();
+    let soa = "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\n";
+    assert_eq!(
+        error(&format!("{}www MX mail\n", soa)),
+        "line 3: wrong number of fields for a MX record"
+    );
+    assert_eq!(
+        error(&format!("{}www HINFO a b\n", soa)),
+        "line 3: unknown record type HINFO"
+    );
+    assert_eq!(
+        error(&format!("{}www TYPE99 abc\n", soa)),
+        "line 3: TYPE99 records need generic \\# rdata"
+    );
+    assert_eq!(
+        error(&format!("{}www TYPE99 \\# 2 abcdef\n", soa)),
+        "line 3: rdata is 3 bytes long, not 2"
+    );
+    assert_eq!(
+        error(&format!("{}www A (\n10.0.0.1\n", soa)),
+        "line 3: ( is never closed"
+    );
+    assert_eq!(
+        error(&format!("{}www TXT \"open\n", soa)),
+        "line 3: string is never closed"
+    );
+    assert_eq!(
+        error(&format!("{}www 1x A 10.0.0.1\n", soa)),
+        "line 3: invalid ttl \"1x\""
+    );
+    assert_eq!(
+        error(&format!("{}$GENERATE 1-2 a A 10.0.0.$\n", soa)),
+        "line 3: unknown directive $GENERATE"
+    );
+    assert_eq!(
+        error("@ SOA ns hostmaster 1 2 3 4 5\nwww A 10.0.0.1\n"),
+        "line 2: no ttl, and no $TTL before it"
+    );
+    assert_eq!(
+        error(" A 10.0.0.1\n"),
+        "line 1: no owner name, and no record before it to take it from"
+    );
+    assert_eq!(
+        error(&format!("{}www.example.net. A 10.0.0.1\n", soa)),
+        "www.example.net. is outside of the zone"
+    );
+    assert_eq!(
+        error(&format!("{}www CNAME web\nwww A 10.0.0.1\n", soa)),
+        "www.example.com. has a CNAME along with other records"
+    );
+    assert_eq!(
+        error("$TTL 60\nwww A 10.0.0.1\n"),
+        "no SOA record for example.com."
+    );
+}
+
+#[test]
+fn test_generic_rdata_for_known_types() {
+    let soa = "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\n";
+    let zone = Zone::parse(
+        &format!(
+            "{}a 300 IN A \\# 4 01020304\n\
+             b 300 IN TYPE1 \\# 4 0102 0304\n\
+             @ MX \\# 20 000a 046d61696c 076578616d706c65 03636f6d 00\n",
+            soa
+        ),
+        "example.com.",
+    )
+    .unwrap();
+    let records = zone.records();
+    let has = |record: Record| assert!(records.contains(&&record), "{:?}", record);
+    has(Record::A {
+        name: "a.example.com.".to_string(),
+        class: 1,
+        ttl: 300,
+        ip: [1, 2, 3, 4],
+    });
+    has(Record::A {
+        name: "b.example.com.".to_string(),
+        class: 1,
+        ttl: 300,
+        ip: [1, 2, 3, 4],
+    });
+    has(Record::MX {
+        name: "example.com.".to_string(),
+        class: 1,
+        ttl: 60,
+        priority: 10,
+        host: "mail.example.com.".to_string(),
+    });
+
+    let error = |text: &str| Zone::parse(text, "example.com.").unwrap_err().to_string();
+    assert_eq!(
+        error(&format!("{}www A \\# 4 010203\n", soa)),
+        "line 3: rdata is 3 bytes long, not 4"
+    );
+    assert_eq!(
+        error(&format!("{}www A \\# 3 010203\n", soa)),
+        "line 3: invalid A rdata"
+    );
+    assert_eq!(
+        error(&format!("{}www A \\# 5 0102030405\n", soa)),
+        "line 3: invalid A rdata"
+    );
+}
+
+fn lookup(
+    zone: &Zone,
+    name: &str,
+    qtype: QueryType,
+) -> (ResponseCode, bool, Vec<String>, Vec<String>, Vec<String>) {
+    let lookup = zone.lookup(name, qtype);
+    let text = |records: &[Record]| records.iter().map(|x| x.to_string()).collect();
+    (
+        lookup.rcode,
+        lookup.authoritative,
+        text(&lookup.answers),
+        text(&lookup.authority),
+        text(&lookup.additional),
+    )
+}
+
+const NEGATIVE_SOA: &str =
+    "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 2024010101 7200 900 604800 300";
+
+#[test]
+fn test_answers() {
+    let zone = example();
+    let (rcode, aa, answers, authority, additional) =
+        lookup(&zone, "WEB.example.com.", QueryType::A);
+    assert_eq!((rcode, aa), (ResponseCode::no_error, true));
+    assert_eq!(answers, vec!["web.example.com. 3600 IN A 192.0.2.3"]);
+    assert!(authority.is_empty() && additional.is_empty());
+
+    // addresses of the hosts named go along
+    let (_, _, answers, _, additional) = lookup(&zone, "example.com.", QueryType::MX);
+    assert_eq!(
+        answers,
+        vec!["example.com. 3600 IN MX 10 mail.example.com."]
+    );
+    assert_eq!(
+        additional,
+        vec![
+            "mail.example.com. 600 IN A 192.0.2.2",
+            "mail.example.com. 3600 IN AAAA 2001:db8::2",
+        ]
+    );
+
+    let (_, _, answers, _, _) = lookup(&zone, "mail.example.com.", QueryType::UNKNOWN(255));
+    assert_eq!(answers.len(), 2);
+}
+
+#[test]
+fn test_cnames() {
+    let zone = example();
+    let (_, aa, answers, _, _) = lookup(&zone, "alias.example.com.", QueryType::A);
+    assert!(aa);
+    assert_eq!(
+        answers,
+        vec![
+            "alias.example.com. 3600 IN CNAME www.example.com.",
+            "www.example.com. 3600 IN CNAME web.example.com.",
+            "web.example.com. 3600 IN A 192.0.2.3",
+        ]
+    );
+
+    // the chain is left to the client once it leaves the zone
+    let (rcode, _, answers, authority, _) = lookup(&zone, "away.example.com.", QueryType::A);
+    assert_eq!(rcode, ResponseCode::no_error);
+    assert_eq!(
+        answers,
+        vec!["away.example.com. 3600 IN CNAME www.example.net."]
+    );
+    assert!(authority.is_empty());
+
+    let (_, _, answers, _, _) = lookup(&zone, "www.example.com.", QueryType::CNAME);
+    assert_eq!(
+        answers,
+        vec!["www.example.com. 3600 IN CNAME web.example.com."]
+    );
+}
+
+#[test]
+fn test_negative_answers() {
+    let zone = example();
+    let (rcode, aa, answers, authority, _) = lookup(&zone, "nothing.example.com.", QueryType::A);
+    assert_eq!((rcode, aa), (ResponseCode::nx_domain, true));
+    assert!(answers.is_empty());
+    assert_eq!(authority, vec![NEGATIVE_SOA]);
+
+    let (rcode, _, answers, authority, _) = lookup(&zone, "web.example.com.", QueryType::AAAA);
+    assert_eq!(rcode, ResponseCode::no_error);
+    assert!(answers.is_empty());
+    assert_eq!(authority, vec![NEGATIVE_SOA]);
+
+    // names with nothing but other names below them do exist
+    let (rcode, _, answers, authority, _) = lookup(&zone, "b.deep.example.com.", QueryType::A);
+    assert_eq!(rcode, ResponseCode::no_error);
+    assert!(answers.is_empty());
+    assert_eq!(authority, vec![NEGATIVE_SOA]);
+}
+
+#[test]
+fn test_wildcards() {
+    let zone = example();
+    let (rcode, aa, answers, _, _) = lookup(&zone, "anything.wild.example.com.", QueryType::A);
+    assert_eq!((rcode, aa), (ResponseCode::no_error, true));
+    assert_eq!(
+        answers,
+        vec!["anything.wild.example.com. 3600 IN A 192.0.2.5"]
+    );
+
+    let (_, _, answers, _, _) = lookup(&zone, "a.b.wild.example.com.", QueryType::A);
+    assert_eq!(answers, vec!["a.b.wild.example.com. 3600 IN A 192.0.2.5"]);
+
+    // names which exist aren't covered, nor is anything below them
+    let (_, _, answers, _, _) = lookup(&zone, "host.wild.example.com.", QueryType::A);
+    assert_eq!(answers, vec!["host.wild.example.com. 3600 IN A 192.0.2.6"]);
+    let (rcode, _, _, _, _) = lookup(&zone, "x.host.wild.example.com.", QueryType::A);
+    assert_eq!(rcode, ResponseCode::nx_domain);
+
+    let (rcode, _, answers, authority, _) = lookup(&zone, "other.wild.example.com.", QueryType::MX);
+    assert_eq!(rcode, ResponseCode::no_error);
+    assert!(answers.is_empty());
+    assert_eq!(authority, vec![NEGATIVE_SOA]);
+}
+
+#[test]
+fn test_delegations() {
+    let zone = example();
+    for name in [
+        "sub.example.com.",
+        "www.sub.example.com.",
+        "ns.sub.example.com.",
+    ]
+    .iter()
+    {
+        let (rcode, aa, answers, authority, additional) = lookup(&zone, name, QueryType::A);
+        assert_eq!((rcode, aa), (ResponseCode::no_error, false), "{}", name);
+        assert!(answers.is_empty());
+        assert_eq!(
+            authority,
+            vec!["sub.example.com. 3600 IN NS ns.sub.example.com."]
+        );
+        assert_eq!(additional, vec!["ns.sub.example.com. 3600 IN A 192.0.2.8"]);
+    }
+}
+
+#[test]
+fn test_most_specific_zone() {
+    let mut zones = Zones::default();
+    assert!(zones.is_empty());
+    zones.insert(example());
+    zones.insert(
+        Zone::parse(
+            "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\n",
+            "sub.example.com",
+        )
+        .unwrap(),
+    );
+    assert_eq!(
+        zones.find("www.sub.example.com.").unwrap().origin,
+        "sub.example.com."
+    );
+    assert_eq!(
+        zones.find("www.example.com.").unwrap().origin,
+        "example.com."
+    );
+    assert!(zones.find("www.example.net.").is_none());
+    assert!(zones.find("com.").is_none());
+}
+
+#[test]
+fn test_served_with_authority() {
+    // no upstream servers at all, everything comes from the zone
+    let mut resolver = resolver(free_port());
+    resolver.zones.insert(example());
+
+    let response = query(&resolver, "web.example.com.", QueryType::A);
+    assert!(response.header.authoritative);
+    assert!(response.header.recursion_available);
+    assert_eq!(
+        response.answers,
+        vec![Record::A {
+            name: "web.example.com.".to_string(),
+            class: 1,
+            ttl: 3600,
+            ip: [192, 0, 2, 3],
+        }]
+    );
+
+    let response = query(&resolver, "nothing.example.com.", QueryType::A);
+    assert!(response.header.authoritative);
+    assert_eq!(response.header.rcode, ResponseCode::nx_domain);
+    assert_eq!(response.authority.len(), 1);
+
+    let response = query(&resolver, "www.sub.example.com.", QueryType::A);
+    assert!(!response.header.authoritative);
+    assert_eq!(response.authority.len(), 1);
+    assert_eq!(response.additional.len(), 1);
+}